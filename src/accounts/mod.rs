@@ -0,0 +1,465 @@
+use log::info;
+use std::collections::HashMap;
+
+use crate::broker::cgt::Disposal;
+use crate::broker::{BacktestBroker, BrokerCashEvent, DividendPayment, EventLog, TransferCash};
+use crate::clock::Clock;
+use crate::input::DataSource;
+use crate::sim::SimulatedBroker;
+use crate::types::{CashValue, DateTime};
+
+///Whether dividends and gains in an account are taxed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TaxTreatment {
+    TaxFree,
+    Taxable,
+}
+
+///Tax wrapper around a single [SimulatedBroker].
+///
+///Contributions can be capped per UK tax year, and withdrawals can be blocked until a date, such
+///as the age at which a pension can be accessed. The cap only applies to cash contributed through
+///[Accounts], income and gains inside the account don't count towards it.
+pub struct Account<T: DataSource> {
+    name: String,
+    broker: SimulatedBroker<T>,
+    treatment: TaxTreatment,
+    cap: Option<f64>,
+    locked_until: Option<DateTime>,
+    contributions: HashMap<i32, f64>,
+}
+
+impl<T: DataSource> Account<T> {
+    //Annual allowances at the time of writing
+    pub const ISA_ALLOWANCE: f64 = 20_000.0;
+    pub const SIPP_ALLOWANCE: f64 = 60_000.0;
+
+    pub fn new(
+        name: impl Into<String>,
+        broker: SimulatedBroker<T>,
+        treatment: TaxTreatment,
+        cap: Option<f64>,
+        locked_until: Option<DateTime>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            broker,
+            treatment,
+            cap,
+            locked_until,
+            contributions: HashMap::new(),
+        }
+    }
+
+    pub fn isa(name: impl Into<String>, broker: SimulatedBroker<T>) -> Self {
+        Self::new(
+            name,
+            broker,
+            TaxTreatment::TaxFree,
+            Some(Self::ISA_ALLOWANCE),
+            None,
+        )
+    }
+
+    pub fn sipp(
+        name: impl Into<String>,
+        broker: SimulatedBroker<T>,
+        locked_until: impl Into<DateTime>,
+    ) -> Self {
+        Self::new(
+            name,
+            broker,
+            TaxTreatment::TaxFree,
+            Some(Self::SIPP_ALLOWANCE),
+            Some(locked_until.into()),
+        )
+    }
+
+    pub fn taxable(name: impl Into<String>, broker: SimulatedBroker<T>) -> Self {
+        Self::new(name, broker, TaxTreatment::Taxable, None, None)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn treatment(&self) -> &TaxTreatment {
+        &self.treatment
+    }
+
+    pub fn broker(&self) -> &SimulatedBroker<T> {
+        &self.broker
+    }
+
+    pub fn broker_mut(&mut self) -> &mut SimulatedBroker<T> {
+        &mut self.broker
+    }
+
+    pub fn contributions(&self, tax_year: i32) -> CashValue {
+        CashValue::from(*self.contributions.get(&tax_year).unwrap_or(&0.0))
+    }
+
+    //None if contributions are not capped
+    pub fn remaining_allowance(&self, tax_year: i32) -> Option<CashValue> {
+        self.cap
+            .map(|cap| CashValue::from((cap - *self.contributions(tax_year)).max(0.0)))
+    }
+
+    pub fn is_locked(&self, date: &DateTime) -> bool {
+        self.locked_until.as_ref().is_some_and(|until| date < until)
+    }
+
+    //Cash that can be withdrawn without selling positions or borrowing
+    fn available_cash(&self) -> f64 {
+        self.broker
+            .get_cash_balance()
+            .min(*self.broker.get_excess_equity())
+            .max(0.0)
+    }
+}
+
+///Holds several tax wrappers, such as ISAs, pensions and taxable accounts, in one simulation.
+///
+///Every broker must be built with the same [Clock] as the accounts. Calls to `check` and `finish`
+///are passed to every broker, so contributions and withdrawals are made between them as with a
+///single broker.
+///
+///Withdrawals take cash from accounts in the withdrawal order, followed by any accounts not named
+///in the order as they were added. Locked accounts are skipped, and positions are not sold to
+///raise cash. Only taxable accounts report dividends and gains for tax.
+pub struct Accounts<T: DataSource> {
+    clock: Clock,
+    accounts: Vec<Account<T>>,
+    withdrawal_order: Vec<String>,
+}
+
+impl<T: DataSource> Accounts<T> {
+    pub fn check(&mut self) {
+        for account in self.accounts.iter_mut() {
+            account.broker.check();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        for account in self.accounts.iter_mut() {
+            account.broker.finish();
+        }
+    }
+
+    pub fn account(&self, name: &str) -> Option<&Account<T>> {
+        self.accounts.iter().find(|account| account.name == name)
+    }
+
+    pub fn account_mut(&mut self, name: &str) -> Option<&mut Account<T>> {
+        self.accounts
+            .iter_mut()
+            .find(|account| account.name == name)
+    }
+
+    pub fn get_total_value(&self) -> CashValue {
+        CashValue::from(
+            self.accounts
+                .iter()
+                .map(|account| *account.broker.get_total_value())
+                .sum::<f64>(),
+        )
+    }
+
+    //Fails without depositing anything if the contribution exceeds the allowance for the current
+    //tax year or there is no account with this name
+    pub fn contribute(&mut self, name: &str, cash: &f64) -> BrokerCashEvent {
+        let tax_year = self.clock.borrow().now().uk_tax_year();
+        let account = match self.account_mut(name) {
+            Some(account) => account,
+            None => {
+                info!("ACCOUNTS: No account called {:?}", name);
+                return BrokerCashEvent::DepositFailure(CashValue::from(*cash));
+            }
+        };
+        if let Some(remaining) = account.remaining_allowance(tax_year) {
+            if *cash > *remaining {
+                info!(
+                    "ACCOUNTS: Contribution of {:?} to {:?} exceeds remaining allowance of {:?}",
+                    cash, name, remaining
+                );
+                return BrokerCashEvent::DepositFailure(CashValue::from(*cash));
+            }
+        }
+        *account.contributions.entry(tax_year).or_default() += cash;
+        account.broker.deposit_cash(cash)
+    }
+
+    //Fails without withdrawing anything if the unlocked accounts hold less cash than requested
+    pub fn withdraw(&mut self, cash: &f64) -> BrokerCashEvent {
+        let now = self.clock.borrow().now();
+        let order = self.withdrawal_positions();
+        let available: f64 = order
+            .iter()
+            .map(|position| &self.accounts[*position])
+            .filter(|account| !account.is_locked(&now))
+            .map(|account| account.available_cash())
+            .sum();
+        if available < *cash {
+            info!(
+                "ACCOUNTS: Attempted withdrawal of {:?} but only have {:?} available",
+                cash, available
+            );
+            return BrokerCashEvent::WithdrawFailure(CashValue::from(*cash));
+        }
+
+        let mut remaining = *cash;
+        for position in order {
+            let account = &mut self.accounts[position];
+            if remaining <= 0.0 || account.is_locked(&now) {
+                continue;
+            }
+            let withdrawn = account.available_cash().min(remaining);
+            if withdrawn > 0.0 {
+                account.broker.withdraw_cash(&withdrawn);
+                remaining -= withdrawn;
+            }
+        }
+        BrokerCashEvent::WithdrawSuccess(CashValue::from(*cash))
+    }
+
+    pub fn taxable_dividends_between(&self, start: &i64, end: &i64) -> Vec<DividendPayment> {
+        self.taxable()
+            .flat_map(|account| account.broker.dividends_between(start, end))
+            .collect()
+    }
+
+    //Disposals matched under the UK share matching rules, in sterling
+    pub fn taxable_gains_between(&self, start: &i64, end: &i64) -> Vec<Disposal> {
+        self.taxable()
+            .flat_map(|account| account.broker.uk_capital_gains().disposals())
+            .filter(|disposal| *disposal.date >= *start && *disposal.date <= *end)
+            .collect()
+    }
+
+    fn taxable(&self) -> impl Iterator<Item = &Account<T>> {
+        self.accounts
+            .iter()
+            .filter(|account| account.treatment == TaxTreatment::Taxable)
+    }
+
+    fn withdrawal_positions(&self) -> Vec<usize> {
+        let mut positions: Vec<usize> = self
+            .withdrawal_order
+            .iter()
+            .filter_map(|name| {
+                self.accounts
+                    .iter()
+                    .position(|account| account.name == *name)
+            })
+            .collect();
+        for position in 0..self.accounts.len() {
+            if !positions.contains(&position) {
+                positions.push(position);
+            }
+        }
+        positions
+    }
+}
+
+pub struct AccountsBuilder<T: DataSource> {
+    clock: Option<Clock>,
+    accounts: Vec<Account<T>>,
+    withdrawal_order: Vec<String>,
+}
+
+impl<T: DataSource> AccountsBuilder<T> {
+    pub fn build(&mut self) -> Accounts<T> {
+        if self.clock.is_none() {
+            panic!("Accounts must be built with clock");
+        }
+        Accounts {
+            clock: self.clock.take().unwrap(),
+            accounts: std::mem::take(&mut self.accounts),
+            withdrawal_order: self.withdrawal_order.clone(),
+        }
+    }
+
+    pub fn with_clock(&mut self, clock: Clock) -> &mut Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn with_account(&mut self, account: Account<T>) -> &mut Self {
+        self.accounts.push(account);
+        self
+    }
+
+    //Names of accounts in the order they are withdrawn from
+    pub fn with_withdrawal_order(&mut self, withdrawal_order: Vec<String>) -> &mut Self {
+        self.withdrawal_order = withdrawal_order;
+        self
+    }
+
+    pub fn new() -> Self {
+        Self {
+            clock: None,
+            accounts: Vec::new(),
+            withdrawal_order: Vec::new(),
+        }
+    }
+}
+
+impl<T: DataSource> Default for AccountsBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use super::{Account, Accounts, AccountsBuilder};
+    use crate::broker::{BacktestBroker, BrokerCashEvent, Order, OrderType, Quote};
+    use crate::clock::{Clock, ClockBuilder};
+    use crate::exchange::DefaultExchangeBuilder;
+    use crate::input::{HashMapInput, HashMapInputBuilder};
+    use crate::sim::{SimulatedBroker, SimulatedBrokerBuilder};
+    use crate::types::{Currency, DateTime, Frequency};
+
+    const DAY: i64 = 86_400;
+    //2023-04-01, five days before the end of the 2022 tax year
+    const START: i64 = 1_680_307_200;
+
+    fn broker(clock: &Clock) -> SimulatedBroker<HashMapInput> {
+        //Price rises from 100 to 120 on the third day
+        let mut quotes: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        for day in 0..10 {
+            let date = START + day * DAY;
+            let price = if day < 2 { 100.0 } else { 120.0 };
+            quotes.insert(
+                date.into(),
+                vec![Quote::new_with_currency(
+                    price,
+                    price,
+                    date,
+                    "ABC",
+                    Currency::GBP,
+                )],
+            );
+        }
+        let source = HashMapInputBuilder::new()
+            .with_quotes(quotes)
+            .with_clock(Rc::clone(clock))
+            .build();
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(clock))
+            .with_data_source(source.clone())
+            .build();
+
+        SimulatedBrokerBuilder::new()
+            .with_data(source)
+            .with_exchange(exchange)
+            .with_base_currency(Currency::GBP)
+            .build()
+    }
+
+    fn setup() -> (Accounts<HashMapInput>, Clock) {
+        let clock = ClockBuilder::with_length_in_days(START, 10)
+            .with_frequency(&Frequency::Daily)
+            .build();
+
+        let accounts = AccountsBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_account(Account::isa("isa", broker(&clock)))
+            .with_account(Account::sipp("sipp", broker(&clock), START + 100 * DAY))
+            .with_account(Account::taxable("taxable", broker(&clock)))
+            .with_withdrawal_order(vec!["taxable".to_string(), "isa".to_string()])
+            .build();
+        (accounts, clock)
+    }
+
+    #[test]
+    fn test_that_contributions_are_capped_per_tax_year() {
+        let (mut accounts, clock) = setup();
+        assert!(matches!(
+            accounts.contribute("isa", &15_000.0),
+            BrokerCashEvent::DepositSuccess(..)
+        ));
+        assert!(matches!(
+            accounts.contribute("isa", &10_000.0),
+            BrokerCashEvent::DepositFailure(..)
+        ));
+        assert!(matches!(
+            accounts.contribute("taxable", &100_000.0),
+            BrokerCashEvent::DepositSuccess(..)
+        ));
+        accounts.finish();
+
+        //Allowance resets on the 6th April
+        for _ in 0..5 {
+            clock.borrow_mut().tick();
+            accounts.check();
+            accounts.finish();
+        }
+        accounts.check();
+        let isa = accounts.account("isa").unwrap();
+        assert_eq!(*isa.contributions(2022), 15_000.0);
+        assert_eq!(*isa.remaining_allowance(2023).unwrap(), 20_000.0);
+        assert!(matches!(
+            accounts.contribute("isa", &10_000.0),
+            BrokerCashEvent::DepositSuccess(..)
+        ));
+        assert_eq!(*accounts.get_total_value(), 125_000.0);
+    }
+
+    #[test]
+    fn test_that_withdrawals_follow_order_and_skip_locked_accounts() {
+        let (mut accounts, _clock) = setup();
+        accounts.contribute("isa", &10_000.0);
+        accounts.contribute("sipp", &50_000.0);
+        accounts.contribute("taxable", &5_000.0);
+
+        assert!(matches!(
+            accounts.withdraw(&20_000.0),
+            BrokerCashEvent::WithdrawFailure(..)
+        ));
+        assert!(matches!(
+            accounts.withdraw(&8_000.0),
+            BrokerCashEvent::WithdrawSuccess(..)
+        ));
+        let cash = |accounts: &Accounts<HashMapInput>, name: &str| {
+            *accounts.account(name).unwrap().broker().get_cash_balance()
+        };
+        assert_eq!(cash(&accounts, "taxable"), 0.0);
+        assert_eq!(cash(&accounts, "isa"), 7_000.0);
+        assert_eq!(cash(&accounts, "sipp"), 50_000.0);
+    }
+
+    #[test]
+    fn test_that_only_taxable_accounts_report_disposals() {
+        let (mut accounts, clock) = setup();
+        for name in ["isa", "taxable"] {
+            accounts.contribute(name, &10_000.0);
+            let broker = accounts.account_mut(name).unwrap().broker_mut();
+            broker.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        }
+        accounts.finish();
+
+        clock.borrow_mut().tick();
+        accounts.check();
+        for name in ["isa", "taxable"] {
+            let broker = accounts.account_mut(name).unwrap().broker_mut();
+            broker.send_order(Order::market(OrderType::MarketSell, "ABC", 10.0));
+        }
+        accounts.finish();
+
+        clock.borrow_mut().tick();
+        accounts.check();
+        accounts.finish();
+
+        let disposals = accounts.taxable_gains_between(&START, &(START + 10 * DAY));
+        assert_eq!(disposals.len(), 1);
+        assert_eq!(*disposals[0].gain(), 200.0);
+        assert!(accounts
+            .taxable_gains_between(&(START + 5 * DAY), &(START + 10 * DAY))
+            .is_empty());
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::{CorporateAction, FxRate, Trade, TradeType};
+use crate::types::{CashValue, Currency, DateTime, PortfolioQty};
+
+///Rule used by HMRC to match shares disposed of against the shares acquired.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchRule {
+    //Acquisitions on the same day as the disposal
+    SameDay,
+    //Acquisitions in the 30 days after the disposal, earliest first
+    BedAndBreakfast,
+    //Average cost of all other shares held
+    Section104,
+}
+
+///Part of a disposal matched under a single [MatchRule]. A disposal matched under more than one
+///rule is reported as several disposals on the same date.
+///
+///Proceeds are net of trade costs, and cost includes them.
+#[derive(Clone, Debug)]
+pub struct Disposal {
+    pub symbol: String,
+    pub date: DateTime,
+    pub quantity: PortfolioQty,
+    pub proceeds: CashValue,
+    pub cost: CashValue,
+    pub rule: MatchRule,
+}
+
+impl Disposal {
+    pub fn gain(&self) -> CashValue {
+        CashValue::from(*self.proceeds - *self.cost)
+    }
+}
+
+///Part of a disposal that exceeds the shares held, such as a short sale. There are no shares to
+///match against so no gain is calculated.
+#[derive(Clone, Debug)]
+pub struct UnmatchedDisposal {
+    pub symbol: String,
+    pub date: DateTime,
+    pub quantity: PortfolioQty,
+    pub proceeds: CashValue,
+}
+
+///Disposals in a UK tax year, which runs from 6 April to 5 April. Year is the calendar year in
+///which the tax year starts.
+#[derive(Clone, Debug)]
+pub struct TaxYearReport {
+    pub year: i32,
+    pub disposals: Vec<Disposal>,
+}
+
+impl TaxYearReport {
+    pub fn proceeds(&self) -> CashValue {
+        CashValue::from(self.disposals.iter().map(|d| *d.proceeds).sum::<f64>())
+    }
+
+    pub fn gains(&self) -> CashValue {
+        CashValue::from(
+            self.disposals
+                .iter()
+                .map(|d| *d.gain())
+                .filter(|gain| *gain > 0.0)
+                .sum::<f64>(),
+        )
+    }
+
+    //Returned as a positive value
+    pub fn losses(&self) -> CashValue {
+        CashValue::from(
+            -self
+                .disposals
+                .iter()
+                .map(|d| *d.gain())
+                .filter(|gain| *gain < 0.0)
+                .sum::<f64>(),
+        )
+    }
+
+    pub fn net_gain(&self) -> CashValue {
+        CashValue::from(*self.gains() - *self.losses())
+    }
+}
+
+//Acquisitions and disposals of a symbol on a single day, which HMRC treats as a single
+//acquisition and a single disposal. Values are what is left to match.
+#[derive(Clone, Debug, Default)]
+struct Day {
+    date: Option<DateTime>,
+    acquired: f64,
+    cost: f64,
+    disposed: f64,
+    proceeds: f64,
+}
+
+//Section 104 pools are built in date order across every symbol because corporate actions can
+//move shares and cost between pools. Actions are applied at the start of the day.
+enum PoolEvent<'a> {
+    Action(&'a CorporateAction),
+    Day(&'a str, &'a Day),
+}
+
+///Capital gains on shares under UK rules, built from the trades recorded by a broker.
+///
+///Disposals are matched against acquisitions on the same day, then against acquisitions in the
+///following 30 days, and then against the Section 104 pool of all other shares at their average
+///cost. Disposals that exceed the shares held, such as short sales, are reported separately as
+///unmatched rather than given a cost.
+///
+///Corporate actions are applied to the pools as they are to the broker cost basis: splits change
+///the number of shares but not the cost, stock mergers move the pool into the new symbol, and
+///spin-offs move part of the cost into a pool for the new symbol.
+///
+///Trades in other currencies are converted into sterling at the latest rate on or before the date
+///of the trade. Trades without a rate can't be valued in sterling so are left out of matching and
+///reported separately, disposals that would have been matched against them are unmatched.
+#[derive(Clone, Debug, Default)]
+pub struct UkCapitalGains {
+    disposals: Vec<Disposal>,
+    unmatched: Vec<UnmatchedDisposal>,
+    unconverted: Vec<Trade>,
+    pools: HashMap<String, (f64, f64)>,
+}
+
+impl UkCapitalGains {
+    const DAY: i64 = 86_400;
+    const BED_AND_BREAKFAST_DAYS: i64 = 30;
+    //Quantities smaller than this are treated as zero so that floating point error isn't
+    //reported as an unmatched disposal
+    const TOLERANCE: f64 = 1e-9;
+
+    pub fn new(trades: &[Trade], actions: &[CorporateAction], rates: &[FxRate]) -> Self {
+        let mut gains = Self::default();
+        let mut days: HashMap<String, BTreeMap<i64, Day>> = HashMap::new();
+        for trade in trades {
+            let rate = match Self::sterling_rate(&trade.currency, &trade.date, rates) {
+                Some(rate) => rate,
+                None => {
+                    gains.unconverted.push(trade.clone());
+                    continue;
+                }
+            };
+            let day = days
+                .entry(trade.symbol.clone())
+                .or_default()
+                .entry(Self::day_number(&trade.date))
+                .or_default();
+            day.date.get_or_insert_with(|| trade.date.clone());
+            match trade.typ {
+                TradeType::Buy => {
+                    day.acquired += *trade.quantity;
+                    day.cost += (*trade.value + *trade.costs) * rate;
+                }
+                TradeType::Sell => {
+                    day.disposed += *trade.quantity;
+                    day.proceeds += (*trade.value - *trade.costs) * rate;
+                }
+            }
+        }
+
+        let mut symbols: Vec<String> = days.keys().cloned().collect();
+        symbols.sort();
+        for symbol in &symbols {
+            let symbol_days = days.get_mut(symbol).unwrap();
+            gains.match_same_day(symbol, symbol_days);
+            gains.match_bed_and_breakfast(symbol, symbol_days);
+        }
+        gains.match_section_104(&symbols, &days, actions);
+        gains.disposals.sort_by(|a, b| a.date.cmp(&b.date));
+        gains.unmatched.sort_by(|a, b| a.date.cmp(&b.date));
+        gains
+    }
+
+    pub fn disposals(&self) -> Vec<Disposal> {
+        self.disposals.clone()
+    }
+
+    pub fn unmatched(&self) -> Vec<UnmatchedDisposal> {
+        self.unmatched.clone()
+    }
+
+    //Trades without a sterling rate on or before the date of the trade
+    pub fn unconverted(&self) -> Vec<Trade> {
+        self.unconverted.clone()
+    }
+
+    //Quantity and total cost of the shares in the Section 104 pool after all trades
+    pub fn pool(&self, symbol: &str) -> Option<(PortfolioQty, CashValue)> {
+        self.pools
+            .get(symbol)
+            .map(|(qty, cost)| (PortfolioQty::from(*qty), CashValue::from(*cost)))
+    }
+
+    pub fn tax_year(&self, year: i32) -> TaxYearReport {
+        TaxYearReport {
+            year,
+            disposals: self
+                .disposals
+                .iter()
+                .filter(|d| d.date.uk_tax_year() == year)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    //One report for every tax year with a disposal, in order
+    pub fn report(&self) -> Vec<TaxYearReport> {
+        let mut years: Vec<i32> = self
+            .disposals
+            .iter()
+            .map(|d| d.date.uk_tax_year())
+            .collect();
+        years.dedup();
+        years.into_iter().map(|year| self.tax_year(year)).collect()
+    }
+
+    fn day_number(date: &DateTime) -> i64 {
+        (**date).div_euclid(Self::DAY)
+    }
+
+    //Rate can be quoted in either direction
+    fn sterling_rate(currency: &Currency, date: &DateTime, rates: &[FxRate]) -> Option<f64> {
+        if *currency == Currency::GBP {
+            return Some(1.0);
+        }
+        rates
+            .iter()
+            .filter(|rate| rate.date <= *date)
+            .filter_map(|rate| {
+                if rate.from == *currency && rate.to == Currency::GBP {
+                    Some((&rate.date, rate.rate))
+                } else if rate.from == Currency::GBP && rate.to == *currency {
+                    Some((&rate.date, 1.0 / rate.rate))
+                } else {
+                    None
+                }
+            })
+            .max_by(|a, b| a.0.cmp(b.0))
+            .map(|(_date, rate)| rate)
+    }
+
+    //Records quantity of the disposal on day as matched at cost, the caller reduces the
+    //acquisition that was matched
+    fn dispose(&mut self, symbol: &str, day: &mut Day, quantity: f64, cost: f64, rule: MatchRule) {
+        let proceeds = day.proceeds * quantity / day.disposed;
+        day.proceeds -= proceeds;
+        day.disposed -= quantity;
+        self.disposals.push(Disposal {
+            symbol: symbol.to_string(),
+            date: day.date.clone().unwrap(),
+            quantity: PortfolioQty::from(quantity),
+            proceeds: CashValue::from(proceeds),
+            cost: CashValue::from(cost),
+            rule,
+        });
+    }
+
+    fn match_same_day(&mut self, symbol: &str, days: &mut BTreeMap<i64, Day>) {
+        for day in days.values_mut() {
+            let matched = day.acquired.min(day.disposed);
+            if matched > 0.0 {
+                let cost = day.cost * matched / day.acquired;
+                day.acquired -= matched;
+                day.cost -= cost;
+                self.dispose(symbol, day, matched, cost, MatchRule::SameDay);
+            }
+        }
+    }
+
+    fn match_bed_and_breakfast(&mut self, symbol: &str, days: &mut BTreeMap<i64, Day>) {
+        let numbers: Vec<i64> = days.keys().cloned().collect();
+        for number in &numbers {
+            for later in numbers.iter().filter(|later| {
+                **later > *number && **later - number <= Self::BED_AND_BREAKFAST_DAYS
+            }) {
+                let (acquired, acquisition_cost) = {
+                    let day = &days[later];
+                    (day.acquired, day.cost)
+                };
+                let matched = acquired.min(days[number].disposed);
+                if matched <= 0.0 {
+                    continue;
+                }
+                let cost = acquisition_cost * matched / acquired;
+                let acquisition = days.get_mut(later).unwrap();
+                acquisition.acquired -= matched;
+                acquisition.cost -= cost;
+                let disposal = days.get_mut(number).unwrap();
+                self.dispose(symbol, disposal, matched, cost, MatchRule::BedAndBreakfast);
+            }
+        }
+    }
+
+    fn match_section_104(
+        &mut self,
+        symbols: &[String],
+        days: &HashMap<String, BTreeMap<i64, Day>>,
+        actions: &[CorporateAction],
+    ) {
+        let mut events: Vec<(i64, u8, PoolEvent)> = actions
+            .iter()
+            .map(|action| {
+                let number = Self::day_number(action.date());
+                (number, 0, PoolEvent::Action(action))
+            })
+            .collect();
+        for symbol in symbols {
+            for (number, day) in &days[symbol] {
+                events.push((*number, 1, PoolEvent::Day(symbol, day)));
+            }
+        }
+        //Sort is stable so actions on the same day are applied in the order given
+        events.sort_by_key(|(number, order, _event)| (*number, *order));
+
+        for (_number, _order, event) in events {
+            match event {
+                PoolEvent::Action(action) => self.apply_action(action),
+                PoolEvent::Day(symbol, day) => {
+                    let (pool_qty, pool_cost) = self.pools.entry(symbol.to_string()).or_default();
+                    *pool_qty += day.acquired;
+                    *pool_cost += day.cost;
+                    let matched = day.disposed.min(*pool_qty);
+                    let mut day = day.clone();
+                    if matched > Self::TOLERANCE {
+                        let cost = *pool_cost * matched / *pool_qty;
+                        *pool_qty -= matched;
+                        *pool_cost -= cost;
+                        self.dispose(symbol, &mut day, matched, cost, MatchRule::Section104);
+                    }
+                    if day.disposed > Self::TOLERANCE {
+                        self.unmatched.push(UnmatchedDisposal {
+                            symbol: symbol.to_string(),
+                            date: day.date.clone().unwrap(),
+                            quantity: PortfolioQty::from(day.disposed),
+                            proceeds: CashValue::from(day.proceeds),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply_action(&mut self, action: &CorporateAction) {
+        match action {
+            //Split changes the number of shares but not the total cost
+            CorporateAction::Split(split) => {
+                if let Some((pool_qty, _pool_cost)) = self.pools.get_mut(&split.symbol) {
+                    *pool_qty *= split.ratio;
+                }
+            }
+            CorporateAction::StockMerger(merger) => {
+                if let Some((qty, cost)) = self.pools.remove(&merger.symbol) {
+                    let (pool_qty, pool_cost) =
+                        self.pools.entry(merger.new_symbol.clone()).or_default();
+                    *pool_qty += qty * merger.ratio;
+                    *pool_cost += cost;
+                }
+            }
+            CorporateAction::SpinOff(spin_off) => {
+                if let Some((qty, cost)) = self.pools.get_mut(&spin_off.symbol) {
+                    let moved_qty = *qty * spin_off.ratio;
+                    let moved_cost = *cost * spin_off.cost_allocation;
+                    *cost -= moved_cost;
+                    let (pool_qty, pool_cost) =
+                        self.pools.entry(spin_off.new_symbol.clone()).or_default();
+                    *pool_qty += moved_qty;
+                    *pool_cost += moved_cost;
+                }
+            }
+            //Positions are closed with a trade
+            CorporateAction::CashMerger(_) | CorporateAction::Delisting(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MatchRule, UkCapitalGains};
+    use crate::broker::{CorporateAction, FxRate, SpinOff, Split, StockMerger, Trade, TradeType};
+    use crate::types::Currency;
+
+    const DAY: i64 = 86_400;
+    //2023-04-06, the first day of the 2023 tax year
+    const START: i64 = 1_680_739_200;
+
+    fn trade(value: f64, quantity: f64, day: i64, typ: TradeType) -> Trade {
+        trade_in("ABC", value, quantity, day, typ)
+    }
+
+    fn trade_in(symbol: &str, value: f64, quantity: f64, day: i64, typ: TradeType) -> Trade {
+        Trade::new_with_currency(
+            symbol,
+            value,
+            quantity,
+            START + day * DAY,
+            typ,
+            Currency::GBP,
+        )
+    }
+
+    #[test]
+    fn test_that_disposals_are_matched_same_day_then_bed_and_breakfast_then_pool() {
+        let trades = vec![
+            trade(1000.0, 100.0, 0, TradeType::Buy),
+            trade(3000.0, 100.0, 10, TradeType::Buy),
+            trade(1500.0, 100.0, 20, TradeType::Sell),
+            trade(600.0, 50.0, 20, TradeType::Buy),
+            trade(800.0, 20.0, 45, TradeType::Buy),
+            trade(1500.0, 100.0, 50, TradeType::Sell),
+        ];
+        let gains = UkCapitalGains::new(&trades, &[], &[]);
+        let disposals = gains.disposals();
+        assert_eq!(disposals.len(), 4);
+
+        assert_eq!(disposals[0].rule, MatchRule::SameDay);
+        assert_eq!(*disposals[0].quantity, 50.0);
+        assert_eq!(*disposals[0].gain(), 150.0);
+        //Purchase 25 days later is matched next, and the remaining 30 from the pool of 200 shares
+        //costing 4000
+        assert_eq!(disposals[1].rule, MatchRule::BedAndBreakfast);
+        assert_eq!(*disposals[1].quantity, 20.0);
+        assert_eq!(*disposals[1].gain(), -500.0);
+        assert_eq!(disposals[2].rule, MatchRule::Section104);
+        assert_eq!(*disposals[2].gain(), -150.0);
+        //Bed-and-breakfast only looks forward, so the second disposal is matched from the pool
+        assert_eq!(disposals[3].rule, MatchRule::Section104);
+        assert_eq!(*disposals[3].gain(), -500.0);
+
+        let (qty, cost) = gains.pool("ABC").unwrap();
+        assert_eq!(*qty, 70.0);
+        assert!((*cost - 1400.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_that_repurchase_within_thirty_days_is_matched_first() {
+        let trades = vec![
+            trade(1000.0, 100.0, 0, TradeType::Buy),
+            trade(2000.0, 100.0, 10, TradeType::Sell),
+            trade(1200.0, 100.0, 40, TradeType::Buy),
+            trade(1500.0, 100.0, 41, TradeType::Buy),
+        ];
+        let gains = UkCapitalGains::new(&trades, &[], &[]);
+        let disposals = gains.disposals();
+        assert_eq!(disposals.len(), 1);
+        assert_eq!(disposals[0].rule, MatchRule::BedAndBreakfast);
+        assert_eq!(*disposals[0].gain(), 800.0);
+
+        let (qty, cost) = gains.pool("ABC").unwrap();
+        assert_eq!(*qty, 200.0);
+        assert_eq!(*cost, 2500.0);
+    }
+
+    #[test]
+    fn test_that_report_splits_gains_by_tax_year_and_applies_splits() {
+        let trades = vec![
+            trade(1000.0, 100.0, -10, TradeType::Buy),
+            trade(700.0, 50.0, -5, TradeType::Sell),
+            trade(1200.0, 100.0, 100, TradeType::Sell),
+        ];
+        let split = CorporateAction::from(Split::new("ABC", 4.0, START + 50 * DAY));
+        let gains = UkCapitalGains::new(&trades, &[split], &[]);
+
+        let report = gains.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].year, 2022);
+        assert_eq!(*report[0].net_gain(), 200.0);
+        //Pool of 50 shares costing 500 becomes 200 shares after the split
+        assert_eq!(report[1].year, 2023);
+        assert_eq!(*report[1].gains(), 950.0);
+        assert_eq!(*report[1].losses(), 0.0);
+        assert_eq!(*gains.pool("ABC").unwrap().0, 100.0);
+    }
+
+    #[test]
+    fn test_that_mergers_and_spin_offs_carry_cost_into_new_pools() {
+        let trades = vec![
+            trade(1000.0, 100.0, 0, TradeType::Buy),
+            trade_in("DEF", 1000.0, 200.0, 100, TradeType::Sell),
+            trade_in("XYZ", 300.0, 50.0, 100, TradeType::Sell),
+        ];
+        let actions = vec![
+            SpinOff::new("ABC", "XYZ", 0.5, 0.2, START + 10 * DAY).into(),
+            StockMerger::new("ABC", "DEF", 2.0, START + 20 * DAY).into(),
+        ];
+        let gains = UkCapitalGains::new(&trades, &actions, &[]);
+
+        //Spin-off takes a fifth of the cost, and the rest moves into the merged symbol
+        let disposals = gains.disposals();
+        assert_eq!(disposals.len(), 2);
+        assert_eq!(disposals[0].symbol, "DEF");
+        assert_eq!(*disposals[0].gain(), 200.0);
+        assert_eq!(disposals[1].symbol, "XYZ");
+        assert_eq!(*disposals[1].gain(), 100.0);
+        assert!(gains.pool("ABC").is_none());
+        assert_eq!(*gains.pool("DEF").unwrap().0, 0.0);
+    }
+
+    #[test]
+    fn test_that_trades_are_converted_into_sterling_at_the_rate_on_the_trade_date() {
+        let trades = vec![
+            Trade::new_with_currency("ABC", 1000.0, 100.0, START, TradeType::Buy, Currency::USD),
+            Trade::new_with_currency(
+                "ABC",
+                1500.0,
+                100.0,
+                START + 50 * DAY,
+                TradeType::Sell,
+                Currency::USD,
+            ),
+        ];
+        let rates = vec![
+            FxRate::new(Currency::USD, Currency::GBP, 0.8, START),
+            FxRate::new(Currency::GBP, Currency::USD, 1.5, START + 40 * DAY),
+            FxRate::new(Currency::USD, Currency::GBP, 0.5, START + 60 * DAY),
+        ];
+        let gains = UkCapitalGains::new(&trades, &[], &rates);
+
+        let disposals = gains.disposals();
+        assert_eq!(*disposals[0].cost, 800.0);
+        assert_eq!(*disposals[0].proceeds, 1000.0);
+        assert!(gains.unconverted().is_empty());
+    }
+
+    #[test]
+    fn test_that_trades_without_a_rate_are_reported_and_not_matched() {
+        let trades = vec![
+            Trade::new_with_currency("ABC", 1000.0, 100.0, START, TradeType::Buy, Currency::USD),
+            Trade::new_with_currency(
+                "ABC",
+                1500.0,
+                100.0,
+                START + 50 * DAY,
+                TradeType::Sell,
+                Currency::USD,
+            ),
+        ];
+        //Rate is only known after the purchase
+        let rates = vec![FxRate::new(
+            Currency::USD,
+            Currency::GBP,
+            0.8,
+            START + 40 * DAY,
+        )];
+        let gains = UkCapitalGains::new(&trades, &[], &rates);
+
+        assert_eq!(gains.unconverted().len(), 1);
+        assert!(gains.disposals().is_empty());
+        let unmatched = gains.unmatched();
+        assert_eq!(unmatched.len(), 1);
+        assert_eq!(*unmatched[0].proceeds, 1200.0);
+    }
+
+    #[test]
+    fn test_that_disposal_beyond_shares_held_is_unmatched() {
+        let trades = vec![
+            trade(1000.0, 100.0, 0, TradeType::Buy),
+            trade(3000.0, 150.0, 10, TradeType::Sell),
+        ];
+        let gains = UkCapitalGains::new(&trades, &[], &[]);
+
+        //Only the shares held are matched, the short sale of the rest has no cost
+        let disposals = gains.disposals();
+        assert_eq!(disposals.len(), 1);
+        assert_eq!(*disposals[0].quantity, 100.0);
+        assert_eq!(*disposals[0].gain(), 1000.0);
+        let unmatched = gains.unmatched();
+        assert_eq!(unmatched.len(), 1);
+        assert_eq!(*unmatched[0].quantity, 50.0);
+        assert_eq!(*unmatched[0].proceeds, 1000.0);
+        assert_eq!(*gains.pool("ABC").unwrap().0, 0.0);
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+
+use super::{BrokerRecordedEvent, CorporateAction, Trade, TradeType};
+use crate::types::{CashValue, DateTime, PortfolioQty, Price};
+
+///Method used to choose which tax lots are relieved when a position is reduced.
+///
+///FIFO relieves the oldest lot first and LIFO the newest. HIFO relieves the lot that realises the
+///smallest gain: the lot with the highest cost per share or, for short positions, the lot that was
+///sold at the lowest price.
+///
+///With specific identification, lots chosen by the client are relieved first, in the order given,
+///and any remainder is relieved FIFO.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum LotMethod {
+    #[default]
+    Fifo,
+    Lifo,
+    Hifo,
+    Specific,
+}
+
+///Shares of a security acquired in a single trade. Lots keep their id and acquisition date through
+///splits and stock mergers, and lots created by a spin-off take the date of the original lot.
+///
+///Quantity is negative for short positions, and the cost is then the proceeds of the short sale.
+///Cost includes trade costs and is in the currency of the trade.
+#[derive(Clone, Debug)]
+pub struct TaxLot {
+    pub id: u64,
+    pub symbol: String,
+    pub quantity: PortfolioQty,
+    pub cost: CashValue,
+    pub date: DateTime,
+}
+
+impl TaxLot {
+    pub fn price(&self) -> Price {
+        Price::from(*self.cost / self.quantity.abs())
+    }
+}
+
+///Gain realised on the shares relieved from a single [TaxLot]. Proceeds are net of trade costs,
+///and cost includes them.
+///
+///For short positions, the proceeds are from the short sale and the cost is the cost of buying the
+///shares back. Acquired is always the date the lot was opened, so the holding period is positive.
+#[derive(Clone, Debug)]
+pub struct RealisedGain {
+    pub lot_id: u64,
+    pub symbol: String,
+    pub quantity: PortfolioQty,
+    pub proceeds: CashValue,
+    pub cost: CashValue,
+    pub acquired: DateTime,
+    pub disposed: DateTime,
+}
+
+impl RealisedGain {
+    pub fn gain(&self) -> CashValue {
+        CashValue::from(*self.proceeds - *self.cost)
+    }
+
+    //In seconds
+    pub fn holding_period(&self) -> i64 {
+        *self.disposed - *self.acquired
+    }
+}
+
+///Lots chosen by the client to be relieved first when the position in a symbol is next reduced,
+///only used with [LotMethod::Specific]. Replaces any earlier selection for the symbol.
+#[derive(Clone, Debug)]
+pub struct LotSelection {
+    pub symbol: String,
+    pub lot_ids: Vec<u64>,
+    pub date: DateTime,
+}
+
+impl LotSelection {
+    pub fn new(symbol: impl Into<String>, lot_ids: Vec<u64>, date: impl Into<DateTime>) -> Self {
+        Self {
+            symbol: symbol.into(),
+            lot_ids,
+            date: date.into(),
+        }
+    }
+}
+
+///Open tax lots and realised gains, built by replaying the events recorded by a broker. Ids are
+///assigned in the order that lots are opened so are stable for the same sequence of events.
+#[derive(Clone, Debug, Default)]
+pub struct TaxLots {
+    method: LotMethod,
+    next_id: u64,
+    lots: Vec<TaxLot>,
+    realised: Vec<RealisedGain>,
+    selections: HashMap<String, Vec<u64>>,
+}
+
+impl TaxLots {
+    //Quantities smaller than this are treated as zero so that lots aren't left open by floating
+    //point error
+    const TOLERANCE: f64 = 1e-9;
+
+    pub fn new(method: LotMethod) -> Self {
+        Self {
+            method,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_events(method: LotMethod, events: &[BrokerRecordedEvent]) -> Self {
+        let mut lots = Self::new(method);
+        for event in events {
+            lots.apply(event);
+        }
+        lots
+    }
+
+    //Returns the gains realised by this event, events that don't change lots are ignored
+    pub fn apply(&mut self, event: &BrokerRecordedEvent) -> Vec<RealisedGain> {
+        let realised = self.realised.len();
+        match event {
+            BrokerRecordedEvent::TradeCompleted(trade)
+            | BrokerRecordedEvent::DividendReinvested(trade) => self.trade(trade),
+            BrokerRecordedEvent::CorporateAction(action) => self.corporate_action(action),
+            BrokerRecordedEvent::LotsSelected(selection) => {
+                self.selections
+                    .insert(selection.symbol.clone(), selection.lot_ids.clone());
+            }
+            _ => (),
+        }
+        self.realised[realised..].to_vec()
+    }
+
+    pub fn open_lots(&self, symbol: &str) -> Vec<TaxLot> {
+        self.lots
+            .iter()
+            .filter(|lot| lot.symbol == symbol)
+            .cloned()
+            .collect()
+    }
+
+    pub fn realised(&self) -> Vec<RealisedGain> {
+        self.realised.clone()
+    }
+
+    pub fn lot(&self, lot_id: u64) -> Option<&TaxLot> {
+        self.lots.iter().find(|lot| lot.id == lot_id)
+    }
+
+    //Id that will be given to the next lot opened
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    //Adds to the cost of an open lot, used to carry a disallowed loss into a replacement lot
+    pub fn adjust_cost(&mut self, lot_id: u64, adjustment: f64) {
+        if let Some(lot) = self.lots.iter_mut().find(|lot| lot.id == lot_id) {
+            lot.cost = CashValue::from(*lot.cost + adjustment);
+        }
+    }
+
+    //Trades reduce lots on the other side of the position before a new lot is opened
+    fn trade(&mut self, trade: &Trade) {
+        let is_buy = matches!(trade.typ, TradeType::Buy);
+        let quantity = *trade.quantity;
+        if quantity <= 0.0 {
+            return;
+        }
+        let unit_value = *trade.value / quantity;
+        let unit_costs = *trade.costs / quantity;
+
+        let mut remaining = quantity;
+        while remaining > Self::TOLERANCE {
+            let position = match self.next_lot(&trade.symbol, !is_buy) {
+                Some(position) => position,
+                None => break,
+            };
+            let lot = &mut self.lots[position];
+            let lot_qty = lot.quantity.abs();
+            let relieved = lot_qty.min(remaining);
+            let lot_cost = *lot.cost * relieved / lot_qty;
+            let (proceeds, cost) = if is_buy {
+                (lot_cost, relieved * (unit_value + unit_costs))
+            } else {
+                (relieved * (unit_value - unit_costs), lot_cost)
+            };
+            self.realised.push(RealisedGain {
+                lot_id: lot.id,
+                symbol: lot.symbol.clone(),
+                quantity: PortfolioQty::from(relieved),
+                proceeds: CashValue::from(proceeds),
+                cost: CashValue::from(cost),
+                acquired: lot.date.clone(),
+                disposed: trade.date.clone(),
+            });
+
+            let signed = if *lot.quantity > 0.0 {
+                relieved
+            } else {
+                -relieved
+            };
+            lot.quantity = PortfolioQty::from(*lot.quantity - signed);
+            lot.cost = CashValue::from(*lot.cost - lot_cost);
+            if lot.quantity.abs() < Self::TOLERANCE {
+                self.lots.remove(position);
+            }
+            remaining -= relieved;
+        }
+
+        if remaining > Self::TOLERANCE {
+            let (signed, cost) = if is_buy {
+                (remaining, remaining * (unit_value + unit_costs))
+            } else {
+                (-remaining, remaining * (unit_value - unit_costs))
+            };
+            self.open(&trade.symbol, signed, cost, trade.date.clone());
+        }
+    }
+
+    fn open(&mut self, symbol: &str, quantity: f64, cost: f64, date: DateTime) {
+        self.lots.push(TaxLot {
+            id: self.next_id,
+            symbol: symbol.to_string(),
+            quantity: PortfolioQty::from(quantity),
+            cost: CashValue::from(cost),
+            date,
+        });
+        self.next_id += 1;
+    }
+
+    //Position in the list of open lots of the next lot to relieve
+    fn next_lot(&self, symbol: &str, is_long: bool) -> Option<usize> {
+        let candidates =
+            self.lots.iter().enumerate().filter(|(_position, lot)| {
+                lot.symbol == symbol && (*lot.quantity > 0.0) == is_long
+            });
+
+        let by_date = |lot: &TaxLot| (*lot.date, lot.id);
+        let oldest = || {
+            candidates
+                .clone()
+                .min_by_key(|(_position, lot)| by_date(lot))
+                .map(|(position, _lot)| position)
+        };
+        match self.method {
+            LotMethod::Fifo => oldest(),
+            LotMethod::Lifo => candidates
+                .max_by_key(|(_position, lot)| by_date(lot))
+                .map(|(position, _lot)| position),
+            LotMethod::Hifo => {
+                let best = if is_long {
+                    candidates.max_by(|(_, a), (_, b)| a.price().partial_cmp(&b.price()).unwrap())
+                } else {
+                    candidates.min_by(|(_, a), (_, b)| a.price().partial_cmp(&b.price()).unwrap())
+                };
+                best.map(|(position, _lot)| position)
+            }
+            LotMethod::Specific => {
+                let selected = self.selections.get(symbol).and_then(|lot_ids| {
+                    lot_ids.iter().find_map(|lot_id| {
+                        candidates
+                            .clone()
+                            .find(|(_position, lot)| lot.id == *lot_id)
+                            .map(|(position, _lot)| position)
+                    })
+                });
+                selected.or_else(oldest)
+            }
+        }
+    }
+
+    fn corporate_action(&mut self, action: &CorporateAction) {
+        match action {
+            CorporateAction::Split(split) => {
+                for lot in self
+                    .lots
+                    .iter_mut()
+                    .filter(|lot| lot.symbol == split.symbol)
+                {
+                    lot.quantity = PortfolioQty::from(*lot.quantity * split.ratio);
+                }
+            }
+            CorporateAction::StockMerger(merger) => {
+                for lot in self
+                    .lots
+                    .iter_mut()
+                    .filter(|lot| lot.symbol == merger.symbol)
+                {
+                    lot.symbol = merger.new_symbol.clone();
+                    lot.quantity = PortfolioQty::from(*lot.quantity * merger.ratio);
+                }
+            }
+            CorporateAction::SpinOff(spin_off) => {
+                let parents = self.open_lots(&spin_off.symbol);
+                for parent in parents {
+                    let moved_cost = *parent.cost * spin_off.cost_allocation;
+                    if let Some(lot) = self.lots.iter_mut().find(|lot| lot.id == parent.id) {
+                        lot.cost = CashValue::from(*lot.cost - moved_cost);
+                    }
+                    self.open(
+                        &spin_off.new_symbol,
+                        *parent.quantity * spin_off.ratio,
+                        moved_cost,
+                        parent.date,
+                    );
+                }
+            }
+            //Positions are closed with a trade
+            CorporateAction::CashMerger(_) | CorporateAction::Delisting(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LotMethod, LotSelection, TaxLots};
+    use crate::broker::{BrokerRecordedEvent, CorporateAction, SpinOff, Split, Trade, TradeType};
+
+    fn buy(value: f64, quantity: f64, date: i64) -> BrokerRecordedEvent {
+        Trade::new("ABC", value, quantity, date, TradeType::Buy).into()
+    }
+
+    fn sell(value: f64, quantity: f64, date: i64) -> BrokerRecordedEvent {
+        Trade::new("ABC", value, quantity, date, TradeType::Sell).into()
+    }
+
+    fn events() -> Vec<BrokerRecordedEvent> {
+        vec![
+            buy(1000.0, 10.0, 100),
+            buy(3000.0, 10.0, 101),
+            buy(2000.0, 10.0, 102),
+            sell(2500.0, 10.0, 103),
+        ]
+    }
+
+    #[test]
+    fn test_that_lot_methods_relieve_expected_lots() {
+        let fifo = TaxLots::from_events(LotMethod::Fifo, &events());
+        let lifo = TaxLots::from_events(LotMethod::Lifo, &events());
+        let hifo = TaxLots::from_events(LotMethod::Hifo, &events());
+
+        assert_eq!(fifo.realised()[0].lot_id, 0);
+        assert_eq!(*fifo.realised()[0].gain(), 1500.0);
+        assert_eq!(fifo.realised()[0].holding_period(), 3);
+        assert_eq!(lifo.realised()[0].lot_id, 2);
+        assert_eq!(*lifo.realised()[0].gain(), 500.0);
+        assert_eq!(hifo.realised()[0].lot_id, 1);
+        assert_eq!(*hifo.realised()[0].gain(), -500.0);
+        assert_eq!(hifo.open_lots("ABC").len(), 2);
+    }
+
+    #[test]
+    fn test_that_sale_across_lots_realises_gain_for_each_lot() {
+        let mut events = events();
+        events.push(sell(3000.0, 15.0, 104));
+        let lots = TaxLots::from_events(LotMethod::Fifo, &events);
+
+        let realised = lots.realised();
+        assert_eq!(realised.len(), 3);
+        assert_eq!(*realised[1].quantity, 10.0);
+        assert_eq!(*realised[1].gain(), -1000.0);
+        assert_eq!(*realised[2].quantity, 5.0);
+        assert_eq!(*realised[2].cost, 1000.0);
+
+        let open = lots.open_lots("ABC");
+        assert_eq!(open.len(), 1);
+        assert_eq!(*open[0].quantity, 5.0);
+        assert_eq!(*open[0].cost, 1000.0);
+    }
+
+    #[test]
+    fn test_that_specific_lots_are_relieved_before_fifo() {
+        let mut events = events();
+        events.insert(3, LotSelection::new("ABC", vec![2], 103).into());
+        events.push(sell(3000.0, 15.0, 104));
+        let lots = TaxLots::from_events(LotMethod::Specific, &events);
+
+        let realised = lots.realised();
+        assert_eq!(realised[0].lot_id, 2);
+        assert_eq!(realised[1].lot_id, 0);
+        assert_eq!(realised[2].lot_id, 1);
+    }
+
+    #[test]
+    fn test_that_short_lots_are_closed_by_buys() {
+        let events = vec![sell(1000.0, 10.0, 100), buy(800.0, 10.0, 101)];
+        let lots = TaxLots::from_events(LotMethod::Fifo, &events);
+
+        let realised = lots.realised();
+        assert_eq!(realised.len(), 1);
+        assert_eq!(*realised[0].gain(), 200.0);
+        assert!(lots.open_lots("ABC").is_empty());
+    }
+
+    #[test]
+    fn test_that_corporate_actions_restate_lots() {
+        let events = vec![
+            buy(1000.0, 10.0, 100),
+            CorporateAction::from(Split::new("ABC", 2.0, 101)).into(),
+            CorporateAction::from(SpinOff::new("ABC", "BCD", 0.5, 0.25, 102)).into(),
+        ];
+        let lots = TaxLots::from_events(LotMethod::Fifo, &events);
+
+        let abc = lots.open_lots("ABC");
+        assert_eq!(*abc[0].quantity, 20.0);
+        assert_eq!(*abc[0].cost, 750.0);
+        let bcd = lots.open_lots("BCD");
+        assert_eq!(*bcd[0].quantity, 10.0);
+        assert_eq!(*bcd[0].cost, 250.0);
+        assert_eq!(bcd[0].date, 100.into());
+    }
+}
@@ -1,20 +1,33 @@
 use log::info;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Formatter;
 use std::{cmp::Ordering, fmt::Display};
 
 use crate::types::{
-    CashValue, DateTime, PortfolioAllocation, PortfolioHoldings, PortfolioQty, PortfolioValues,
-    Price,
+    CashValue, Currency, DateTime, Frequency, PortfolioAllocation, PortfolioHoldings, PortfolioQty,
+    PortfolioValues, Price,
 };
+use lots::{LotSelection, RealisedGain};
+use wash::WashSale;
 
+pub mod cgt;
+pub mod lots;
+pub mod pnl;
 pub mod record;
+pub mod wash;
 
 //Contains data structures and traits that refer solely to the data held and operations required
 //for broker implementations.
 
 /// Represents a point-in-time quote of both sides of the market (bid+offer) from an exchange.
 ///
+/// Prices are in the currency that the security trades in, quotes created without a currency
+/// are in the default [Currency].
+///
+/// Volume is optional and is only used by exchanges that limit the size of trades relative to
+/// the traded volume in the period.
+///
 /// Equality checked against ticker and date. Ordering against date only.
 ///
 /// let q = Quote::new(
@@ -24,6 +37,22 @@ pub mod record;
 ///   "ABC"
 /// );
 ///
+/// let q1 = Quote::new_with_currency(
+///   10.0,
+///   11.0,
+///   100,
+///   "ABC",
+///   Currency::GBP,
+/// );
+///
+/// let q2 = Quote::new_with_volume(
+///   10.0,
+///   11.0,
+///   100,
+///   "ABC",
+///   1000.0,
+/// );
+///
 #[derive(Clone, Debug)]
 pub struct Quote {
     //TODO: more indirection is needed for this type, possibly implemented as trait
@@ -31,6 +60,8 @@ pub struct Quote {
     pub ask: Price,
     pub date: DateTime,
     pub symbol: String,
+    pub currency: Currency,
+    pub volume: Option<f64>,
 }
 
 impl Quote {
@@ -39,14 +70,38 @@ impl Quote {
         ask: impl Into<Price>,
         date: impl Into<DateTime>,
         symbol: impl Into<String>,
+    ) -> Self {
+        Self::new_with_currency(bid, ask, date, symbol, Currency::default())
+    }
+
+    pub fn new_with_currency(
+        bid: impl Into<Price>,
+        ask: impl Into<Price>,
+        date: impl Into<DateTime>,
+        symbol: impl Into<String>,
+        currency: impl Into<Currency>,
     ) -> Self {
         Self {
             bid: bid.into(),
             ask: ask.into(),
             date: date.into(),
             symbol: symbol.into(),
+            currency: currency.into(),
+            volume: None,
         }
     }
+
+    pub fn new_with_volume(
+        bid: impl Into<Price>,
+        ask: impl Into<Price>,
+        date: impl Into<DateTime>,
+        symbol: impl Into<String>,
+        volume: f64,
+    ) -> Self {
+        let mut quote = Self::new(bid, ask, date, symbol);
+        quote.volume = Some(volume);
+        quote
+    }
 }
 
 impl Ord for Quote {
@@ -69,7 +124,151 @@ impl PartialEq for Quote {
     }
 }
 
-///Represents a single dividend payment in per-share terms.
+/// Represents the open, high, low and close prices, and the volume traded, over a period that
+/// ends at `date`. Bars have a single price, there is no spread.
+///
+/// Equality checked against ticker and date. Ordering against date only.
+///
+/// let b = Bar::new(
+///   10.0,
+///   12.0,
+///   9.0,
+///   11.0,
+///   100,
+///   "ABC",
+/// );
+#[derive(Clone, Debug)]
+pub struct Bar {
+    pub open: Price,
+    pub high: Price,
+    pub low: Price,
+    pub close: Price,
+    pub date: DateTime,
+    pub symbol: String,
+    pub currency: Currency,
+    pub volume: Option<f64>,
+}
+
+impl Bar {
+    pub fn new(
+        open: impl Into<Price>,
+        high: impl Into<Price>,
+        low: impl Into<Price>,
+        close: impl Into<Price>,
+        date: impl Into<DateTime>,
+        symbol: impl Into<String>,
+    ) -> Self {
+        Self {
+            open: open.into(),
+            high: high.into(),
+            low: low.into(),
+            close: close.into(),
+            date: date.into(),
+            symbol: symbol.into(),
+            currency: Currency::default(),
+            volume: None,
+        }
+    }
+
+    //Quote with no spread at a price within the bar, used to execute and value against bars
+    pub fn quote_at(&self, price: &Price) -> Quote {
+        let mut quote = Quote::new_with_currency(
+            price.clone(),
+            price.clone(),
+            self.date.clone(),
+            &self.symbol,
+            self.currency,
+        );
+        quote.volume = self.volume;
+        quote
+    }
+}
+
+impl Ord for Bar {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.date.cmp(&other.date)
+    }
+}
+
+impl PartialOrd for Bar {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Eq for Bar {}
+
+impl PartialEq for Bar {
+    fn eq(&self, other: &Self) -> bool {
+        self.date == other.date && self.symbol == other.symbol
+    }
+}
+
+///Represents the rate at which one unit of the `from` currency is exchanged into the `to` currency
+///at a point in time. The rate can be used in either direction, brokers do not need a quote for
+///the inverse pair.
+///
+///let fx = FxRate::new(
+///  Currency::USD,
+///  Currency::GBP,
+///  0.8,
+///  100,
+///);
+#[derive(Clone, Debug)]
+pub struct FxRate {
+    pub from: Currency,
+    pub to: Currency,
+    pub rate: f64,
+    pub date: DateTime,
+}
+
+impl FxRate {
+    pub fn new(
+        from: impl Into<Currency>,
+        to: impl Into<Currency>,
+        rate: f64,
+        date: impl Into<DateTime>,
+    ) -> Self {
+        Self {
+            from: from.into(),
+            to: to.into(),
+            rate,
+            date: date.into(),
+        }
+    }
+}
+
+///Represents the annual rate of interest paid on cash held in a currency, from the date of the rate
+///until the next rate for that currency.
+///
+///let r = InterestRate::new(
+///  Currency::USD,
+///  0.05,
+///  100,
+///);
+#[derive(Clone, Debug)]
+pub struct InterestRate {
+    pub currency: Currency,
+    pub rate: f64,
+    pub date: DateTime,
+}
+
+impl InterestRate {
+    pub fn new(currency: impl Into<Currency>, rate: f64, date: impl Into<DateTime>) -> Self {
+        Self {
+            currency: currency.into(),
+            rate,
+            date: date.into(),
+        }
+    }
+}
+
+///Represents a single dividend payment in per-share terms. Value is in the same currency as the
+///quotes for the symbol.
+///
+///Date is the ex-date: the dividend is paid on positions held at the start of the ex-date, and
+///positions opened on the ex-date are not entitled. Cash is paid on the pay date, which is the same
+///as the ex-date unless created with `new_with_pay_date`.
 ///
 ///Equality checked against ticker and date. Ordering against date only.
 ///
@@ -84,6 +283,7 @@ pub struct Dividend {
     pub value: Price,
     pub symbol: String,
     pub date: DateTime,
+    pub pay_date: DateTime,
 }
 
 impl Dividend {
@@ -92,10 +292,31 @@ impl Dividend {
         symbol: impl Into<String>,
         date: impl Into<DateTime>,
     ) -> Self {
+        let date = date.into();
         Self {
             value: value.into(),
             symbol: symbol.into(),
-            date: date.into(),
+            date: date.clone(),
+            pay_date: date,
+        }
+    }
+
+    pub fn new_with_pay_date(
+        value: impl Into<Price>,
+        symbol: impl Into<String>,
+        ex_date: impl Into<DateTime>,
+        pay_date: impl Into<DateTime>,
+    ) -> Self {
+        let date = ex_date.into();
+        let pay_date = pay_date.into();
+        if pay_date < date {
+            panic!("Dividend cannot be paid before the ex-date");
+        }
+        Self {
+            value: value.into(),
+            symbol: symbol.into(),
+            date,
+            pay_date,
         }
     }
 }
@@ -123,6 +344,10 @@ impl PartialEq for Dividend {
 ///Represents a single dividend payment in cash terms. Type is used internally within broker and
 ///is used only to credit the cash balance. Shouldn't be used outside a broker impl.
 ///
+///Value is the cash received, net of any tax withheld. Withheld is the tax deducted at source, in
+///the same currency as the value, so the gross dividend is the sum of the two. Date is the pay
+///date.
+///
 ///Equality checked against ticker and date. Ordering against date only.
 ///
 ///let dp = DividendPayment::new(
@@ -135,6 +360,8 @@ pub struct DividendPayment {
     pub value: CashValue,
     pub symbol: String,
     pub date: DateTime,
+    pub currency: Currency,
+    pub withheld: CashValue,
 }
 
 impl DividendPayment {
@@ -142,13 +369,28 @@ impl DividendPayment {
         value: impl Into<CashValue>,
         symbol: impl Into<String>,
         date: impl Into<DateTime>,
+    ) -> Self {
+        Self::new_with_currency(value, symbol, date, Currency::default())
+    }
+
+    pub fn new_with_currency(
+        value: impl Into<CashValue>,
+        symbol: impl Into<String>,
+        date: impl Into<DateTime>,
+        currency: impl Into<Currency>,
     ) -> Self {
         Self {
             value: value.into(),
             symbol: symbol.into(),
             date: date.into(),
+            currency: currency.into(),
+            withheld: CashValue::from(0.0),
         }
     }
+
+    pub fn gross(&self) -> CashValue {
+        CashValue::from(*self.value + *self.withheld)
+    }
 }
 
 impl Ord for DividendPayment {
@@ -171,6 +413,216 @@ impl PartialEq for DividendPayment {
     }
 }
 
+///Stock split, on the date of the split every share held becomes `ratio` shares. Reverse splits
+///have a ratio below one.
+///
+///let split = Split::new("ABC", 2.0, 100);
+///let reverse_split = Split::new("ABC", 0.1, 100);
+#[derive(Clone, Debug, PartialEq)]
+pub struct Split {
+    pub symbol: String,
+    pub ratio: f64,
+    pub date: DateTime,
+}
+
+impl Split {
+    pub fn new(symbol: impl Into<String>, ratio: f64, date: impl Into<DateTime>) -> Self {
+        if ratio <= 0.0 {
+            panic!("Split ratio must be greater than zero");
+        }
+        Self {
+            symbol: symbol.into(),
+            ratio,
+            date: date.into(),
+        }
+    }
+}
+
+///Acquisition for cash, every share held is converted into `price` in cash in the currency that
+///the security is quoted in.
+///
+///let merger = CashMerger::new("ABC", 120.0, 100);
+#[derive(Clone, Debug, PartialEq)]
+pub struct CashMerger {
+    pub symbol: String,
+    pub price: Price,
+    pub date: DateTime,
+}
+
+impl CashMerger {
+    pub fn new(
+        symbol: impl Into<String>,
+        price: impl Into<Price>,
+        date: impl Into<DateTime>,
+    ) -> Self {
+        Self {
+            symbol: symbol.into(),
+            price: price.into(),
+            date: date.into(),
+        }
+    }
+}
+
+///Acquisition for shares, every share held becomes `ratio` shares of `new_symbol`. The cost of the
+///original position is carried over to the new position.
+///
+///let merger = StockMerger::new("ABC", "BCD", 0.5, 100);
+#[derive(Clone, Debug, PartialEq)]
+pub struct StockMerger {
+    pub symbol: String,
+    pub new_symbol: String,
+    pub ratio: f64,
+    pub date: DateTime,
+}
+
+impl StockMerger {
+    pub fn new(
+        symbol: impl Into<String>,
+        new_symbol: impl Into<String>,
+        ratio: f64,
+        date: impl Into<DateTime>,
+    ) -> Self {
+        if ratio <= 0.0 {
+            panic!("Merger ratio must be greater than zero");
+        }
+        Self {
+            symbol: symbol.into(),
+            new_symbol: new_symbol.into(),
+            ratio,
+            date: date.into(),
+        }
+    }
+}
+
+///New security distributed to holders, every share held receives `ratio` shares of `new_symbol`.
+///The original position is unchanged but `cost_allocation`, a fraction of the cost of the original
+///position, is moved to the new position.
+///
+///let spin_off = SpinOff::new("ABC", "BCD", 0.2, 0.1, 100);
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpinOff {
+    pub symbol: String,
+    pub new_symbol: String,
+    pub ratio: f64,
+    pub cost_allocation: f64,
+    pub date: DateTime,
+}
+
+impl SpinOff {
+    pub fn new(
+        symbol: impl Into<String>,
+        new_symbol: impl Into<String>,
+        ratio: f64,
+        cost_allocation: f64,
+        date: impl Into<DateTime>,
+    ) -> Self {
+        if ratio <= 0.0 {
+            panic!("Spin-off ratio must be greater than zero");
+        }
+        if !(0.0..=1.0).contains(&cost_allocation) {
+            panic!("Cost allocation must be between zero and one");
+        }
+        Self {
+            symbol: symbol.into(),
+            new_symbol: new_symbol.into(),
+            ratio,
+            cost_allocation,
+            date: date.into(),
+        }
+    }
+}
+
+///Security stops trading. If `cash_out` is true then positions are closed at the last price seen,
+///otherwise the position is written off with no value.
+///
+///let bankruptcy = Delisting::new("ABC", false, 100);
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delisting {
+    pub symbol: String,
+    pub cash_out: bool,
+    pub date: DateTime,
+}
+
+impl Delisting {
+    pub fn new(symbol: impl Into<String>, cash_out: bool, date: impl Into<DateTime>) -> Self {
+        Self {
+            symbol: symbol.into(),
+            cash_out,
+            date: date.into(),
+        }
+    }
+}
+
+///Events that change the shares of a security held by clients. Provided by the [DataSource] on the
+///date that they take effect, prices on that date should already reflect the action.
+///
+///Positions that are closed by an action are recorded as a sale in the [BrokerLog], at zero value
+///if the position is written off. Short positions in a delisted security are only closed if the
+///delisting is cashed out, otherwise they are left open as there is no price to buy back shares.
+///
+///[DataSource]: crate::input::DataSource
+///[BrokerLog]: record::BrokerLog
+#[derive(Clone, Debug, PartialEq)]
+pub enum CorporateAction {
+    Split(Split),
+    CashMerger(CashMerger),
+    StockMerger(StockMerger),
+    SpinOff(SpinOff),
+    Delisting(Delisting),
+}
+
+impl CorporateAction {
+    pub fn symbol(&self) -> &str {
+        match self {
+            CorporateAction::Split(split) => &split.symbol,
+            CorporateAction::CashMerger(merger) => &merger.symbol,
+            CorporateAction::StockMerger(merger) => &merger.symbol,
+            CorporateAction::SpinOff(spin_off) => &spin_off.symbol,
+            CorporateAction::Delisting(delisting) => &delisting.symbol,
+        }
+    }
+
+    pub fn date(&self) -> &DateTime {
+        match self {
+            CorporateAction::Split(split) => &split.date,
+            CorporateAction::CashMerger(merger) => &merger.date,
+            CorporateAction::StockMerger(merger) => &merger.date,
+            CorporateAction::SpinOff(spin_off) => &spin_off.date,
+            CorporateAction::Delisting(delisting) => &delisting.date,
+        }
+    }
+}
+
+impl From<Split> for CorporateAction {
+    fn from(split: Split) -> Self {
+        CorporateAction::Split(split)
+    }
+}
+
+impl From<CashMerger> for CorporateAction {
+    fn from(merger: CashMerger) -> Self {
+        CorporateAction::CashMerger(merger)
+    }
+}
+
+impl From<StockMerger> for CorporateAction {
+    fn from(merger: StockMerger) -> Self {
+        CorporateAction::StockMerger(merger)
+    }
+}
+
+impl From<SpinOff> for CorporateAction {
+    fn from(spin_off: SpinOff) -> Self {
+        CorporateAction::SpinOff(spin_off)
+    }
+}
+
+impl From<Delisting> for CorporateAction {
+    fn from(delisting: Delisting) -> Self {
+        CorporateAction::Delisting(delisting)
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum TradeType {
     Buy,
@@ -181,6 +633,12 @@ pub enum TradeType {
 ///client. This type is a pure internal representation, and clients do not pass trades to the
 ///broker to execute but pass an [Order] instaed.
 ///
+///Value is in the currency of the quote that the trade executed against. Trades executed by an
+///exchange hold the id of the order, trades made by the broker outside of the exchange have no id.
+///
+///Costs are the [BrokerCost]s charged on the trade, in the same currency as the value. Value does
+///not include costs. Costs are set by the broker when the trade is settled.
+///
 ///Equality checked against ticker, date, and quantity. Ordering against date only.
 ///
 ///let t = Trade::new(
@@ -198,6 +656,9 @@ pub struct Trade {
     pub quantity: PortfolioQty,
     pub date: DateTime,
     pub typ: TradeType,
+    pub currency: Currency,
+    pub order_id: Option<OrderId>,
+    pub costs: CashValue,
 }
 
 impl Trade {
@@ -207,6 +668,17 @@ impl Trade {
         quantity: impl Into<PortfolioQty>,
         date: impl Into<DateTime>,
         typ: TradeType,
+    ) -> Self {
+        Self::new_with_currency(symbol, value, quantity, date, typ, Currency::default())
+    }
+
+    pub fn new_with_currency(
+        symbol: impl Into<String>,
+        value: impl Into<CashValue>,
+        quantity: impl Into<PortfolioQty>,
+        date: impl Into<DateTime>,
+        typ: TradeType,
+        currency: impl Into<Currency>,
     ) -> Self {
         Self {
             symbol: symbol.into(),
@@ -214,6 +686,9 @@ impl Trade {
             quantity: quantity.into(),
             date: date.into(),
             typ,
+            currency: currency.into(),
+            order_id: None,
+            costs: CashValue::from(0.0),
         }
     }
 }
@@ -238,17 +713,29 @@ impl PartialEq for Trade {
     }
 }
 
+///Identifies an order for the lifetime of a simulation. Ids are assigned by the exchange when the
+///order is accepted and are never reused.
+pub type OrderId = u32;
+
 ///Events generated by broker in the course of executing transactions.
 ///
 ///Brokers have two sources of state: holdings of stock and cash. Events represent modifications of
 ///that state over time. The vast majority, but not all, of these events could be returned to client
 ///applications.
+///
+///Orders accepted by the exchange are returned with their id, which clients can use to cancel or
+///modify the order, or to query its status.
 #[derive(Clone, Debug)]
 pub enum BrokerEvent {
-    OrderSentToExchange(Order),
+    OrderSentToExchange(Order, OrderId),
     OrderInvalid(Order),
     OrderCreated(Order),
     OrderFailure(Order),
+    OrderCancelled(OrderId),
+    OrderModified(Order, OrderId),
+    //Order is no longer on the exchange, either because it was executed, cancelled, or the id is
+    //unknown
+    OrderNotFound(OrderId),
 }
 
 #[derive(Clone, Debug)]
@@ -258,6 +745,11 @@ pub enum BrokerCashEvent {
     WithdrawSuccess(CashValue),
     WithdrawFailure(CashValue),
     DepositSuccess(CashValue),
+    DepositFailure(CashValue),
+    //Success holds the value received in the target currency, failure holds the value that was
+    //to be converted
+    ConversionSuccess(CashValue),
+    ConversionFailure(CashValue),
 }
 
 ///Events generated by broker in the course of executing internal transactions.
@@ -268,6 +760,34 @@ pub enum BrokerCashEvent {
 pub enum BrokerRecordedEvent {
     TradeCompleted(Trade),
     DividendPaid(DividendPayment),
+    MarginInterestCharged(InterestPayment),
+    MarginCall(MarginCall),
+    ForcedLiquidation(ForcedLiquidation),
+    OrderExpired(OrderExpiry),
+    CorporateAction(CorporateAction),
+    //Trade that reinvested a dividend, recorded in place of TradeCompleted
+    DividendReinvested(Trade),
+    InterestPaid(InterestPayment),
+    FeeCharged(FeeCharge),
+    LotsSelected(LotSelection),
+}
+
+impl From<CorporateAction> for BrokerRecordedEvent {
+    fn from(action: CorporateAction) -> Self {
+        BrokerRecordedEvent::CorporateAction(action)
+    }
+}
+
+impl From<LotSelection> for BrokerRecordedEvent {
+    fn from(selection: LotSelection) -> Self {
+        BrokerRecordedEvent::LotsSelected(selection)
+    }
+}
+
+impl From<FeeCharge> for BrokerRecordedEvent {
+    fn from(charge: FeeCharge) -> Self {
+        BrokerRecordedEvent::FeeCharged(charge)
+    }
 }
 
 impl From<Trade> for BrokerRecordedEvent {
@@ -282,6 +802,328 @@ impl From<DividendPayment> for BrokerRecordedEvent {
     }
 }
 
+impl From<MarginCall> for BrokerRecordedEvent {
+    fn from(margin_call: MarginCall) -> Self {
+        BrokerRecordedEvent::MarginCall(margin_call)
+    }
+}
+
+impl From<ForcedLiquidation> for BrokerRecordedEvent {
+    fn from(liquidation: ForcedLiquidation) -> Self {
+        BrokerRecordedEvent::ForcedLiquidation(liquidation)
+    }
+}
+
+///Represents a single payment of interest in cash terms, either charged on a loan or paid on cash.
+///
+///let i = InterestPayment::new(
+///  10.0,
+///  100,
+///);
+#[derive(Clone, Debug)]
+pub struct InterestPayment {
+    pub value: CashValue,
+    pub date: DateTime,
+    pub currency: Currency,
+}
+
+impl InterestPayment {
+    pub fn new(value: impl Into<CashValue>, date: impl Into<DateTime>) -> Self {
+        Self::new_with_currency(value, date, Currency::default())
+    }
+
+    pub fn new_with_currency(
+        value: impl Into<CashValue>,
+        date: impl Into<DateTime>,
+        currency: impl Into<Currency>,
+    ) -> Self {
+        Self {
+            value: value.into(),
+            date: date.into(),
+            currency: currency.into(),
+        }
+    }
+}
+
+///Represents a single charge of an [AccountFee] in cash terms, in the base currency.
+#[derive(Clone, Debug)]
+pub struct FeeCharge {
+    pub value: CashValue,
+    pub date: DateTime,
+    pub fee: AccountFee,
+}
+
+impl FeeCharge {
+    pub fn new(value: impl Into<CashValue>, date: impl Into<DateTime>, fee: AccountFee) -> Self {
+        Self {
+            value: value.into(),
+            date: date.into(),
+            fee,
+        }
+    }
+}
+
+///Represents the state of the account when equity fell below the maintenance margin.
+#[derive(Clone, Debug)]
+pub struct MarginCall {
+    pub equity: CashValue,
+    pub gross_exposure: CashValue,
+    pub date: DateTime,
+}
+
+impl MarginCall {
+    pub fn new(
+        equity: impl Into<CashValue>,
+        gross_exposure: impl Into<CashValue>,
+        date: impl Into<DateTime>,
+    ) -> Self {
+        Self {
+            equity: equity.into(),
+            gross_exposure: gross_exposure.into(),
+            date: date.into(),
+        }
+    }
+}
+
+///Represents an order sent to the exchange by the broker, without instruction from the client,
+///to reduce the size of the portfolio after a [MarginCall].
+#[derive(Clone, Debug)]
+pub struct ForcedLiquidation {
+    pub order: Order,
+    pub date: DateTime,
+}
+
+impl ForcedLiquidation {
+    pub fn new(order: Order, date: impl Into<DateTime>) -> Self {
+        Self {
+            order,
+            date: date.into(),
+        }
+    }
+}
+
+///Represents an order that was removed from the exchange without being executed, or with only part
+///of the order executed, because of the [TimeInForce] on the order. Order holds the quantity that
+///was unfilled at expiry.
+#[derive(Clone, Debug)]
+pub struct OrderExpiry {
+    pub order_id: OrderId,
+    pub order: Order,
+    pub date: DateTime,
+}
+
+impl OrderExpiry {
+    pub fn new(order_id: OrderId, order: Order, date: impl Into<DateTime>) -> Self {
+        Self {
+            order_id,
+            order,
+            date: date.into(),
+        }
+    }
+}
+
+impl From<OrderExpiry> for BrokerRecordedEvent {
+    fn from(expiry: OrderExpiry) -> Self {
+        BrokerRecordedEvent::OrderExpired(expiry)
+    }
+}
+
+///Classification of an [Instrument], this is reference data and doesn't change how the instrument
+///is traded.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AssetClass {
+    #[default]
+    Equity,
+    Fund,
+    Bond,
+    Future,
+    Option,
+    Crypto,
+    Other,
+}
+
+///Reference data and trading rules for a security. Brokers use these rules to size orders and to
+///reject orders that could not be executed.
+///
+///Quantities must be a multiple of the lot size and no smaller than the minimum order size. Prices
+///of orders that are executed at a set price must be a multiple of the tick size, if one is set.
+///Securities that do not allow fractional shares can only trade whole shares.
+///
+///Brokers without an instrument for a symbol trade whole shares, with no other restrictions.
+///
+///The multiplier is the number of units of the underlying represented by one share or contract,
+///the value of a position is the price multiplied by the quantity and the multiplier.
+///
+///Instruments can only be traded from the listing date, if set, and until the delisting date, if
+///set. The delisting date is the first date that the instrument cannot be traded.
+///
+///Country is where the issuer is domiciled, and is used to find the rate of [WithholdingTax] on
+///dividends.
+///
+///let equity = Instrument::new("ABC");
+///let mut crypto = Instrument::new_fractional("BTC", 0.00001);
+///crypto.min_order_size = 0.0001;
+///crypto.tick_size = Some(0.01);
+///crypto.asset_class = AssetClass::Crypto;
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instrument {
+    pub symbol: String,
+    pub asset_class: AssetClass,
+    pub currency: Currency,
+    pub exchange: Option<String>,
+    pub country: Option<String>,
+    pub lot_size: f64,
+    pub min_order_size: f64,
+    pub tick_size: Option<f64>,
+    pub fractional: bool,
+    pub multiplier: f64,
+    pub listed: Option<DateTime>,
+    pub delisted: Option<DateTime>,
+}
+
+impl Instrument {
+    //Tolerance for checking that a quantity or price is a multiple of the lot or tick size,
+    //without this sizes such as 0.1 fail the check due to floating point error
+    const TOLERANCE: f64 = 1e-9;
+
+    fn is_multiple(value: f64, size: f64) -> bool {
+        let units = value / size;
+        (units - units.round()).abs() < Self::TOLERANCE
+    }
+
+    //Whole shares, with a lot size of one
+    pub fn new(symbol: impl Into<String>) -> Self {
+        Self {
+            symbol: symbol.into(),
+            asset_class: AssetClass::default(),
+            currency: Currency::default(),
+            exchange: None,
+            country: None,
+            lot_size: 1.0,
+            min_order_size: 0.0,
+            tick_size: None,
+            fractional: false,
+            multiplier: 1.0,
+            listed: None,
+            delisted: None,
+        }
+    }
+
+    pub fn new_fractional(symbol: impl Into<String>, lot_size: f64) -> Self {
+        if lot_size <= 0.0 {
+            panic!("Lot size must be greater than zero");
+        }
+        Self {
+            fractional: true,
+            lot_size,
+            ..Self::new(symbol)
+        }
+    }
+
+    pub fn is_valid_quantity(&self, shares: &PortfolioQty) -> bool {
+        let shares = shares.abs();
+        if !self.fractional && !Self::is_multiple(shares, 1.0) {
+            return false;
+        }
+        Self::is_multiple(shares, self.lot_size) && shares >= self.min_order_size
+    }
+
+    pub fn is_tradable(&self, date: &DateTime) -> bool {
+        let is_listed = match &self.listed {
+            Some(listed) => date >= listed,
+            None => true,
+        };
+        is_listed && !self.is_delisted(date)
+    }
+
+    pub fn is_delisted(&self, date: &DateTime) -> bool {
+        match &self.delisted {
+            Some(delisted) => date >= delisted,
+            None => false,
+        }
+    }
+
+    pub fn is_valid_price(&self, price: &Price) -> bool {
+        match self.tick_size {
+            Some(tick_size) => Self::is_multiple(**price, tick_size),
+            None => true,
+        }
+    }
+
+    //Rounds down to a quantity that can be traded, returns zero if the quantity is below the
+    //minimum order size. Sign is preserved.
+    pub fn round_quantity(&self, shares: f64) -> f64 {
+        let lots = (shares.abs() / self.lot_size + Self::TOLERANCE).floor();
+        let mut rounded = lots * self.lot_size;
+        if !self.fractional {
+            rounded = (rounded + Self::TOLERANCE).floor();
+        }
+        if rounded < self.min_order_size {
+            return 0.0;
+        }
+        rounded.copysign(shares)
+    }
+
+    //Rounds up to a quantity that can be traded, quantities below the minimum order size are
+    //rounded up to the minimum
+    pub fn round_quantity_up(&self, shares: f64) -> f64 {
+        let lots = (shares.abs() / self.lot_size - Self::TOLERANCE).ceil();
+        let min_lots = (self.min_order_size / self.lot_size - Self::TOLERANCE).ceil();
+        let mut rounded = lots.max(min_lots) * self.lot_size;
+        if !self.fractional {
+            rounded = (rounded - Self::TOLERANCE).ceil();
+        }
+        rounded.copysign(shares)
+    }
+}
+
+///Holds the [Instrument] for every symbol that can be traded. An empty registry places no
+///restrictions on trading, once an instrument has been added symbols that are not in the registry
+///cannot be traded.
+///
+///let mut registry = InstrumentRegistry::new();
+///registry.insert(Instrument::new("ABC"));
+#[derive(Clone, Debug, Default)]
+pub struct InstrumentRegistry(HashMap<String, Instrument>);
+
+impl InstrumentRegistry {
+    pub fn get(&self, symbol: &str) -> Option<&Instrument> {
+        self.0.get(symbol)
+    }
+
+    pub fn insert(&mut self, instrument: Instrument) {
+        self.0.insert(instrument.symbol.clone(), instrument);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn is_tradable(&self, symbol: &str, date: &DateTime) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        match self.get(symbol) {
+            Some(instrument) => instrument.is_tradable(date),
+            None => false,
+        }
+    }
+
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl From<Vec<Instrument>> for InstrumentRegistry {
+    fn from(instruments: Vec<Instrument>) -> Self {
+        let mut registry = Self::new();
+        for instrument in instruments {
+            registry.insert(instrument);
+        }
+        registry
+    }
+}
+
 ///Represents the order types that a broker implementation should support.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum OrderType {
@@ -291,6 +1133,66 @@ pub enum OrderType {
     LimitBuy,
     StopSell,
     StopBuy,
+    TrailingStopSell,
+    TrailingStopBuy,
+    MarketOnOpenSell,
+    MarketOnOpenBuy,
+    MarketOnCloseSell,
+    MarketOnCloseBuy,
+}
+
+impl OrderType {
+    pub fn is_buy(&self) -> bool {
+        matches!(
+            self,
+            OrderType::MarketBuy
+                | OrderType::LimitBuy
+                | OrderType::StopBuy
+                | OrderType::TrailingStopBuy
+                | OrderType::MarketOnOpenBuy
+                | OrderType::MarketOnCloseBuy
+        )
+    }
+}
+
+///Distance between the best price seen since a trailing stop was placed and the stop price. A
+///percentage trail is expressed as a fraction, 0.05 is a trail of 5%.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trail {
+    Absolute(Price),
+    Percentage(f64),
+}
+
+impl Trail {
+    //Stop price for a sell below the given price, or for a buy above it
+    pub fn stop_price(&self, price: &Price, is_buy: bool) -> Price {
+        let distance = match self {
+            Trail::Absolute(distance) => **distance,
+            Trail::Percentage(pct) => **price * pct,
+        };
+        if is_buy {
+            Price::from(**price + distance)
+        } else {
+            Price::from(**price - distance)
+        }
+    }
+}
+
+///Represents how long an order stays on the exchange before it expires. Orders are
+///[TimeInForce::GoodTillCancelled] unless set otherwise.
+///
+///Day orders expire when the exchange moves onto a date after the date that the order was first
+///checked. Immediate orders, [TimeInForce::ImmediateOrCancel] and [TimeInForce::FillOrKill], are
+///checked once and expire if they can't be executed against that quote; [TimeInForce::FillOrKill]
+///orders must be executed in full or not at all.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancelled,
+    Day,
+    ImmediateOrCancel,
+    FillOrKill,
+    GoodTillDate(DateTime),
 }
 
 ///Represents an order that is sent to a broker to execute. Trading strategies can send orders to
@@ -310,18 +1212,30 @@ pub enum OrderType {
 ///  100.0,
 ///);
 ///
-///let o1 = Order::delayed(
+///let mut o1 = Order::delayed(
 ///  OrderType::StopSell,
 ///  "ABC",
 ///  100.0,
 ///  10.0,
 ///);
+///o1.set_time_in_force(TimeInForce::Day);
+///
+///let o2 = Order::trailing(
+///  OrderType::TrailingStopSell,
+///  "ABC",
+///  100.0,
+///  Trail::Percentage(0.05),
+///);
 #[derive(Clone, Debug)]
 pub struct Order {
     order_type: OrderType,
     symbol: String,
     shares: PortfolioQty,
     price: Option<Price>,
+    time_in_force: TimeInForce,
+    //Only set for trailing stops, the stop price is held in price once the exchange has seen a
+    //quote
+    trail: Option<Trail>,
 }
 
 impl Order {
@@ -342,6 +1256,39 @@ impl Order {
         &self.order_type
     }
 
+    pub fn get_trail(&self) -> &Option<Trail> {
+        &self.trail
+    }
+
+    //Used by exchanges to move the stop price of trailing stops
+    pub fn set_price(&mut self, price: impl Into<Price>) {
+        self.price = Some(price.into());
+    }
+
+    pub fn get_time_in_force(&self) -> &TimeInForce {
+        &self.time_in_force
+    }
+
+    pub fn set_time_in_force(&mut self, time_in_force: TimeInForce) {
+        self.time_in_force = time_in_force;
+    }
+
+    //Used by exchanges to update the quantity left to trade after a partial fill
+    pub fn set_shares(&mut self, shares: impl Into<PortfolioQty>) {
+        self.shares = shares.into();
+    }
+
+    //Used by exchanges to restate orders in post-split shares, the quantity is not rounded
+    pub fn apply_split(&mut self, ratio: f64) {
+        self.shares = PortfolioQty::from(*self.shares * ratio);
+        if let Some(price) = &self.price {
+            self.price = Some(Price::from(**price / ratio));
+        }
+        if let Some(Trail::Absolute(distance)) = &self.trail {
+            self.trail = Some(Trail::Absolute(Price::from(**distance / ratio)));
+        }
+    }
+
     pub fn market(
         order_type: OrderType,
         symbol: impl Into<String>,
@@ -352,6 +1299,8 @@ impl Order {
             symbol: symbol.into(),
             shares: shares.into(),
             price: None,
+            time_in_force: TimeInForce::default(),
+            trail: None,
         }
     }
 
@@ -366,6 +1315,24 @@ impl Order {
             symbol: symbol.into(),
             shares: shares.into(),
             price: Some(price.into()),
+            time_in_force: TimeInForce::default(),
+            trail: None,
+        }
+    }
+
+    pub fn trailing(
+        order_type: OrderType,
+        symbol: impl Into<String>,
+        shares: impl Into<PortfolioQty>,
+        trail: Trail,
+    ) -> Self {
+        Self {
+            order_type,
+            symbol: symbol.into(),
+            shares: shares.into(),
+            price: None,
+            time_in_force: TimeInForce::default(),
+            trail: Some(trail),
         }
     }
 }
@@ -380,14 +1347,88 @@ impl PartialEq for Order {
     }
 }
 
+///Entry order with a take-profit and a stop-loss attached. The exits are sent to the exchange as a
+///one-cancels-other group once the entry has executed, so the position is closed by whichever
+///exit triggers first.
+///
+///Exits are for the same quantity as the entry and on the opposite side: a buy entry creates a
+///[OrderType::LimitSell] take-profit and a [OrderType::StopSell] stop-loss.
+///
+///let bracket = BracketOrder::new(
+///  Order::market(OrderType::MarketBuy, "ABC", 100.0),
+///  110.0,
+///  95.0,
+///);
+#[derive(Clone, Debug)]
+pub struct BracketOrder {
+    pub entry: Order,
+    pub take_profit: Order,
+    pub stop_loss: Order,
+}
+
+impl BracketOrder {
+    pub fn new(entry: Order, take_profit: impl Into<Price>, stop_loss: impl Into<Price>) -> Self {
+        let (take_profit_type, stop_loss_type) = if entry.get_order_type().is_buy() {
+            (OrderType::LimitSell, OrderType::StopSell)
+        } else {
+            (OrderType::LimitBuy, OrderType::StopBuy)
+        };
+        let symbol = entry.get_symbol().clone();
+        let shares = entry.get_shares().clone();
+        Self {
+            take_profit: Order::delayed(take_profit_type, &symbol, shares.clone(), take_profit),
+            stop_loss: Order::delayed(stop_loss_type, &symbol, shares, stop_loss),
+            entry,
+        }
+    }
+}
+
+///Represents the state of an order on the exchange. Partially filled orders hold the quantity
+///filled so far, the remaining quantity stays in the book.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderStatus {
+    Pending,
+    PartiallyFilled(PortfolioQty),
+    Filled,
+    Cancelled,
+    Expired,
+}
+
 ///Implementation of various cost models for brokers. Broker implementations would either define or
 ///cost model or would provide the user the option of intializing one; the broker impl would then
 ///call the variant's calculation methods as trades are executed.
+///
+///Tiered costs charge a percentage of the trade value that depends on the size of the trade: each
+///tier is the minimum trade value and the rate charged on the whole trade at or above that value.
+///Buy taxes, such as UK stamp duty, are a percentage of value charged on buys only.
+///
+///Costs can be wrapped to apply a minimum and/or maximum charge to each trade, or to apply only to
+///trades in securities listed on a given exchange, as set on the [Instrument].
+///
+///Values and bounds are in the base currency of the broker. Trades in other currencies are valued
+///at the latest exchange rate to calculate the cost, which is then charged in the currency of the
+///trade.
+///
+///let commission = BrokerCost::bounded(BrokerCost::pct_of_value(0.001), Some(5.0), Some(50.0));
+///let stamp_duty = BrokerCost::buy_tax(0.005);
+///let tiered = BrokerCost::tiered(vec![(0.0, 0.002), (10_000.0, 0.001)]);
+///let levy = BrokerCost::exchange_fee("LSE", BrokerCost::flat(1.0));
 #[derive(Clone, Debug)]
 pub enum BrokerCost {
     PerShare(Price),
     PctOfValue(f64),
     Flat(CashValue),
+    Tiered(Vec<(CashValue, f64)>),
+    BuyTax(f64),
+    Bounded {
+        cost: Box<BrokerCost>,
+        min: Option<CashValue>,
+        max: Option<CashValue>,
+    },
+    ExchangeFee {
+        exchange: String,
+        cost: Box<BrokerCost>,
+    },
 }
 
 impl BrokerCost {
@@ -403,11 +1444,90 @@ impl BrokerCost {
         BrokerCost::Flat(CashValue::from(val))
     }
 
-    pub fn calc(&self, trade: &Trade) -> CashValue {
+    pub fn tiered(tiers: Vec<(f64, f64)>) -> Self {
+        if tiers.is_empty() {
+            panic!("Tiered cost must have at least one tier");
+        }
+        let mut tiers = tiers
+            .into_iter()
+            .map(|(threshold, rate)| (CashValue::from(threshold), rate))
+            .collect::<Vec<(CashValue, f64)>>();
+        tiers.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        BrokerCost::Tiered(tiers)
+    }
+
+    pub fn buy_tax(val: f64) -> Self {
+        BrokerCost::BuyTax(val)
+    }
+
+    pub fn bounded(cost: BrokerCost, min: Option<f64>, max: Option<f64>) -> Self {
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                panic!("Minimum cost cannot be greater than maximum cost");
+            }
+        }
+        BrokerCost::Bounded {
+            cost: Box::new(cost),
+            min: min.map(CashValue::from),
+            max: max.map(CashValue::from),
+        }
+    }
+
+    pub fn exchange_fee(exchange: impl Into<String>, cost: BrokerCost) -> Self {
+        BrokerCost::ExchangeFee {
+            exchange: exchange.into(),
+            cost: Box::new(cost),
+        }
+    }
+
+    //Rate of the highest tier that the value reaches, values below every tier are charged at the
+    //rate of the lowest tier
+    fn tier_rate(tiers: &[(CashValue, f64)], value: &f64) -> f64 {
+        tiers
+            .iter()
+            .rev()
+            .find(|(threshold, _rate)| value >= threshold)
+            .or(tiers.first())
+            .map_or(0.0, |(_threshold, rate)| *rate)
+    }
+
+    fn bound(cost: f64, min: &Option<CashValue>, max: &Option<CashValue>) -> f64 {
+        let mut bounded = cost;
+        if let Some(min) = min {
+            bounded = bounded.max(**min);
+        }
+        if let Some(max) = max {
+            bounded = bounded.min(**max);
+        }
+        bounded
+    }
+
+    //Exchange is the exchange that the traded security is listed on, if known
+    pub fn calc(&self, trade: &Trade, exchange: Option<&String>) -> CashValue {
         match self {
             BrokerCost::PerShare(cost) => CashValue::from(*cost.clone() * *trade.quantity.clone()),
             BrokerCost::PctOfValue(pct) => CashValue::from(*trade.value * *pct),
             BrokerCost::Flat(val) => val.clone(),
+            BrokerCost::Tiered(tiers) => {
+                CashValue::from(*trade.value * Self::tier_rate(tiers, &trade.value))
+            }
+            BrokerCost::BuyTax(pct) => match trade.typ {
+                TradeType::Buy => CashValue::from(*trade.value * *pct),
+                TradeType::Sell => CashValue::from(0.0),
+            },
+            BrokerCost::Bounded { cost, min, max } => {
+                CashValue::from(Self::bound(*cost.calc(trade, exchange), min, max))
+            }
+            BrokerCost::ExchangeFee {
+                exchange: fee_exchange,
+                cost,
+            } => {
+                if exchange == Some(fee_exchange) {
+                    cost.calc(trade, exchange)
+                } else {
+                    CashValue::from(0.0)
+                }
+            }
         }
     }
 
@@ -418,6 +1538,7 @@ impl BrokerCost {
         gross_budget: &f64,
         gross_price: &f64,
         is_buy: bool,
+        exchange: Option<&String>,
     ) -> (CashValue, Price) {
         let mut net_budget = *gross_budget;
         let mut net_price = *gross_price;
@@ -433,6 +1554,31 @@ impl BrokerCost {
                 net_budget *= 1.0 - pct;
             }
             BrokerCost::Flat(val) => net_budget -= *val.clone(),
+            BrokerCost::Tiered(tiers) => {
+                net_budget *= 1.0 - Self::tier_rate(tiers, gross_budget);
+            }
+            BrokerCost::BuyTax(pct) => {
+                if is_buy {
+                    net_budget *= 1.0 - pct;
+                }
+            }
+            BrokerCost::Bounded { cost, min, max } => {
+                //The unbounded cost is estimated for the shares that the budget can trade, and
+                //the bounded cost is then taken from the budget
+                let (budget, price) =
+                    cost.trade_impact(gross_budget, gross_price, is_buy, exchange);
+                let shares = if *price != 0.0 { *budget / *price } else { 0.0 };
+                let estimated = (*gross_budget - *budget) + (*price - *gross_price).abs() * shares;
+                net_budget -= Self::bound(estimated, min, max);
+            }
+            BrokerCost::ExchangeFee {
+                exchange: fee_exchange,
+                cost,
+            } => {
+                if exchange == Some(fee_exchange) {
+                    return cost.trade_impact(gross_budget, gross_price, is_buy, exchange);
+                }
+            }
         }
         (CashValue::from(net_budget), Price::from(net_price))
     }
@@ -442,15 +1588,250 @@ impl BrokerCost {
         gross_budget: &f64,
         gross_price: &f64,
         is_buy: bool,
+        exchange: Option<&String>,
     ) -> (CashValue, Price) {
         let mut res = (CashValue::from(*gross_budget), Price::from(*gross_price));
         for cost in trade_costs {
-            res = cost.trade_impact(&res.0, &res.1, is_buy);
+            res = cost.trade_impact(&res.0, &res.1, is_buy, exchange);
         }
         res
     }
 }
 
+///Ongoing fees charged on the account, rather than on trades. Fees are charged in the base currency
+///at the end of every `Frequency` period: with a monthly frequency, the fee for a month is charged
+///on the first check of the next month.
+///
+///Percentage fees are an annual rate of the total value of the account, accrued every time the
+///broker is checked on the value at that check, and can be capped at a maximum charge for each
+///period. Flat fees are charged once every period.
+///
+///let management = AccountFee::pct_of_value(0.0025, Frequency::Monthly);
+///let platform = AccountFee::flat(5.0, Frequency::Monthly);
+///let custody = AccountFee::capped_pct_of_value(0.001, 20.0, Frequency::Yearly);
+#[derive(Clone, Debug)]
+pub enum AccountFee {
+    PctOfValue {
+        rate: f64,
+        cap: Option<CashValue>,
+        frequency: Frequency,
+    },
+    Flat {
+        value: CashValue,
+        frequency: Frequency,
+    },
+}
+
+impl AccountFee {
+    const SECS_IN_YEAR: f64 = 31_536_000.0;
+
+    pub fn pct_of_value(rate: f64, frequency: Frequency) -> Self {
+        AccountFee::PctOfValue {
+            rate,
+            cap: None,
+            frequency,
+        }
+    }
+
+    pub fn capped_pct_of_value(rate: f64, cap: f64, frequency: Frequency) -> Self {
+        AccountFee::PctOfValue {
+            rate,
+            cap: Some(CashValue::from(cap)),
+            frequency,
+        }
+    }
+
+    pub fn flat(value: f64, frequency: Frequency) -> Self {
+        AccountFee::Flat {
+            value: CashValue::from(value),
+            frequency,
+        }
+    }
+
+    pub fn frequency(&self) -> &Frequency {
+        match self {
+            AccountFee::PctOfValue { frequency, .. } => frequency,
+            AccountFee::Flat { frequency, .. } => frequency,
+        }
+    }
+
+    //Fee accrued on the value of the account held for the given number of seconds, flat fees are
+    //not accrued
+    pub fn accrue(&self, value: &f64, seconds: i64) -> f64 {
+        match self {
+            AccountFee::PctOfValue { rate, .. } => {
+                value.max(0.0) * rate * (seconds as f64 / Self::SECS_IN_YEAR)
+            }
+            AccountFee::Flat { .. } => 0.0,
+        }
+    }
+
+    //Fee charged at the end of a period, given the fee accrued over that period
+    pub fn charge(&self, accrued: f64) -> f64 {
+        match self {
+            AccountFee::PctOfValue { cap, .. } => match cap {
+                Some(cap) => accrued.min(**cap),
+                None => accrued,
+            },
+            AccountFee::Flat { value, .. } => **value,
+        }
+    }
+}
+
+///Configuration of a margin account. Margin ratios are the fraction of the gross value of
+///positions that has to be held as equity: an initial margin of 0.5 allows a portfolio worth
+///twice the equity in the account to be opened. If equity falls below the maintenance margin then
+///the broker will liquidate positions until the initial margin is restored.
+///
+///Negative cash balances are a loan from the broker, interest is charged on that loan at an
+///annual rate.
+///
+///let m = Margin::new(
+///  0.5,
+///  0.25,
+///  0.05,
+///);
+#[derive(Clone, Debug)]
+pub struct Margin {
+    pub initial: f64,
+    pub maintenance: f64,
+    pub interest_rate: f64,
+}
+
+impl Margin {
+    const SECS_IN_YEAR: f64 = 31_536_000.0;
+
+    pub fn new(initial: f64, maintenance: f64, interest_rate: f64) -> Self {
+        if initial <= 0.0 || initial > 1.0 {
+            panic!("Initial margin must be greater than zero and no greater than one");
+        }
+        if maintenance <= 0.0 || maintenance > initial {
+            panic!("Maintenance margin must be greater than zero and no greater than initial");
+        }
+        Self {
+            initial,
+            maintenance,
+            interest_rate,
+        }
+    }
+
+    //Interest owed on a loan of the given size held for the given number of seconds
+    pub fn interest(&self, loan: &f64, seconds: i64) -> CashValue {
+        CashValue::from(loan * self.interest_rate * (seconds as f64 / Margin::SECS_IN_YEAR))
+    }
+}
+
+///Source of the annual rate of interest paid on cash. A fixed rate is paid on cash in every
+///currency, rates from the `DataSource` are set for each currency and cash in currencies without a
+///rate earns nothing.
+#[derive(Clone, Debug)]
+pub enum CashInterestRate {
+    Fixed(f64),
+    DataSource,
+}
+
+///Configuration of the interest paid on positive cash balances. Interest is accrued every time the
+///broker is checked, on the balance held since the last check, and is credited once the
+///`Frequency` period ends: with a monthly frequency, interest accrued during a month is paid on the
+///first check of the next month.
+///
+///Interest is not compounded until it is credited.
+///
+///let i = CashInterest::fixed(0.05, Frequency::Monthly);
+#[derive(Clone, Debug)]
+pub struct CashInterest {
+    pub rate: CashInterestRate,
+    pub frequency: Frequency,
+}
+
+impl CashInterest {
+    const SECS_IN_YEAR: f64 = 31_536_000.0;
+
+    pub fn fixed(rate: f64, frequency: Frequency) -> Self {
+        Self {
+            rate: CashInterestRate::Fixed(rate),
+            frequency,
+        }
+    }
+
+    pub fn from_data_source(frequency: Frequency) -> Self {
+        Self {
+            rate: CashInterestRate::DataSource,
+            frequency,
+        }
+    }
+
+    //Interest earned on the balance at an annual rate for the given number of seconds
+    pub fn interest(balance: &f64, rate: f64, seconds: i64) -> f64 {
+        balance * rate * (seconds as f64 / Self::SECS_IN_YEAR)
+    }
+}
+
+///Rates of tax withheld from dividends at source. The rate for a symbol is used if one is set,
+///then the rate for the country of the [Instrument], and then the default rate.
+///
+///Tax is only withheld from dividends received on long positions, dividends paid on short
+///positions are paid in full.
+///
+///let mut tax = WithholdingTax::new(0.15);
+///tax.countries.insert("GB".to_string(), 0.0);
+///tax.symbols.insert("ABC".to_string(), 0.3);
+#[derive(Clone, Debug, Default)]
+pub struct WithholdingTax {
+    pub default_rate: f64,
+    pub symbols: HashMap<String, f64>,
+    pub countries: HashMap<String, f64>,
+}
+
+impl WithholdingTax {
+    pub fn new(default_rate: f64) -> Self {
+        if !(0.0..=1.0).contains(&default_rate) {
+            panic!("Withholding tax rate must be between zero and one");
+        }
+        Self {
+            default_rate,
+            symbols: HashMap::new(),
+            countries: HashMap::new(),
+        }
+    }
+
+    pub fn rate(&self, symbol: &str, country: Option<&String>) -> f64 {
+        if let Some(rate) = self.symbols.get(symbol) {
+            return *rate;
+        }
+        if let Some(rate) = country.and_then(|country| self.countries.get(country)) {
+            return *rate;
+        }
+        self.default_rate
+    }
+}
+
+///Sets which dividends are used to buy more shares of the security that paid them. Dividends are
+///paid in cash and then reinvested with a market order on the next tick, so reinvestment is subject
+///to the same price movements and execution rules as any other order.
+///
+///Reinvestment is rounded down to a quantity that can be traded, any remaining cash is held.
+///Dividends on short positions are never reinvested.
+///
+///let drip = DividendReinvestment::Symbols(vec!["ABC".to_string()]);
+#[derive(Clone, Debug, Default)]
+pub enum DividendReinvestment {
+    #[default]
+    Off,
+    All,
+    Symbols(Vec<String>),
+}
+
+impl DividendReinvestment {
+    pub fn reinvests(&self, symbol: &str) -> bool {
+        match self {
+            DividendReinvestment::Off => false,
+            DividendReinvestment::All => true,
+            DividendReinvestment::Symbols(symbols) => symbols.iter().any(|s| s == symbol),
+        }
+    }
+}
+
 //Key traits for broker implementations.
 //
 //Whilst broker is implemented within this package as a singular broker, the intention of these
@@ -488,13 +1869,20 @@ pub trait BacktestBroker {
     }
 
     fn get_position_liquidation_value(&self, symbol: &str) -> Option<CashValue> {
-        //TODO: we need to introduce some kind of distinction between short and long
-        //      positions.
         if let Some(position_value) = self.get_position_value(symbol) {
             if let Some(qty) = self.get_position_qty(symbol) {
                 let price = Price::from(*position_value / **qty);
+                if **qty < 0.0 {
+                    //Short positions are liquidated by buying back the shares so the costs of the
+                    //trade add to the liability rather than reducing the proceeds
+                    let gross_value = position_value.abs();
+                    let (value_after_costs, _price_after_costs) =
+                        self.calc_trade_impact(symbol, &gross_value, &price, true);
+                    let costs = gross_value - *value_after_costs;
+                    return Some(CashValue::from(-(gross_value + costs)));
+                }
                 let (value_after_costs, _price_after_costs) =
-                    self.calc_trade_impact(&position_value, &price, false);
+                    self.calc_trade_impact(symbol, &position_value, &price, false);
                 return Some(value_after_costs);
             }
         }
@@ -521,6 +1909,37 @@ pub trait BacktestBroker {
         value
     }
 
+    //Sum of the absolute value of all positions, long and short
+    fn get_gross_exposure(&self) -> CashValue {
+        let mut value = 0.0;
+        for asset in self.get_positions() {
+            if let Some(position_value) = self.get_position_value(&asset) {
+                value += position_value.abs();
+            }
+        }
+        CashValue::from(value)
+    }
+
+    //Fraction of the gross exposure that must be held as equity. Brokers without leverage hold
+    //the full value of positions.
+    fn get_initial_margin(&self) -> f64 {
+        1.0
+    }
+
+    //Equity that isn't required to support the current positions, this is the amount of cash
+    //that can be withdrawn. Without leverage or short positions, this is equal to the cash
+    //balance.
+    fn get_excess_equity(&self) -> CashValue {
+        let required = self.get_initial_margin() * *self.get_gross_exposure();
+        CashValue::from(*self.get_total_value() - required)
+    }
+
+    //Value of new positions that can be opened. Proceeds from short sales are credited to cash
+    //but are held as collateral so cannot be used to fund new positions.
+    fn get_buying_power(&self) -> CashValue {
+        CashValue::from(*self.get_excess_equity() / self.get_initial_margin())
+    }
+
     fn get_values(&self) -> PortfolioValues {
         let mut holdings = PortfolioValues::new();
         let assets = self.get_positions();
@@ -533,21 +1952,69 @@ pub trait BacktestBroker {
         holdings
     }
 
+    //Currency that all values returned by the broker are reported in
+    fn get_base_currency(&self) -> Currency {
+        Currency::default()
+    }
+
+    //Rate to convert one unit of currency into the base currency, brokers that hold a single
+    //currency cannot convert
+    fn get_fx_rate(&self, currency: &Currency) -> Option<f64> {
+        if *currency == self.get_base_currency() {
+            return Some(1.0);
+        }
+        None
+    }
+
+    //Brokers without instruments trade in whole shares
+    fn get_instrument(&self, _symbol: &str) -> Option<&Instrument> {
+        None
+    }
+
+    //Brokers without instruments can trade any symbol
+    fn is_tradable(&self, _symbol: &str) -> bool {
+        true
+    }
+
+    //Value of a position is the price multiplied by quantity and the multiplier
+    fn get_multiplier(&self, symbol: &str) -> f64 {
+        self.get_instrument(symbol)
+            .map_or(1.0, |instrument| instrument.multiplier)
+    }
+
+    //Total cash held in all currencies, converted into the base currency
     fn get_cash_balance(&self) -> CashValue;
     //TODO: Position qty can always return a value, if we don't have the position then qty is 0
+    //Short positions are represented by negative quantities
     fn get_position_qty(&self, symbol: &str) -> Option<&PortfolioQty>;
     //TODO: Position value can always return a value, if we don't have a position then value is 0
+    //Short positions have negative value, representing the cost of buying back the shares. Value
+    //is in the base currency.
     fn get_position_value(&self, symbol: &str) -> Option<CashValue>;
     fn get_position_cost(&self, symbol: &str) -> Option<Price>;
     fn get_positions(&self) -> Vec<String>;
     fn get_holdings(&self) -> PortfolioHoldings;
     //This should only be called internally
     fn get_trade_costs(&self, trade: &Trade) -> CashValue;
-    fn calc_trade_impact(&self, budget: &f64, price: &f64, is_buy: bool) -> (CashValue, Price);
+    fn calc_trade_impact(
+        &self,
+        symbol: &str,
+        budget: &f64,
+        price: &f64,
+        is_buy: bool,
+    ) -> (CashValue, Price);
     fn update_holdings(&mut self, symbol: &str, change: PortfolioQty);
     fn pay_dividends(&mut self);
     fn send_order(&mut self, order: Order) -> BrokerEvent;
     fn send_orders(&mut self, order: Vec<Order>) -> Vec<BrokerEvent>;
+    //Orders are sent as a group, once any order in the group executes the others are cancelled
+    fn send_oco_orders(&mut self, orders: Vec<Order>) -> Vec<BrokerEvent>;
+    //Returns the event for the entry order, exits receive ids when they are sent to the exchange
+    fn send_bracket_order(&mut self, bracket: BracketOrder) -> BrokerEvent;
+    fn cancel_order(&mut self, order_id: OrderId) -> BrokerEvent;
+    //Replaces an order that hasn't been fully executed, the replacement keeps the same id
+    fn modify_order(&mut self, order_id: OrderId, order: Order) -> BrokerEvent;
+    fn get_order_status(&self, order_id: &OrderId) -> Option<OrderStatus>;
     fn clear_pending_market_orders_by_symbol(&mut self, symbol: &str);
     fn debit(&mut self, value: &f64) -> BrokerCashEvent;
     fn credit(&mut self, value: &f64) -> BrokerCashEvent;
@@ -559,11 +2026,12 @@ pub trait BacktestBroker {
 ///This does not come with base implementation because clients may wish to restrict this behaviour.
 pub trait TransferCash: BacktestBroker {
     fn withdraw_cash(&mut self, cash: &f64) -> BrokerCashEvent {
-        if cash > &self.get_cash_balance() {
+        //Cash held as collateral against positions cannot be withdrawn
+        if cash > &self.get_excess_equity() {
             info!(
                 "BROKER: Attempted cash withdraw of {:?} but only have {:?}",
                 cash,
-                self.get_cash_balance()
+                self.get_excess_equity()
             );
             return BrokerCashEvent::WithdrawFailure(CashValue::from(*cash));
         }
@@ -605,6 +2073,22 @@ pub trait GetsQuote {
 pub trait EventLog {
     fn trades_between(&self, start: &i64, end: &i64) -> Vec<Trade>;
     fn dividends_between(&self, start: &i64, end: &i64) -> Vec<DividendPayment>;
+    //Filtered on the date of disposal. Brokers that don't track tax lots report no gains.
+    //
+    //Gains are before wash sale adjustments, so a loss that is disallowed by a wash sale is still
+    //reported in full here.
+    fn realised_gains_between(&self, _start: &i64, _end: &i64) -> Vec<RealisedGain> {
+        Vec::new()
+    }
+    //Filtered on the date of the sale. Brokers that don't check for wash sales report none.
+    fn wash_sales_between(&self, _start: &i64, _end: &i64) -> Vec<WashSale> {
+        Vec::new()
+    }
+    //Realised gains with disallowed losses removed from the sale and realised on the replacement
+    //lot instead, filtered on the date of disposal
+    fn adjusted_gains_between(&self, _start: &i64, _end: &i64) -> Vec<RealisedGain> {
+        Vec::new()
+    }
 }
 
 ///Implements functionality that is standard to most brokers. These calculations are generic so are
@@ -653,6 +2137,10 @@ impl BrokerCalculations {
             let mut sell_orders: Vec<Order> = Vec::new();
             for ticker in positions {
                 let position_value = brkr.get_position_value(&ticker).unwrap_or_default();
+                //Closing a short position requires cash so cannot be used to raise cash
+                if (*position_value).lt(&0.0) {
+                    continue;
+                }
                 //Position won't generate enough cash to fulfill total order
                 //Create orders for selling 100% of position, continue
                 //to next position to see if we can generate enough cash
@@ -670,8 +2158,15 @@ impl BrokerCalculations {
                     //Create orders to sell 100% of position, don't continue to next stock
                     //
                     //Cannot be called without quote existing so unwrap
-                    let price = &brkr.get_quote(&ticker).unwrap().bid;
-                    let shares_req = PortfolioQty::from((total_sold / **price).ceil());
+                    let quote = brkr.get_quote(&ticker).unwrap();
+                    //Position has a value so there must be a rate to the base currency
+                    let rate = brkr.get_fx_rate(&quote.currency).unwrap();
+                    let price = *quote.bid * rate * brkr.get_multiplier(&ticker);
+                    let shares_req = match brkr.get_instrument(&ticker) {
+                        Some(instrument) => instrument.round_quantity_up(total_sold / price),
+                        None => (total_sold / price).ceil(),
+                    };
+                    let shares_req = PortfolioQty::from(shares_req);
                     let order = Order::market(OrderType::MarketSell, ticker, shares_req);
                     info!("BROKER: Withdrawing {:?} with liquidation, queueing sale of {:?} shares of {:?}", cash, order.get_shares(), order.get_symbol());
                     sell_orders.push(order);
@@ -703,6 +2198,9 @@ impl BrokerCalculations {
     //target_weights passed into the function.
     //Returns orders so calling function has control over when orders are executed
     //Requires mutable reference to brkr because it calls get_position_value
+    //
+    //Negative weights represent short positions, the broker must support short selling for the
+    //resulting sell orders to be accepted.
     pub fn diff_brkr_against_target_weights<T: BacktestBroker + GetsQuote>(
         target_weights: &PortfolioAllocation,
         brkr: &mut T,
@@ -721,25 +2219,37 @@ impl BrokerCalculations {
 
         //This returns a positive number for buy and negative for sell, this is necessary because
         //of calculations made later to find the net position of orders on the exchange.
-        let calc_required_shares_with_costs = |diff_val: &f64, quote: &Quote, brkr: &T| -> f64 {
-            if diff_val.lt(&0.0) {
-                let price = &quote.bid;
-                let costs = brkr.calc_trade_impact(&diff_val.abs(), &price, false);
-                let total = (*costs.0 / *costs.1).floor();
-                -total
-            } else {
-                let price = &quote.ask;
-                let costs = brkr.calc_trade_impact(&diff_val.abs(), &price, true);
-                (*costs.0 / *costs.1).floor()
-            }
-        };
+        //
+        //Values are in the base currency so the price of the quote has to be converted with the
+        //rate passed in.
+        //
+        //Shares are rounded down to a quantity that the instrument can trade, or whole shares if
+        //the broker has no instrument for the symbol.
+        let calc_required_shares_with_costs =
+            |diff_val: &f64, quote: &Quote, rate: &f64, brkr: &T| -> f64 {
+                let round = |shares: f64| match brkr.get_instrument(&quote.symbol) {
+                    Some(instrument) => instrument.round_quantity(shares),
+                    None => shares.floor(),
+                };
+                let multiplier = brkr.get_multiplier(&quote.symbol);
+                if diff_val.lt(&0.0) {
+                    let price = *quote.bid * rate * multiplier;
+                    let costs =
+                        brkr.calc_trade_impact(&quote.symbol, &diff_val.abs(), &price, false);
+                    let total = round(*costs.0 / *costs.1);
+                    -total
+                } else {
+                    let price = *quote.ask * rate * multiplier;
+                    let costs =
+                        brkr.calc_trade_impact(&quote.symbol, &diff_val.abs(), &price, true);
+                    round(*costs.0 / *costs.1)
+                }
+            };
 
         for symbol in target_weights.keys() {
             let curr_val = brkr.get_position_value(&symbol).unwrap_or_default();
             //Iterating over target_weights so will always find value
-            let target_val = CashValue::from(
-                *total_value * **target_weights.get(&symbol).unwrap(),
-            );
+            let target_val = CashValue::from(*total_value * **target_weights.get(&symbol).unwrap());
             let diff_val = CashValue::from(*target_val - *curr_val);
             if (*diff_val).eq(&0.0) {
                 break;
@@ -748,8 +2258,14 @@ impl BrokerCalculations {
             //We do not throw an error here, we just proceed assuming that the client has passed in data that will
             //eventually prove correct if we are missing quotes for the current time.
             if let Some(quote) = brkr.get_quote(&symbol) {
+                //Same assumption as for quotes, the rate may be available at a later time
+                let rate = match brkr.get_fx_rate(&quote.currency) {
+                    Some(rate) => rate,
+                    None => continue,
+                };
                 //This will be negative if the net is selling
-                let required_shares = calc_required_shares_with_costs(&diff_val, &quote, brkr);
+                let required_shares =
+                    calc_required_shares_with_costs(&diff_val, quote, &rate, brkr);
                 //Clear any pending orders on the exchange
                 brkr.clear_pending_market_orders_by_symbol(&symbol);
                 if required_shares.ne(&0.0) {
@@ -776,23 +2292,44 @@ impl BrokerCalculations {
         orders
     }
 
+    //Only the part of the order that opens or increases a position requires cash: buys that
+    //cover a short position and sells out of a long position reduce the exposure of the
+    //portfolio. Trade costs are estimated on the value of that part of the order.
     pub fn client_has_sufficient_cash(
         order: &Order,
         price: &Price,
         brkr: &impl BacktestBroker,
     ) -> Result<(), InsufficientCashError> {
-        let shares = order.get_shares();
-        let value = CashValue::from(**shares * **price);
-        match order.get_order_type() {
-            OrderType::MarketBuy => {
-                if brkr.get_cash_balance() > value {
-                    return Ok(());
-                }
-                Err(InsufficientCashError)
-            }
-            OrderType::MarketSell => Ok(()),
-            _ => unreachable!("Shouldn't hit unless something has gone wrong"),
+        let shares = **order.get_shares();
+        let curr_position = brkr
+            .get_position_qty(order.get_symbol())
+            .map(|qty| **qty)
+            .unwrap_or(0.0);
+        let opening_shares = if order.get_order_type().is_buy() {
+            shares - shares.min((-curr_position).max(0.0))
+        } else {
+            shares - shares.min(curr_position.max(0.0))
+        };
+        if opening_shares.eq(&0.0) {
+            return Ok(());
+        }
+        let value = opening_shares * **price;
+        let (net_value, net_price) = brkr.calc_trade_impact(
+            order.get_symbol(),
+            &value,
+            price,
+            order.get_order_type().is_buy(),
+        );
+        let net_shares = if *net_price != 0.0 {
+            *net_value / *net_price
+        } else {
+            0.0
+        };
+        let costs = (value - *net_value) + (*net_price - **price).abs() * net_shares;
+        if *brkr.get_buying_power() >= value + costs {
+            return Ok(());
         }
+        Err(InsufficientCashError)
     }
 
     pub fn client_has_sufficient_holdings_for_sale(
@@ -800,7 +2337,7 @@ impl BrokerCalculations {
         brkr: &impl BacktestBroker,
     ) -> Result<(), UnexecutableOrderError> {
         if let OrderType::MarketSell = order.get_order_type() {
-            if let Some(holding) = brkr.get_position_qty(&order.get_symbol()) {
+            if let Some(holding) = brkr.get_position_qty(order.get_symbol()) {
                 if *holding >= order.shares {
                     return Ok(());
                 }
@@ -811,11 +2348,47 @@ impl BrokerCalculations {
         }
     }
 
-    pub fn client_is_issuing_nonsense_order(order: &Order) -> Result<(), UnexecutableOrderError> {
+    pub fn client_order_fits_instrument(
+        order: &Order,
+        instrument: &Instrument,
+    ) -> Result<(), UnexecutableOrderError> {
+        if !instrument.is_valid_quantity(order.get_shares()) {
+            return Err(UnexecutableOrderError);
+        }
+        if let Some(price) = order.get_price() {
+            if !instrument.is_valid_price(price) {
+                return Err(UnexecutableOrderError);
+            }
+        }
+        Ok(())
+    }
+
+    //Orders must be for an instrument that can be traded on the current date, and must fit the
+    //lot and tick sizes of the instrument
+    pub fn client_is_issuing_nonsense_order(
+        order: &Order,
+        brkr: &impl BacktestBroker,
+    ) -> Result<(), UnexecutableOrderError> {
         let shares = **order.get_shares();
         if shares == 0.0 {
             return Err(UnexecutableOrderError);
         }
+        if !brkr.is_tradable(order.get_symbol()) {
+            return Err(UnexecutableOrderError);
+        }
+        if let Some(instrument) = brkr.get_instrument(order.get_symbol()) {
+            Self::client_order_fits_instrument(order, instrument)?;
+        }
+        if let OrderType::TrailingStopBuy | OrderType::TrailingStopSell = order.get_order_type() {
+            let valid_trail = match order.get_trail() {
+                Some(Trail::Absolute(distance)) => **distance > 0.0,
+                Some(Trail::Percentage(pct)) => *pct > 0.0 && *pct < 1.0,
+                None => false,
+            };
+            if !valid_trail {
+                return Err(UnexecutableOrderError);
+            }
+        }
         Ok(())
     }
 }
@@ -845,7 +2418,7 @@ impl Display for UnexecutableOrderError {
 #[cfg(test)]
 mod tests {
 
-    use crate::broker::{BacktestBroker, OrderType};
+    use crate::broker::{BacktestBroker, BrokerEvent, OrderType};
     use crate::exchange::DefaultExchangeBuilder;
     use crate::input::{fake_data_generator, HashMapInputBuilder};
     use crate::sim::SimulatedBrokerBuilder;
@@ -854,7 +2427,9 @@ mod tests {
     use std::collections::HashMap;
     use std::rc::Rc;
 
-    use super::{BrokerCalculations, BrokerCost, Quote, TransferCash};
+    use super::{
+        BrokerCalculations, BrokerCost, Quote, Trade, TradeType, TransferCash, WithholdingTax,
+    };
 
     #[test]
     fn diff_direction_correct_if_need_to_buy() {
@@ -883,7 +2458,7 @@ mod tests {
         let orders = BrokerCalculations::diff_brkr_against_target_weights(&weights, &mut brkr);
         println!("{:?}", orders);
         let first = orders.first().unwrap();
-        assert!(matches!(first.order_type, OrderType::MarketBuy { .. }));
+        assert!(matches!(first.order_type, OrderType::MarketBuy));
     }
 
     #[test]
@@ -931,7 +2506,7 @@ mod tests {
 
         println!("{:?}", orders1);
         let first = orders1.first().unwrap();
-        assert!(matches!(first.order_type, OrderType::MarketSell { .. }));
+        assert!(matches!(first.order_type, OrderType::MarketSell));
     }
 
     #[test]
@@ -968,6 +2543,46 @@ mod tests {
         assert!(orders.len() == 1);
     }
 
+    #[test]
+    fn diff_direction_correct_if_target_is_short() {
+        let clock = ClockBuilder::with_length_in_days(0, 10)
+            .with_frequency(&Frequency::Daily)
+            .build();
+        let input = fake_data_generator(Rc::clone(&clock));
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_data_source(input.clone())
+            .with_clock(Rc::clone(&clock))
+            .build();
+
+        let mut brkr = SimulatedBrokerBuilder::new()
+            .with_data(input)
+            .with_exchange(exchange)
+            .with_short_selling(true)
+            .build();
+
+        let mut weights = PortfolioAllocation::new();
+        weights.insert("ABC", -0.5);
+
+        brkr.deposit_cash(&100_000.0);
+        let orders = BrokerCalculations::diff_brkr_against_target_weights(&weights, &mut brkr);
+        let first = orders.first().unwrap();
+        assert!(matches!(first.order_type, OrderType::MarketSell));
+
+        let res = brkr.send_orders(orders);
+        assert!(matches!(
+            res.first().unwrap(),
+            BrokerEvent::OrderSentToExchange(..)
+        ));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        assert!(**brkr.get_position_qty("ABC").unwrap() < 0.0);
+    }
+
     #[test]
     #[should_panic]
     fn diff_panics_if_brkr_has_no_cash() {
@@ -1001,24 +2616,53 @@ mod tests {
         let flat = BrokerCost::flat(10.0);
         let pct = BrokerCost::pct_of_value(0.01);
 
-        let res = pershare.trade_impact(&1000.0, &1.0, true);
+        let res = pershare.trade_impact(&1000.0, &1.0, true, None);
         assert!((*res.1).eq(&1.1));
 
-        let res = pershare.trade_impact(&1000.0, &1.0, false);
+        let res = pershare.trade_impact(&1000.0, &1.0, false, None);
         assert!((*res.1).eq(&0.9));
 
-        let res = flat.trade_impact(&1000.0, &1.0, true);
+        let res = flat.trade_impact(&1000.0, &1.0, true, None);
         assert!((*res.0).eq(&990.00));
 
-        let res = pct.trade_impact(&100.0, &1.0, true);
+        let res = pct.trade_impact(&100.0, &1.0, true, None);
         assert!((*res.0).eq(&99.0));
 
         let costs = vec![pershare, flat];
-        let initial = BrokerCost::trade_impact_total(&costs, &1000.0, &1.0, true);
+        let initial = BrokerCost::trade_impact_total(&costs, &1000.0, &1.0, true, None);
         assert!((*initial.0).eq(&990.00));
         assert!((*initial.1).eq(&1.1));
     }
 
+    #[test]
+    fn can_calculate_tiered_bounded_buy_only_and_exchange_costs() {
+        let buy = Trade::new("ABC", 1000.0, 10.0, 100, TradeType::Buy);
+        let sell = Trade::new("ABC", 20_000.0, 200.0, 100, TradeType::Sell);
+        let lse = "LSE".to_string();
+
+        let tiered = BrokerCost::tiered(vec![(10_000.0, 0.001), (0.0, 0.002)]);
+        assert_eq!(*tiered.calc(&buy, None), 2.0);
+        assert_eq!(*tiered.calc(&sell, None), 20.0);
+
+        let bounded = BrokerCost::bounded(BrokerCost::pct_of_value(0.001), Some(5.0), Some(15.0));
+        assert_eq!(*bounded.calc(&buy, None), 5.0);
+        assert_eq!(*bounded.calc(&sell, None), 15.0);
+        let res = bounded.trade_impact(&1000.0, &1.0, true, None);
+        assert_eq!(*res.0, 995.0);
+
+        let stamp_duty = BrokerCost::buy_tax(0.005);
+        assert_eq!(*stamp_duty.calc(&buy, None), 5.0);
+        assert_eq!(*stamp_duty.calc(&sell, None), 0.0);
+        let res = stamp_duty.trade_impact(&1000.0, &1.0, false, None);
+        assert_eq!(*res.0, 1000.0);
+
+        let levy = BrokerCost::exchange_fee("LSE", BrokerCost::flat(1.0));
+        assert_eq!(*levy.calc(&buy, Some(&lse)), 1.0);
+        assert_eq!(*levy.calc(&buy, None), 0.0);
+        let res = levy.trade_impact(&1000.0, &1.0, true, Some(&lse));
+        assert_eq!(*res.0, 999.0);
+    }
+
     #[test]
     fn diff_handles_sent_but_unexecuted_orders() {
         //It is possible for the client to issue orders for infinitely increasing numbers of shares
@@ -1162,4 +2806,16 @@ mod tests {
         //required by the newest price
         assert_eq!(*(*brkr.get_position_qty("ABC").unwrap()), 1200.0);
     }
+
+    #[test]
+    fn withholding_tax_uses_symbol_then_country_then_default_rate() {
+        let mut tax = WithholdingTax::new(0.15);
+        tax.countries.insert("GB".to_string(), 0.0);
+        tax.symbols.insert("ABC".to_string(), 0.3);
+
+        let gb = "GB".to_string();
+        assert_eq!(tax.rate("ABC", Some(&gb)), 0.3);
+        assert_eq!(tax.rate("BCD", Some(&gb)), 0.0);
+        assert_eq!(tax.rate("BCD", None), 0.15);
+    }
 }
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use super::lots::{LotMethod, RealisedGain, TaxLots};
+use super::BrokerRecordedEvent;
+use crate::types::{CashValue, Currency, DateTime};
+
+///Source of an entry in the [PnlLedger].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PnlSource {
+    //Gain realised by a single trade, summed across the tax lots relieved
+    RealisedGain,
+    //Net of any tax withheld
+    Dividend,
+    //Interest paid on cash is positive and interest charged on margin loans is negative
+    Interest,
+    Fee,
+}
+
+///Single item of profit or loss. Realised gains and dividends have a symbol, fees and interest are
+///charged against the account.
+///
+///Values are in the currency of the event, fees and margin interest are in the base currency of
+///the broker.
+#[derive(Clone, Debug)]
+pub struct PnlEntry {
+    pub source: PnlSource,
+    pub symbol: Option<String>,
+    pub value: CashValue,
+    pub currency: Currency,
+    pub date: DateTime,
+}
+
+///Realised profit and loss, built from the events recorded by a broker. Gains are realised as
+///tax lots are relieved, so depend on the [LotMethod].
+///
+///Brokers can update the ledger as each event is recorded, passing in the gains realised by the
+///event, or the ledger can be built by replaying every event.
+///
+///Totals are keyed by currency as entries are not converted into the base currency. Unrealised
+///profit depends on prices so is calculated by the broker.
+#[derive(Clone, Debug, Default)]
+pub struct PnlLedger {
+    base_currency: Currency,
+    entries: Vec<PnlEntry>,
+}
+
+impl PnlLedger {
+    pub fn new(base_currency: &Currency) -> Self {
+        Self {
+            base_currency: *base_currency,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn from_events(
+        method: LotMethod,
+        base_currency: &Currency,
+        events: &[BrokerRecordedEvent],
+    ) -> Self {
+        let mut lots = TaxLots::new(method);
+        let mut ledger = Self::new(base_currency);
+        for event in events {
+            let realised = lots.apply(event);
+            ledger.apply(event, &realised);
+        }
+        ledger
+    }
+
+    //Realised is the gains that the event realised on the tax lots, events that aren't profit or
+    //loss are ignored
+    pub fn apply(&mut self, event: &BrokerRecordedEvent, realised: &[RealisedGain]) {
+        let entry = match event {
+            BrokerRecordedEvent::TradeCompleted(trade)
+            | BrokerRecordedEvent::DividendReinvested(trade) => {
+                if realised.is_empty() {
+                    return;
+                }
+                PnlEntry {
+                    source: PnlSource::RealisedGain,
+                    symbol: Some(trade.symbol.clone()),
+                    value: CashValue::from(realised.iter().map(|gain| *gain.gain()).sum::<f64>()),
+                    currency: trade.currency,
+                    date: trade.date.clone(),
+                }
+            }
+            BrokerRecordedEvent::DividendPaid(dividend) => PnlEntry {
+                source: PnlSource::Dividend,
+                symbol: Some(dividend.symbol.clone()),
+                value: dividend.value.clone(),
+                currency: dividend.currency,
+                date: dividend.date.clone(),
+            },
+            BrokerRecordedEvent::InterestPaid(payment) => PnlEntry {
+                source: PnlSource::Interest,
+                symbol: None,
+                value: payment.value.clone(),
+                currency: payment.currency,
+                date: payment.date.clone(),
+            },
+            BrokerRecordedEvent::MarginInterestCharged(payment) => PnlEntry {
+                source: PnlSource::Interest,
+                symbol: None,
+                value: CashValue::from(-*payment.value),
+                currency: self.base_currency,
+                date: payment.date.clone(),
+            },
+            BrokerRecordedEvent::FeeCharged(charge) => PnlEntry {
+                source: PnlSource::Fee,
+                symbol: None,
+                value: CashValue::from(-*charge.value),
+                currency: self.base_currency,
+                date: charge.date.clone(),
+            },
+            _ => return,
+        };
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> Vec<PnlEntry> {
+        self.entries.clone()
+    }
+
+    //All entries up to and including date
+    pub fn entries_at(&self, date: &DateTime) -> Vec<PnlEntry> {
+        self.until(date).cloned().collect()
+    }
+
+    //Realised gains and dividends on symbol up to and including date
+    pub fn symbol_total(&self, symbol: &str, date: &DateTime) -> HashMap<Currency, CashValue> {
+        Self::sum(
+            self.until(date)
+                .filter(|entry| entry.symbol.as_deref() == Some(symbol)),
+        )
+    }
+
+    pub fn source_total(
+        &self,
+        source: &PnlSource,
+        date: &DateTime,
+    ) -> HashMap<Currency, CashValue> {
+        Self::sum(self.until(date).filter(|entry| entry.source == *source))
+    }
+
+    //Includes fees and interest
+    pub fn total(&self, date: &DateTime) -> HashMap<Currency, CashValue> {
+        Self::sum(self.until(date))
+    }
+
+    fn until<'a>(&'a self, date: &'a DateTime) -> impl Iterator<Item = &'a PnlEntry> {
+        self.entries.iter().filter(move |entry| entry.date <= *date)
+    }
+
+    fn sum<'a>(entries: impl Iterator<Item = &'a PnlEntry>) -> HashMap<Currency, CashValue> {
+        let mut totals: HashMap<Currency, CashValue> = HashMap::new();
+        for entry in entries {
+            let total = totals.entry(entry.currency).or_default();
+            *total = CashValue::from(**total + *entry.value);
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PnlLedger, PnlSource};
+    use crate::broker::lots::LotMethod;
+    use crate::broker::{
+        AccountFee, BrokerRecordedEvent, DividendPayment, FeeCharge, InterestPayment, Trade,
+        TradeType,
+    };
+    use crate::types::{Currency, Frequency};
+
+    #[test]
+    fn test_that_ledger_records_sources_separately() {
+        let events: Vec<BrokerRecordedEvent> = vec![
+            Trade::new("ABC", 1000.0, 10.0, 100, TradeType::Buy).into(),
+            Trade::new("ABC", 3000.0, 10.0, 101, TradeType::Buy).into(),
+            DividendPayment::new(50.0, "ABC", 102).into(),
+            Trade::new("ABC", 1500.0, 10.0, 103, TradeType::Sell).into(),
+            BrokerRecordedEvent::InterestPaid(InterestPayment::new(10.0, 104)),
+            BrokerRecordedEvent::MarginInterestCharged(InterestPayment::new(4.0, 104)),
+            FeeCharge::new(5.0, 105, AccountFee::flat(5.0, Frequency::Monthly)).into(),
+        ];
+        let usd = Currency::default();
+        let ledger = PnlLedger::from_events(LotMethod::Fifo, &usd, &events);
+
+        //Buys realise nothing so aren't recorded
+        assert_eq!(ledger.entries().len(), 5);
+        assert_eq!(
+            *ledger.source_total(&PnlSource::RealisedGain, &105.into())[&usd],
+            500.0
+        );
+        assert_eq!(
+            *ledger.source_total(&PnlSource::Interest, &105.into())[&usd],
+            6.0
+        );
+        assert_eq!(*ledger.symbol_total("ABC", &102.into())[&usd], 50.0);
+        assert_eq!(*ledger.symbol_total("ABC", &105.into())[&usd], 550.0);
+        assert_eq!(*ledger.total(&104.into())[&usd], 556.0);
+        assert_eq!(*ledger.total(&105.into())[&usd], 551.0);
+
+        let hifo = PnlLedger::from_events(LotMethod::Hifo, &usd, &events);
+        assert_eq!(*hifo.symbol_total("ABC", &105.into())[&usd], -1450.0);
+    }
+
+    #[test]
+    fn test_that_totals_are_kept_separate_by_currency() {
+        let events: Vec<BrokerRecordedEvent> = vec![
+            Trade::new_with_currency("ABC", 1000.0, 10.0, 100, TradeType::Buy, Currency::USD)
+                .into(),
+            Trade::new_with_currency("ABC", 1200.0, 10.0, 101, TradeType::Sell, Currency::USD)
+                .into(),
+            FeeCharge::new(5.0, 102, AccountFee::flat(5.0, Frequency::Monthly)).into(),
+        ];
+        let ledger = PnlLedger::from_events(LotMethod::Fifo, &Currency::GBP, &events);
+
+        let total = ledger.total(&102.into());
+        assert_eq!(total.len(), 2);
+        assert_eq!(*total[&Currency::USD], 200.0);
+        assert_eq!(*total[&Currency::GBP], -5.0);
+    }
+}
@@ -1,7 +1,15 @@
 use itertools::Itertools;
+use std::collections::HashMap;
 
-use super::{BrokerRecordedEvent, DividendPayment, Trade, TradeType};
-use crate::types::{CashValue, DateTime, PortfolioQty, Price};
+use super::cgt::UkCapitalGains;
+use super::lots::{LotMethod, RealisedGain, TaxLots};
+use super::pnl::PnlLedger;
+use super::wash::{WashSale, WashSales};
+use super::{
+    BrokerRecordedEvent, CorporateAction, DividendPayment, FeeCharge, FxRate, InterestPayment,
+    MarginCall, OrderExpiry, Trade, TradeType,
+};
+use crate::types::{Currency, DateTime, Price};
 
 ///Records certain events executed by the broker.
 ///
@@ -18,10 +26,23 @@ impl BrokerLog {
         self.log.push(brokerevent);
     }
 
+    //Includes trades that reinvested dividends
     pub fn trades(&self) -> Vec<Trade> {
         let mut trades = Vec::new();
         for event in &self.log {
-            if let BrokerRecordedEvent::TradeCompleted(trade) = event {
+            match event {
+                BrokerRecordedEvent::TradeCompleted(trade)
+                | BrokerRecordedEvent::DividendReinvested(trade) => trades.push(trade.clone()),
+                _ => (),
+            }
+        }
+        trades
+    }
+
+    pub fn dividend_reinvestments(&self) -> Vec<Trade> {
+        let mut trades = Vec::new();
+        for event in &self.log {
+            if let BrokerRecordedEvent::DividendReinvested(trade) = event {
                 trades.push(trade.clone());
             }
         }
@@ -38,6 +59,57 @@ impl BrokerLog {
         dividends
     }
 
+    //Interest paid on cash, does not include interest charged on margin loans
+    pub fn interest_paid(&self) -> Vec<InterestPayment> {
+        let mut payments = Vec::new();
+        for event in &self.log {
+            if let BrokerRecordedEvent::InterestPaid(payment) = event {
+                payments.push(payment.clone());
+            }
+        }
+        payments
+    }
+
+    pub fn fees(&self) -> Vec<FeeCharge> {
+        let mut fees = Vec::new();
+        for event in &self.log {
+            if let BrokerRecordedEvent::FeeCharged(charge) = event {
+                fees.push(charge.clone());
+            }
+        }
+        fees
+    }
+
+    pub fn margin_calls(&self) -> Vec<MarginCall> {
+        let mut margin_calls = Vec::new();
+        for event in &self.log {
+            if let BrokerRecordedEvent::MarginCall(margin_call) = event {
+                margin_calls.push(margin_call.clone());
+            }
+        }
+        margin_calls
+    }
+
+    pub fn expired_orders(&self) -> Vec<OrderExpiry> {
+        let mut expired = Vec::new();
+        for event in &self.log {
+            if let BrokerRecordedEvent::OrderExpired(expiry) = event {
+                expired.push(expiry.clone());
+            }
+        }
+        expired
+    }
+
+    pub fn corporate_actions(&self) -> Vec<CorporateAction> {
+        let mut actions = Vec::new();
+        for event in &self.log {
+            if let BrokerRecordedEvent::CorporateAction(action) = event {
+                actions.push(action.clone());
+            }
+        }
+        actions
+    }
+
     pub fn dividends_between(&self, start: &i64, stop: &i64) -> Vec<DividendPayment> {
         let dividends = self.dividends();
         dividends
@@ -56,33 +128,118 @@ impl BrokerLog {
             .collect_vec()
     }
 
+    pub fn tax_lots(&self, method: LotMethod) -> TaxLots {
+        TaxLots::from_events(method, &self.log)
+    }
+
+    pub fn wash_sales(&self, method: LotMethod) -> WashSales {
+        WashSales::from_events(method, &self.log)
+    }
+
+    pub fn wash_sales_between(&self, method: LotMethod, start: &i64, stop: &i64) -> Vec<WashSale> {
+        self.wash_sales(method)
+            .wash_sales()
+            .into_iter()
+            .filter(|v| {
+                v.sale_date >= DateTime::from(*start) && v.sale_date <= DateTime::from(*stop)
+            })
+            .collect_vec()
+    }
+
+    pub fn adjusted_gains_between(
+        &self,
+        method: LotMethod,
+        start: &i64,
+        stop: &i64,
+    ) -> Vec<RealisedGain> {
+        self.wash_sales(method)
+            .realised()
+            .into_iter()
+            .filter(|v| v.disposed >= DateTime::from(*start) && v.disposed <= DateTime::from(*stop))
+            .collect_vec()
+    }
+
+    pub fn uk_capital_gains(&self, rates: &[FxRate]) -> UkCapitalGains {
+        UkCapitalGains::new(&self.trades(), &self.corporate_actions(), rates)
+    }
+
+    pub fn pnl_ledger(&self, method: LotMethod, base_currency: &Currency) -> PnlLedger {
+        PnlLedger::from_events(method, base_currency, &self.log)
+    }
+
+    pub fn realised_gains_between(
+        &self,
+        method: LotMethod,
+        start: &i64,
+        stop: &i64,
+    ) -> Vec<RealisedGain> {
+        self.tax_lots(method)
+            .realised()
+            .into_iter()
+            .filter(|v| v.disposed >= DateTime::from(*start) && v.disposed <= DateTime::from(*stop))
+            .collect_vec()
+    }
+
+    //Corporate actions can move cost between symbols, so the cost of every position is tracked
+    //as quantity and value
     pub fn cost_basis(&self, symbol: &str) -> Option<Price> {
-        let mut cum_qty = PortfolioQty::default();
-        let mut cum_val = CashValue::default();
+        let mut positions: HashMap<String, (f64, f64)> = HashMap::new();
         for event in &self.log {
-            if let BrokerRecordedEvent::TradeCompleted(trade) = event {
-                if trade.symbol.eq(symbol) {
+            match event {
+                BrokerRecordedEvent::TradeCompleted(trade)
+                | BrokerRecordedEvent::DividendReinvested(trade) => {
+                    let (cum_qty, cum_val) = positions.entry(trade.symbol.clone()).or_default();
                     match trade.typ {
                         TradeType::Buy => {
-                            cum_qty = PortfolioQty::from(*cum_qty + *trade.quantity.clone());
-                            cum_val = CashValue::from(*cum_val + *trade.value.clone());
+                            *cum_qty += *trade.quantity;
+                            *cum_val += *trade.value;
                         }
                         TradeType::Sell => {
-                            cum_qty = PortfolioQty::from(*cum_qty - *trade.quantity.clone());
-                            cum_val = CashValue::from(*cum_val - *trade.value.clone());
+                            *cum_qty -= *trade.quantity;
+                            *cum_val -= *trade.value;
                         }
                     }
                     //reset the value if we are back to zero
                     if (*cum_qty).eq(&0.0) {
-                        cum_val = CashValue::default();
+                        *cum_val = 0.0;
                     }
                 }
+                BrokerRecordedEvent::CorporateAction(action) => match action {
+                    //Split changes the number of shares but not the total cost
+                    CorporateAction::Split(split) => {
+                        if let Some((cum_qty, _cum_val)) = positions.get_mut(&split.symbol) {
+                            *cum_qty *= split.ratio;
+                        }
+                    }
+                    CorporateAction::StockMerger(merger) => {
+                        if let Some((qty, val)) = positions.remove(&merger.symbol) {
+                            let (cum_qty, cum_val) =
+                                positions.entry(merger.new_symbol.clone()).or_default();
+                            *cum_qty += qty * merger.ratio;
+                            *cum_val += val;
+                        }
+                    }
+                    CorporateAction::SpinOff(spin_off) => {
+                        if let Some((qty, val)) = positions.get_mut(&spin_off.symbol) {
+                            let moved_qty = *qty * spin_off.ratio;
+                            let moved_val = *val * spin_off.cost_allocation;
+                            *val -= moved_val;
+                            let (cum_qty, cum_val) =
+                                positions.entry(spin_off.new_symbol.clone()).or_default();
+                            *cum_qty += moved_qty;
+                            *cum_val += moved_val;
+                        }
+                    }
+                    //Positions are closed with a trade
+                    CorporateAction::CashMerger(_) | CorporateAction::Delisting(_) => (),
+                },
+                _ => (),
             }
         }
-        if (*cum_qty).eq(&0.0) {
-            return None;
+        match positions.get(symbol) {
+            Some((cum_qty, cum_val)) if !cum_qty.eq(&0.0) => Some(Price::from(cum_val / cum_qty)),
+            _ => None,
         }
-        Some(Price::from(*cum_val / *cum_qty))
     }
 }
 
@@ -102,7 +259,7 @@ impl Default for BrokerLog {
 mod tests {
     use super::BrokerLog;
 
-    use crate::broker::{Trade, TradeType};
+    use crate::broker::{CorporateAction, Split, Trade, TradeType};
 
     fn setup() -> BrokerLog {
         let mut rec = BrokerLog::new();
@@ -137,4 +294,15 @@ mod tests {
         assert_eq!(*abc_cost, 6.0);
         assert_eq!(*bcd_cost, 1.0);
     }
+
+    #[test]
+    fn test_that_cost_basis_is_adjusted_for_split() {
+        let mut log = setup();
+        log.record(CorporateAction::from(Split::new("ABC", 2.0, 105)));
+        assert_eq!(*log.cost_basis("ABC").unwrap(), 3.0);
+
+        log.record(CorporateAction::from(Split::new("ABC", 0.1, 106)));
+        assert_eq!(*log.cost_basis("ABC").unwrap(), 30.0);
+        assert_eq!(log.corporate_actions().len(), 2);
+    }
 }
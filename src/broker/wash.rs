@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use super::lots::{LotMethod, RealisedGain, TaxLot, TaxLots};
+use super::{BrokerRecordedEvent, TradeType};
+use crate::types::{CashValue, DateTime, PortfolioQty};
+
+///Loss on shares sold that is disallowed under the US wash sale rule because shares of the same
+///security were bought within 30 days before or after the sale. The disallowed loss is added to
+///the cost of the replacement lot.
+///
+///For a loss on closing a short position the replacement is another short sale, and the disallowed
+///loss reduces the proceeds of the replacement lot.
+#[derive(Clone, Debug)]
+pub struct WashSale {
+    pub symbol: String,
+    //Lot sold at a loss
+    pub lot_id: u64,
+    pub sale_date: DateTime,
+    pub quantity: PortfolioQty,
+    //Positive value
+    pub disallowed: CashValue,
+    pub replacement_lot_id: u64,
+    pub replacement_date: DateTime,
+}
+
+//Lot opened that can still replace shares disposed of at a loss
+#[derive(Clone, Debug)]
+struct Acquisition {
+    lot_id: u64,
+    symbol: String,
+    date: DateTime,
+    available: f64,
+    short: bool,
+}
+
+//Loss that can still be washed by a lot opened within the window after the disposal
+#[derive(Clone, Debug)]
+struct Loss {
+    gain: RealisedGain,
+    //Position of the gain in the realised gains of [WashSales]
+    index: usize,
+    unmatched: f64,
+    short: bool,
+}
+
+///Wash sales found by replaying the events recorded by a broker. Losses are matched against
+///replacement shares in the order the replacement shares were bought, and each replacement share
+///can only wash one share sold.
+///
+///Losses on long and short positions are checked, long losses are replaced by purchases and short
+///losses by short sales. Realised gains are adjusted for wash sales: the disallowed loss is
+///removed from the disposal and carried into the replacement lot, so is realised when that lot is
+///closed.
+///
+///Replacement shares take on the holding period of the shares disposed of, so gains realised on
+///them are acquired earlier than the replacement lot. Within a lot, replacement shares are
+///relieved first. Open lots keep the date they were opened, and holding periods aren't carried
+///into lots created by a spin-off.
+#[derive(Clone, Debug)]
+pub struct WashSales {
+    lots: TaxLots,
+    wash_sales: Vec<WashSale>,
+    realised: Vec<RealisedGain>,
+    //Fraction of the open quantity of a lot that replaced shares, and the holding period in
+    //seconds that those shares take on. Kept as a fraction so that splits and mergers don't
+    //change it.
+    tacked: HashMap<u64, Vec<(f64, i64)>>,
+}
+
+impl WashSales {
+    const WINDOW: i64 = 30 * 86_400;
+    const TOLERANCE: f64 = 1e-9;
+
+    pub fn from_events(method: LotMethod, events: &[BrokerRecordedEvent]) -> Self {
+        let mut wash_sales = Self {
+            lots: TaxLots::new(method),
+            wash_sales: Vec::new(),
+            realised: Vec::new(),
+            tacked: HashMap::new(),
+        };
+        let mut acquisitions: Vec<Acquisition> = Vec::new();
+        let mut losses: Vec<Loss> = Vec::new();
+        for event in events {
+            let next_id = wash_sales.lots.next_id();
+            let realised = wash_sales.lots.apply(event);
+            let trade = match event {
+                BrokerRecordedEvent::TradeCompleted(trade)
+                | BrokerRecordedEvent::DividendReinvested(trade) => trade,
+                _ => continue,
+            };
+            //Buys realise gains on short lots, and sales on long lots
+            let short = matches!(trade.typ, TradeType::Buy);
+            let realised: Vec<RealisedGain> = realised
+                .into_iter()
+                .flat_map(|gain| wash_sales.tack(gain))
+                .collect();
+            for gain in realised {
+                let index = wash_sales.realised.len();
+                wash_sales.realised.push(gain.clone());
+                if *gain.gain() >= 0.0 {
+                    continue;
+                }
+                let replaces = |acquisition: &&mut Acquisition| {
+                    acquisition.symbol == gain.symbol
+                        && acquisition.short == short
+                        && acquisition.lot_id != gain.lot_id
+                        && *gain.disposed - *acquisition.date <= Self::WINDOW
+                };
+                let candidates: Vec<&mut Acquisition> =
+                    acquisitions.iter_mut().filter(replaces).collect();
+                let mut loss = Loss {
+                    unmatched: *gain.quantity,
+                    gain,
+                    index,
+                    short,
+                };
+                for acquisition in candidates {
+                    wash_sales.wash(&mut loss, acquisition);
+                }
+                if loss.unmatched > Self::TOLERANCE {
+                    losses.push(loss);
+                }
+            }
+
+            //Trades that only close positions don't open a lot
+            let quantity = match wash_sales.lots.lot(next_id) {
+                Some(lot) => *lot.quantity,
+                None => continue,
+            };
+            let opens_short = quantity < 0.0;
+            let mut acquisition = Acquisition {
+                lot_id: next_id,
+                symbol: trade.symbol.clone(),
+                date: trade.date.clone(),
+                available: quantity.abs(),
+                short: opens_short,
+            };
+            losses.retain(|loss| *trade.date - *loss.gain.disposed <= Self::WINDOW);
+            for loss in losses
+                .iter_mut()
+                .filter(|loss| loss.gain.symbol == trade.symbol && loss.short == opens_short)
+            {
+                wash_sales.wash(loss, &mut acquisition);
+            }
+            losses.retain(|loss| loss.unmatched > Self::TOLERANCE);
+            acquisitions.push(acquisition);
+        }
+        wash_sales
+    }
+
+    pub fn wash_sales(&self) -> Vec<WashSale> {
+        self.wash_sales.clone()
+    }
+
+    pub fn disallowed(&self) -> CashValue {
+        CashValue::from(self.wash_sales.iter().map(|w| *w.disallowed).sum::<f64>())
+    }
+
+    pub fn open_lots(&self, symbol: &str) -> Vec<TaxLot> {
+        self.lots.open_lots(symbol)
+    }
+
+    pub fn realised(&self) -> Vec<RealisedGain> {
+        self.realised.clone()
+    }
+
+    //Splits a gain on a lot with replacement shares into the gain on those shares, with the
+    //holding period they took on, and the gain on the rest of the shares
+    fn tack(&mut self, gain: RealisedGain) -> Vec<RealisedGain> {
+        let tacks = match self.tacked.remove(&gain.lot_id) {
+            Some(tacks) => tacks,
+            None => return vec![gain],
+        };
+        let remaining = self
+            .lots
+            .lot(gain.lot_id)
+            .map(|lot| lot.quantity.abs())
+            .unwrap_or_default();
+        let before = *gain.quantity + remaining;
+        let mut untacked = *gain.quantity;
+        let mut gains = Vec::new();
+        let mut left = Vec::new();
+        for (fraction, period) in tacks {
+            let shares = fraction * before;
+            let relieved = shares.min(untacked);
+            if relieved > Self::TOLERANCE {
+                gains.push(Self::part(&gain, relieved, period));
+                untacked -= relieved;
+            }
+            if shares - relieved > Self::TOLERANCE && remaining > Self::TOLERANCE {
+                left.push(((shares - relieved) / remaining, period));
+            }
+        }
+        if untacked > Self::TOLERANCE {
+            gains.push(Self::part(&gain, untacked, 0));
+        }
+        if !left.is_empty() {
+            self.tacked.insert(gain.lot_id, left);
+        }
+        gains
+    }
+
+    fn part(gain: &RealisedGain, quantity: f64, period: i64) -> RealisedGain {
+        let share = quantity / *gain.quantity;
+        RealisedGain {
+            quantity: PortfolioQty::from(quantity),
+            proceeds: CashValue::from(*gain.proceeds * share),
+            cost: CashValue::from(*gain.cost * share),
+            acquired: DateTime::from(*gain.acquired - period),
+            ..gain.clone()
+        }
+    }
+
+    //Replacement shares that have since been disposed of can't be used
+    fn wash(&mut self, loss: &mut Loss, acquisition: &mut Acquisition) {
+        let open = self
+            .lots
+            .lot(acquisition.lot_id)
+            .map(|lot| lot.quantity.abs())
+            .unwrap_or_default();
+        let matched = loss.unmatched.min(acquisition.available).min(open);
+        if matched <= Self::TOLERANCE {
+            return;
+        }
+        let disallowed = -*loss.gain.gain() * matched / *loss.gain.quantity;
+        //Cost of a short lot is the proceeds of the short sale
+        let adjustment = if acquisition.short {
+            -disallowed
+        } else {
+            disallowed
+        };
+        self.lots.adjust_cost(acquisition.lot_id, adjustment);
+        self.tacked
+            .entry(acquisition.lot_id)
+            .or_default()
+            .push((matched / open, loss.gain.holding_period()));
+        let sale = &mut self.realised[loss.index];
+        sale.cost = CashValue::from(*sale.cost - disallowed);
+        loss.unmatched -= matched;
+        acquisition.available -= matched;
+        self.wash_sales.push(WashSale {
+            symbol: loss.gain.symbol.clone(),
+            lot_id: loss.gain.lot_id,
+            sale_date: loss.gain.disposed.clone(),
+            quantity: PortfolioQty::from(matched),
+            disallowed: CashValue::from(disallowed),
+            replacement_lot_id: acquisition.lot_id,
+            replacement_date: acquisition.date.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WashSales;
+    use crate::broker::lots::LotMethod;
+    use crate::broker::{BrokerRecordedEvent, Trade, TradeType};
+
+    const DAY: i64 = 86_400;
+
+    fn trade(value: f64, quantity: f64, day: i64, typ: TradeType) -> BrokerRecordedEvent {
+        Trade::new("ABC", value, quantity, day * DAY, typ).into()
+    }
+
+    #[test]
+    fn test_that_repurchase_after_loss_disallows_loss_and_adjusts_cost() {
+        let events = vec![
+            trade(1000.0, 100.0, 0, TradeType::Buy),
+            trade(800.0, 100.0, 10, TradeType::Sell),
+            trade(900.0, 100.0, 40, TradeType::Buy),
+        ];
+        let wash_sales = WashSales::from_events(LotMethod::Fifo, &events);
+
+        let sales = wash_sales.wash_sales();
+        assert_eq!(sales.len(), 1);
+        assert_eq!(sales[0].lot_id, 0);
+        assert_eq!(sales[0].replacement_lot_id, 1);
+        assert_eq!(*sales[0].disallowed, 200.0);
+        assert_eq!(*wash_sales.open_lots("ABC")[0].cost, 1100.0);
+
+        //Repurchase is outside the window
+        let mut events = events;
+        events[2] = trade(900.0, 100.0, 41, TradeType::Buy);
+        let wash_sales = WashSales::from_events(LotMethod::Fifo, &events);
+        assert!(wash_sales.wash_sales().is_empty());
+        assert_eq!(*wash_sales.open_lots("ABC")[0].cost, 900.0);
+    }
+
+    #[test]
+    fn test_that_earlier_purchase_washes_part_of_loss() {
+        let events = vec![
+            trade(1000.0, 100.0, 0, TradeType::Buy),
+            trade(450.0, 50.0, 5, TradeType::Buy),
+            trade(800.0, 100.0, 10, TradeType::Sell),
+            trade(1000.0, 50.0, 15, TradeType::Buy),
+            trade(400.0, 25.0, 16, TradeType::Buy),
+        ];
+        let wash_sales = WashSales::from_events(LotMethod::Fifo, &events);
+
+        //Half the shares are replaced by the earlier purchase and half by the first later one
+        let sales = wash_sales.wash_sales();
+        assert_eq!(sales.len(), 2);
+        assert_eq!(sales[0].replacement_lot_id, 1);
+        assert_eq!(sales[1].replacement_lot_id, 2);
+        assert_eq!(*wash_sales.disallowed(), 200.0);
+
+        let open = wash_sales.open_lots("ABC");
+        assert_eq!(*open[0].cost, 550.0);
+        assert_eq!(*open[1].cost, 1100.0);
+        assert_eq!(*open[2].cost, 400.0);
+        //Loss is fully disallowed so nothing is realised on the sale
+        assert_eq!(*wash_sales.realised()[0].gain(), 0.0);
+    }
+
+    #[test]
+    fn test_that_disallowed_loss_is_realised_once_replacement_is_sold() {
+        let events = vec![
+            trade(1000.0, 100.0, 0, TradeType::Buy),
+            trade(800.0, 100.0, 10, TradeType::Sell),
+            trade(900.0, 100.0, 20, TradeType::Buy),
+            trade(900.0, 100.0, 50, TradeType::Sell),
+        ];
+        let wash_sales = WashSales::from_events(LotMethod::Fifo, &events);
+
+        let realised = wash_sales.realised();
+        assert_eq!(realised.len(), 2);
+        assert_eq!(*realised[0].gain(), 0.0);
+        assert_eq!(*realised[1].gain(), -200.0);
+        let total: f64 = realised.iter().map(|gain| *gain.gain()).sum();
+        assert_eq!(total, -200.0);
+    }
+
+    #[test]
+    fn test_that_loss_on_short_is_washed_by_another_short_sale() {
+        let events = vec![
+            trade(1000.0, 100.0, 0, TradeType::Sell),
+            trade(1200.0, 100.0, 10, TradeType::Buy),
+            trade(900.0, 100.0, 20, TradeType::Sell),
+            trade(900.0, 100.0, 50, TradeType::Buy),
+        ];
+        let wash_sales = WashSales::from_events(LotMethod::Fifo, &events);
+
+        //Disallowed loss reduces the proceeds of the second short, and is realised when it closes
+        let sales = wash_sales.wash_sales();
+        assert_eq!(sales.len(), 1);
+        assert_eq!(sales[0].replacement_lot_id, 1);
+        assert_eq!(*sales[0].disallowed, 200.0);
+        let realised = wash_sales.realised();
+        assert_eq!(*realised[0].gain(), 0.0);
+        assert_eq!(*realised[1].gain(), -200.0);
+
+        //Purchase opens a long lot so doesn't replace the short
+        let mut events = events;
+        events[2] = trade(900.0, 100.0, 20, TradeType::Buy);
+        let wash_sales = WashSales::from_events(LotMethod::Fifo, &events);
+        assert!(wash_sales.wash_sales().is_empty());
+    }
+
+    #[test]
+    fn test_that_replacement_shares_take_on_holding_period_of_shares_sold() {
+        let events = vec![
+            trade(1000.0, 100.0, 0, TradeType::Buy),
+            trade(800.0, 100.0, 10, TradeType::Sell),
+            trade(1800.0, 200.0, 20, TradeType::Buy),
+            trade(1350.0, 150.0, 50, TradeType::Sell),
+            trade(500.0, 50.0, 60, TradeType::Sell),
+        ];
+        let wash_sales = WashSales::from_events(LotMethod::Fifo, &events);
+
+        //Half of the replacement lot was held for ten days before it was bought, these shares are
+        //relieved first
+        let realised = wash_sales.realised();
+        assert_eq!(realised.len(), 4);
+        assert_eq!(*realised[1].quantity, 100.0);
+        assert_eq!(realised[1].holding_period(), 40 * DAY);
+        assert_eq!(*realised[2].quantity, 50.0);
+        assert_eq!(realised[2].holding_period(), 30 * DAY);
+        assert_eq!(*realised[3].quantity, 50.0);
+        assert_eq!(realised[3].holding_period(), 40 * DAY);
+        //Loss of 200 on the first lot and gain of 50 on the replacement are both realised
+        let total: f64 = realised.iter().map(|gain| *gain.gain()).sum();
+        assert!((total + 150.0).abs() < 1e-6);
+    }
+}
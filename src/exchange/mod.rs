@@ -1,10 +1,17 @@
 use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::broker::{Order, OrderType, Quote, Trade, TradeType};
+use crate::broker::{
+    Bar, BracketOrder, Instrument, InstrumentRegistry, Order, OrderExpiry, OrderId, OrderStatus,
+    OrderType, Quote, Split, TimeInForce, Trade, TradeType,
+};
 use crate::clock::Clock;
 use crate::input::DataSource;
-use crate::types::CashValue;
+use crate::types::{CashValue, DateTime, PortfolioQty, Price};
+
+pub mod slippage;
+
+use slippage::SlippageModel;
 
 ///Exchanges accept orders for securities, store them on an internal order book, and then execute
 ///them over time.
@@ -25,19 +32,43 @@ use crate::types::CashValue;
 ///Clients must check, then insert new orders, then finish; ordering of operations should be
 ///maintained through state in the implementation.
 pub trait Exchange {
-    fn insert_order(&mut self, order: Order) -> DefaultExchangeOrderId;
-    fn delete_order(&mut self, order_id: DefaultExchangeOrderId);
-    fn get_order(&self, order_id: &DefaultExchangeOrderId) -> Option<&Order>;
+    fn insert_order(&mut self, order: Order) -> OrderId;
+    //Inserts a one-cancels-other group: when any order in the group executes, in full or in part,
+    //the other orders are removed from the book
+    fn insert_oco_group(&mut self, orders: Vec<Order>) -> Vec<OrderId>;
+    //Inserts the entry order, the exits are inserted as a one-cancels-other group once the entry
+    //has executed. Returns the id of the entry order.
+    fn insert_bracket(&mut self, bracket: BracketOrder) -> OrderId;
+    //Removes an order from the book before it has executed, the order is cancelled
+    fn delete_order(&mut self, order_id: OrderId);
+    //Replaces an order in the book, returns false if the order is no longer in the book
+    fn modify_order(&mut self, order_id: OrderId, order: Order) -> bool;
+    fn get_order(&self, order_id: &OrderId) -> Option<&Order>;
+    //Status is held for every order inserted, including those no longer in the book
+    fn get_order_status(&self, order_id: &OrderId) -> Option<&OrderStatus>;
     fn check(&mut self);
     fn finish(&mut self);
     fn get_trade_log(&self) -> Vec<Trade>;
     //Represents size of orders in orderbook
     fn orderbook_size(&self) -> usize;
     fn flush_buffer(&mut self) -> Vec<Trade>;
+    //Orders that have expired since the last flush, follows the same rules as `flush_buffer`
+    fn flush_expired(&mut self) -> Vec<OrderExpiry>;
     fn get_quote(&self, symbol: &str) -> Option<&Quote>;
     fn get_quotes(&self) -> Option<&Vec<Quote>>;
+    fn get_instrument(&self, symbol: &str) -> Option<&Instrument>;
+    //Symbols can be traded if they are listed on the current date. Exchanges without instruments
+    //can trade any symbol.
+    fn is_tradable(&self, symbol: &str) -> bool;
+    //Restates orders and the last seen price in post-split shares, orders that round down to zero
+    //shares are cancelled
+    fn apply_split(&mut self, split: &Split);
+    fn cancel_orders_by_symbol(&mut self, symbol: &str);
     fn clear(&mut self);
     fn clear_pending_market_orders_by_symbol(&mut self, symbol: &str);
+    //Current date of the exchange, brokers use this to time events that aren't triggered by
+    //trades
+    fn now(&self) -> DateTime;
 }
 
 ///Exchange state maintains the consistency and timing of transactions. Intended for use with
@@ -54,11 +85,33 @@ enum DefaultExchangeState {
     Ready,
 }
 
-type DefaultExchangeOrderId = u32;
+///Sets the price that limit and stop orders execute at once triggered. Limit buys trigger when the
+///ask is at or below the limit, limit sells when the bid is at or above it. Stop buys trigger when
+///the ask is at or above the stop, stop sells when the bid is at or below it. Orders checked
+///against a bar trigger if the price reached the order price at any point within the bar.
+///
+///Market orders are not affected.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FillPolicy {
+    ///Orders execute at the quote, so limit orders can get a better price than the limit and stops
+    ///can execute through the stop. Against a bar, orders execute at the open if the bar opened
+    ///through the order price and otherwise at the order price.
+    #[default]
+    Quote,
+    ///Orders execute at the order price, even if the price has moved through it.
+    OrderPrice,
+    ///Limit orders execute at the limit price. Stop orders execute at the worse of the stop price
+    ///and the quote, or the open of the bar.
+    GapAware,
+}
 
 pub struct DefaultExchangeBuilder<D: DataSource> {
     data_source: Option<D>,
     clock: Option<Clock>,
+    participation_limit: Option<f64>,
+    slippage: Option<Rc<dyn SlippageModel>>,
+    fill_policy: FillPolicy,
+    instruments: InstrumentRegistry,
 }
 
 impl<D: DataSource> DefaultExchangeBuilder<D> {
@@ -71,10 +124,44 @@ impl<D: DataSource> DefaultExchangeBuilder<D> {
             panic!("Exchange must have clock");
         }
 
-        DefaultExchange::new(
+        let mut exchange = DefaultExchange::new(
             Rc::clone(self.clock.as_ref().unwrap()),
             self.data_source.as_ref().unwrap().clone(),
-        )
+        );
+        exchange.participation_limit = self.participation_limit;
+        exchange.slippage = self.slippage.clone();
+        exchange.fill_policy = self.fill_policy;
+        exchange.instruments = self.instruments.clone();
+        exchange
+    }
+
+    //Once instruments have been added, only symbols with an instrument can be traded
+    pub fn with_instruments(&mut self, instruments: Vec<Instrument>) -> &mut Self {
+        for instrument in instruments {
+            self.instruments.insert(instrument);
+        }
+        self
+    }
+
+    pub fn with_fill_policy(&mut self, fill_policy: FillPolicy) -> &mut Self {
+        self.fill_policy = fill_policy;
+        self
+    }
+
+    //Without a model, orders execute at the quoted bid or ask
+    pub fn with_slippage(&mut self, slippage: impl SlippageModel + 'static) -> &mut Self {
+        self.slippage = Some(Rc::new(slippage));
+        self
+    }
+
+    //Limits the shares traded in each symbol on every tick to a fraction of the volume on the
+    //quote. Orders that are larger than the limit are filled partially over several ticks.
+    pub fn with_participation_limit(&mut self, participation_limit: f64) -> &mut Self {
+        if participation_limit <= 0.0 || participation_limit > 1.0 {
+            panic!("Participation limit must be greater than zero and no greater than one");
+        }
+        self.participation_limit = Some(participation_limit);
+        self
     }
 
     pub fn with_clock(&mut self, clock: Clock) -> &mut Self {
@@ -91,6 +178,10 @@ impl<D: DataSource> DefaultExchangeBuilder<D> {
         Self {
             clock: None,
             data_source: None,
+            participation_limit: None,
+            slippage: None,
+            fill_policy: FillPolicy::default(),
+            instruments: InstrumentRegistry::new(),
         }
     }
 }
@@ -113,16 +204,46 @@ impl<D: DataSource> Default for DefaultExchangeBuilder<D> {
 ///
 ///In both cases, we are potentially creating silent errors but this more closely represents the
 ///execution model that would exist in reality.
+///
+///If the exchange is built with a participation limit, the shares traded in each symbol on a tick
+///are capped at that fraction of the volume on the quote. Orders are filled in the order that they
+///were inserted, and any unfilled quantity stays in the book until a later tick. Quotes without
+///volume are not limited.
+///
+///Trades execute at the quoted bid or ask unless the exchange is built with a [SlippageModel].
+///The price that triggered limit and stop orders execute at is set by the [FillPolicy].
+///
+///When an order in a one-cancels-other group executes, the other orders in the group are cancelled
+///on the same tick. If several orders in a group trigger on the same tick, only the oldest order
+///executes.
+///
+///Orders expire according to their [TimeInForce]. Expiry is checked before execution so an order
+///that is [TimeInForce::GoodTillDate] can execute on the expiry date but not after. Orders for an
+///[Instrument] that has been delisted expire on the delisting date.
+///
+///The value of trades is multiplied by the multiplier of the [Instrument], if there is one.
 #[derive(Clone, Debug)]
 pub struct DefaultExchange<D: DataSource> {
     clock: Clock,
-    orderbook: HashMap<DefaultExchangeOrderId, Order>,
-    last: DefaultExchangeOrderId,
+    orderbook: HashMap<OrderId, Order>,
+    last: OrderId,
     data_source: D,
     trade_log: Vec<Trade>,
     trade_buffer: Vec<Trade>,
     ready_state: DefaultExchangeState,
     last_seen_quote: HashMap<String, Quote>,
+    participation_limit: Option<f64>,
+    slippage: Option<Rc<dyn SlippageModel>>,
+    fill_policy: FillPolicy,
+    instruments: InstrumentRegistry,
+    order_status: HashMap<OrderId, OrderStatus>,
+    //Date of the first check that an order was in the book for, used to expire day orders
+    active_since: HashMap<OrderId, DateTime>,
+    expired_buffer: Vec<OrderExpiry>,
+    //Maps every order in a one-cancels-other group to the other orders in the group
+    order_groups: HashMap<OrderId, Vec<OrderId>>,
+    //Exits waiting for the entry order, keyed by the id of the entry
+    pending_brackets: HashMap<OrderId, Vec<Order>>,
 }
 
 impl<D: DataSource> DefaultExchange<D> {
@@ -140,6 +261,213 @@ impl<D: DataSource> DefaultExchange<D> {
             //Exchange is empty, so it must be ready to accept new orders.
             ready_state: DefaultExchangeState::Ready,
             last_seen_quote: HashMap::new(),
+            participation_limit: None,
+            slippage: None,
+            fill_policy: FillPolicy::default(),
+            instruments: InstrumentRegistry::new(),
+            order_status: HashMap::new(),
+            active_since: HashMap::new(),
+            expired_buffer: Vec::new(),
+            order_groups: HashMap::new(),
+            pending_brackets: HashMap::new(),
+        }
+    }
+
+    //Returns the quote that the order executes against, or None if the order doesn't execute on
+    //this tick. Execution against a bar or at the order price is represented by a quote with no
+    //spread at the fill price.
+    //
+    //Market orders execute against the quote, or the open of the bar if there is no quote. Orders
+    //that execute at the open or close need a bar. Limit and stop orders are checked against the
+    //range of the bar when there is one, and then priced according to the [FillPolicy]. Trailing
+    //stops can only execute against quotes.
+    fn get_fill(&self, order: &Order, quote: Option<&Quote>, bar: Option<&Bar>) -> Option<Quote> {
+        let order_type = order.get_order_type();
+        match order_type {
+            OrderType::MarketBuy | OrderType::MarketSell => {
+                return match (quote, bar) {
+                    (Some(quote), _) => Some(quote.clone()),
+                    (None, Some(bar)) => Some(bar.quote_at(&bar.open)),
+                    _ => None,
+                }
+            }
+            OrderType::MarketOnOpenBuy | OrderType::MarketOnOpenSell => {
+                return bar.map(|bar| bar.quote_at(&bar.open))
+            }
+            OrderType::MarketOnCloseBuy | OrderType::MarketOnCloseSell => {
+                return bar.map(|bar| bar.quote_at(&bar.close))
+            }
+            _ => (),
+        }
+
+        //Trailing stops have no price until they have seen a quote
+        let order_price = order.get_price().as_ref()?;
+        let is_buy = order_type.is_buy();
+        let is_limit = matches!(order_type, OrderType::LimitBuy | OrderType::LimitSell);
+        let is_trailing = matches!(
+            order_type,
+            OrderType::TrailingStopBuy | OrderType::TrailingStopSell
+        );
+        //Limit buys and stop sells trigger when the price falls to the order price, limit sells
+        //and stop buys when the price rises to it
+        let triggers_below = is_limit == is_buy;
+
+        if let (Some(bar), false) = (bar, is_trailing) {
+            let (triggered, gapped) = if triggers_below {
+                (bar.low <= *order_price, bar.open < *order_price)
+            } else {
+                (bar.high >= *order_price, bar.open > *order_price)
+            };
+            if !triggered {
+                return None;
+            }
+            let price = match self.fill_policy {
+                FillPolicy::OrderPrice => order_price,
+                FillPolicy::Quote if gapped => &bar.open,
+                FillPolicy::GapAware if gapped && !is_limit => &bar.open,
+                _ => order_price,
+            };
+            return Some(bar.quote_at(price));
+        }
+
+        let quote = quote?;
+        let market = if is_buy { &quote.ask } else { &quote.bid };
+        let triggered = if triggers_below {
+            *market <= *order_price
+        } else {
+            *market >= *order_price
+        };
+        if !triggered {
+            return None;
+        }
+        //A triggered stop is already at or through the stop price, so the quote is the worse of
+        //the two
+        let at_order_price = match self.fill_policy {
+            FillPolicy::Quote => false,
+            FillPolicy::OrderPrice => true,
+            FillPolicy::GapAware => is_limit,
+        };
+        if at_order_price {
+            let mut fill = quote.clone();
+            fill.bid = order_price.clone();
+            fill.ask = order_price.clone();
+            return Some(fill);
+        }
+        Some(quote.clone())
+    }
+
+    //Stop price is set from the first quote seen, and then ratchets towards the price as the price
+    //moves in the client's favour. The stop never moves back. Returns None if the stop is
+    //unchanged.
+    fn get_trailing_stop(order: &Order, quote: Option<&Quote>) -> Option<Price> {
+        let quote = quote?;
+        let (stop, moved) = match order.get_order_type() {
+            OrderType::TrailingStopBuy => {
+                //Unwrap is safe because TrailingStopBuy will always have a trail
+                let stop = order
+                    .get_trail()
+                    .as_ref()
+                    .unwrap()
+                    .stop_price(&quote.ask, true);
+                let moved = match order.get_price() {
+                    Some(order_price) => stop < *order_price,
+                    None => true,
+                };
+                (stop, moved)
+            }
+            OrderType::TrailingStopSell => {
+                //Unwrap is safe because TrailingStopSell will always have a trail
+                let stop = order
+                    .get_trail()
+                    .as_ref()
+                    .unwrap()
+                    .stop_price(&quote.bid, false);
+                let moved = match order.get_price() {
+                    Some(order_price) => stop > *order_price,
+                    None => true,
+                };
+                (stop, moved)
+            }
+            _ => return None,
+        };
+        if moved {
+            Some(stop)
+        } else {
+            None
+        }
+    }
+
+    fn add_order(&mut self, order: Order) -> OrderId {
+        self.last += 1;
+        self.orderbook.insert(self.last, order);
+        self.order_status.insert(self.last, OrderStatus::Pending);
+        self.last
+    }
+
+    fn add_oco_group(&mut self, orders: Vec<Order>) -> Vec<OrderId> {
+        let order_ids: Vec<OrderId> = orders
+            .into_iter()
+            .map(|order| self.add_order(order))
+            .collect();
+        for order_id in &order_ids {
+            let siblings = order_ids
+                .iter()
+                .filter(|sibling| *sibling != order_id)
+                .cloned()
+                .collect();
+            self.order_groups.insert(*order_id, siblings);
+        }
+        order_ids
+    }
+
+    //Exits are sized to the quantity of the entry that executed
+    fn activate_bracket(&mut self, entry_id: OrderId, filled: &PortfolioQty) {
+        if let Some(mut exits) = self.pending_brackets.remove(&entry_id) {
+            for exit in exits.iter_mut() {
+                exit.set_shares(filled.clone());
+            }
+            self.add_oco_group(exits);
+        }
+    }
+
+    //Removes an order without updating status
+    fn remove_order(&mut self, order_id: &OrderId) -> Option<Order> {
+        if let Some(siblings) = self.order_groups.remove(order_id) {
+            for sibling in siblings {
+                if let Some(group) = self.order_groups.get_mut(&sibling) {
+                    group.retain(|member| member != order_id);
+                }
+            }
+        }
+        self.orderbook.remove(order_id)
+    }
+
+    fn get_execution_price(
+        &self,
+        quote: &Quote,
+        shares: &PortfolioQty,
+        trade_type: &TradeType,
+    ) -> Price {
+        if let Some(slippage) = &self.slippage {
+            return slippage.execution_price(quote, shares, trade_type);
+        }
+        match trade_type {
+            TradeType::Buy => quote.ask.clone(),
+            TradeType::Sell => quote.bid.clone(),
+        }
+    }
+
+    fn get_multiplier(&self, symbol: &str) -> f64 {
+        self.instruments
+            .get(symbol)
+            .map_or(1.0, |instrument| instrument.multiplier)
+    }
+
+    //Returns None if there is no limit on the quantity that can trade
+    fn get_available_volume(&self, volume: Option<f64>) -> Option<f64> {
+        match (self.participation_limit, volume) {
+            (Some(limit), Some(volume)) => Some(volume * limit),
+            _ => None,
         }
     }
 }
@@ -160,6 +488,57 @@ impl<D: DataSource> Exchange for DefaultExchange<D> {
         self.data_source.get_quotes()
     }
 
+    fn now(&self) -> DateTime {
+        self.clock.borrow().now()
+    }
+
+    fn get_instrument(&self, symbol: &str) -> Option<&Instrument> {
+        self.instruments.get(symbol)
+    }
+
+    fn is_tradable(&self, symbol: &str) -> bool {
+        self.instruments.is_tradable(symbol, &self.now())
+    }
+
+    fn apply_split(&mut self, split: &Split) {
+        let instrument = self.instruments.get(&split.symbol);
+        let mut cancelled_keys = Vec::new();
+        for (key, order) in self.orderbook.iter_mut() {
+            if !order.get_symbol().eq(&split.symbol) {
+                continue;
+            }
+            order.apply_split(split.ratio);
+            let shares = match instrument {
+                Some(instrument) => instrument.round_quantity(**order.get_shares()),
+                None => order.get_shares().floor(),
+            };
+            if shares == 0.0 {
+                cancelled_keys.push(*key);
+            } else {
+                order.set_shares(shares);
+            }
+            if let Some(OrderStatus::PartiallyFilled(filled)) = self.order_status.get_mut(key) {
+                *filled = PortfolioQty::from(**filled * split.ratio);
+            }
+        }
+        //Exits are sized when the entry executes so only the price needs to change
+        for exits in self.pending_brackets.values_mut() {
+            for exit in exits.iter_mut() {
+                if exit.get_symbol().eq(&split.symbol) {
+                    exit.apply_split(split.ratio);
+                }
+            }
+        }
+        for key in cancelled_keys {
+            self.delete_order(key);
+        }
+        if let Some(quote) = self.last_seen_quote.get_mut(&split.symbol) {
+            quote.bid = Price::from(*quote.bid / split.ratio);
+            quote.ask = Price::from(*quote.ask / split.ratio);
+            quote.volume = quote.volume.map(|volume| volume * split.ratio);
+        }
+    }
+
     fn flush_buffer(&mut self) -> Vec<Trade> {
         match self.ready_state {
             DefaultExchangeState::Ready => {
@@ -175,6 +554,15 @@ impl<D: DataSource> Exchange for DefaultExchange<D> {
         }
     }
 
+    fn flush_expired(&mut self) -> Vec<OrderExpiry> {
+        match self.ready_state {
+            DefaultExchangeState::Ready => std::mem::take(&mut self.expired_buffer),
+            DefaultExchangeState::Waiting => {
+                panic!("called flush_expired without first calling check");
+            }
+        }
+    }
+
     fn finish(&mut self) {
         self.ready_state = DefaultExchangeState::Waiting;
     }
@@ -183,10 +571,14 @@ impl<D: DataSource> Exchange for DefaultExchange<D> {
         self.orderbook.keys().len()
     }
 
-    fn get_order(&self, order_id: &DefaultExchangeOrderId) -> Option<&Order> {
+    fn get_order(&self, order_id: &OrderId) -> Option<&Order> {
         self.orderbook.get(order_id)
     }
 
+    fn get_order_status(&self, order_id: &OrderId) -> Option<&OrderStatus> {
+        self.order_status.get(order_id)
+    }
+
     fn get_trade_log(&self) -> Vec<Trade> {
         self.trade_log.clone()
     }
@@ -195,88 +587,203 @@ impl<D: DataSource> Exchange for DefaultExchange<D> {
         //orderbook only contains orders that are pending, once an order has been executed it is
         //removed from the orderbook so we can just check all orders here
         let mut executed_trades: Vec<Trade> = Vec::new();
-        let mut removed_keys: Vec<DefaultExchangeOrderId> = Vec::new();
+        let mut removed_keys: Vec<OrderId> = Vec::new();
+        let mut partial_fills: Vec<(OrderId, PortfolioQty, PortfolioQty)> = Vec::new();
+        //Volume that can still be traded in each symbol on this tick, only populated when a limit
+        //applies
+        let mut available_volume: HashMap<String, f64> = HashMap::new();
+        let mut expired_keys: Vec<OrderId> = Vec::new();
+        let mut trailing_stops: Vec<(OrderId, Price)> = Vec::new();
+        let mut cancelled_keys: Vec<OrderId> = Vec::new();
+
+        let now = self.clock.borrow().now();
+        for key in self.orderbook.keys() {
+            self.active_since.entry(*key).or_insert_with(|| now.clone());
+        }
 
-        let execute_buy = |quote: &Quote, order: &Order| -> Trade {
-            let trade_price = &quote.ask;
-            let value = CashValue::from(**trade_price * **order.get_shares());
+        let execute_buy = |quote: &Quote, shares: &PortfolioQty| -> Trade {
+            let trade_price = self.get_execution_price(quote, shares, &TradeType::Buy);
+            let value =
+                CashValue::from(*trade_price * **shares * self.get_multiplier(&quote.symbol));
             let date = self.clock.borrow().now();
-            Trade::new(
-                order.get_symbol(),
+            Trade::new_with_currency(
+                &quote.symbol,
                 value,
-                *order.get_shares().clone(),
+                **shares,
                 date,
                 TradeType::Buy,
+                quote.currency,
             )
         };
 
-        let execute_sell = |quote: &Quote, order: &Order| -> Trade {
-            let trade_price = &quote.bid;
-            let value = CashValue::from(**trade_price * **order.get_shares());
+        let execute_sell = |quote: &Quote, shares: &PortfolioQty| -> Trade {
+            let trade_price = self.get_execution_price(quote, shares, &TradeType::Sell);
+            let value =
+                CashValue::from(*trade_price * **shares * self.get_multiplier(&quote.symbol));
             let date = self.clock.borrow().now();
-            Trade::new(
-                order.get_symbol(),
+            Trade::new_with_currency(
+                &quote.symbol,
                 value,
-                *order.get_shares().clone(),
+                **shares,
                 date,
                 TradeType::Sell,
+                quote.currency,
             )
         };
 
-        for (key, order) in &self.orderbook {
+        //Ids are assigned sequentially so sorting gives priority to the oldest orders when volume
+        //is limited
+        let mut keys: Vec<&OrderId> = self.orderbook.keys().collect();
+        keys.sort();
+
+        for key in keys {
+            //Another order in the group has executed on this tick
+            if cancelled_keys.contains(key) {
+                continue;
+            }
+            let order = self.orderbook.get(key).unwrap();
             let security_id = order.get_symbol();
-            if let Some(quote) = self.data_source.get_quote(&security_id) {
-                let result = match order.get_order_type() {
-                    OrderType::MarketBuy => Some(execute_buy(&quote, order)),
-                    OrderType::MarketSell => Some(execute_sell(&quote, order)),
-                    OrderType::LimitBuy => {
-                        //Unwrap is safe because LimitBuy will always have a price
-                        let order_price = order.get_price().as_ref().unwrap();
-                        if *order_price < quote.ask {
-                            Some(execute_buy(&quote, order))
-                        } else {
-                            None
-                        }
-                    }
-                    OrderType::LimitSell => {
-                        //Unwrap is safe because LimitSell will always have a price
-                        let order_price = order.get_price().as_ref().unwrap();
-                        if *order_price > quote.bid {
-                            Some(execute_sell(&quote, order))
-                        } else {
-                            None
-                        }
-                    }
-                    OrderType::StopBuy => {
-                        //Unwrap is safe because StopBuy will always have a price
-                        let order_price = order.get_price().as_ref().unwrap();
-                        if quote.ask > *order_price {
-                            Some(execute_buy(&quote, order))
-                        } else {
-                            None
-                        }
-                    }
-                    OrderType::StopSell => {
-                        //Unwrap is safe because StopSell will always have a price
-                        let order_price = order.get_price().as_ref().unwrap();
-                        if quote.bid < *order_price {
-                            Some(execute_sell(&quote, order))
-                        } else {
-                            None
-                        }
-                    }
+            let is_immediate = matches!(
+                order.get_time_in_force(),
+                TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill
+            );
+            let has_expired = match order.get_time_in_force() {
+                TimeInForce::GoodTillDate(expiry) => now > *expiry,
+                //Unwrap is safe because every order in the book was given a date above
+                TimeInForce::Day => !self.active_since.get(key).unwrap().is_same_day(&now),
+                _ => false,
+            } || self
+                .instruments
+                .get(security_id)
+                .is_some_and(|instrument| instrument.is_delisted(&now));
+            if has_expired {
+                expired_keys.push(*key);
+                continue;
+            }
+
+            let quote = self.data_source.get_quote(security_id);
+            let bar = self.data_source.get_bar(security_id);
+            if quote.is_none() && bar.is_none() {
+                if is_immediate {
+                    expired_keys.push(*key);
+                }
+                continue;
+            }
+
+            //Stop moves with the price even if the order can't be filled on this tick
+            if let Some(stop) = Self::get_trailing_stop(order, quote) {
+                trailing_stops.push((*key, stop));
+            }
+
+            let mut shares = order.get_shares().clone();
+            let volume = quote
+                .and_then(|quote| quote.volume)
+                .or_else(|| bar.and_then(|bar| bar.volume));
+            if let Some(volume) = self.get_available_volume(volume) {
+                let remaining = available_volume
+                    .entry(security_id.clone())
+                    .or_insert(volume);
+                if *shares > *remaining {
+                    //Volume limit isn't a tradable quantity so is rounded down to the lot size
+                    let capped = remaining.max(0.0);
+                    shares = PortfolioQty::from(match self.instruments.get(security_id) {
+                        Some(instrument) => instrument.round_quantity(capped),
+                        None => capped.floor(),
+                    });
+                }
+            }
+            let can_fill = match order.get_time_in_force() {
+                TimeInForce::FillOrKill => shares == *order.get_shares(),
+                _ => *shares > 0.0,
+            };
+            if !can_fill {
+                if is_immediate {
+                    expired_keys.push(*key);
+                }
+                continue;
+            }
+
+            let result = self.get_fill(order, quote, bar).map(|fill| {
+                let mut trade = if order.get_order_type().is_buy() {
+                    execute_buy(&fill, &shares)
+                } else {
+                    execute_sell(&fill, &shares)
                 };
+                trade.order_id = Some(*key);
+                trade
+            });
 
-                if let Some(trade) = result {
-                    executed_trades.push(trade);
+            if let Some(trade) = result {
+                if let Some(siblings) = self.order_groups.get(key) {
+                    cancelled_keys.extend(siblings);
+                }
+                if let Some(remaining) = available_volume.get_mut(security_id) {
+                    *remaining -= *shares;
+                }
+                if shares < *order.get_shares() {
+                    let unfilled = **order.get_shares() - *shares;
+                    partial_fills.push((*key, shares.clone(), PortfolioQty::from(unfilled)));
+                    //Unfilled quantity of an immediate order cannot wait for the next tick
+                    if is_immediate {
+                        expired_keys.push(*key);
+                    }
+                } else {
                     removed_keys.push(*key);
                 }
+                executed_trades.push(trade);
+            } else if is_immediate {
+                expired_keys.push(*key);
             }
         }
 
+        for (key, stop) in trailing_stops {
+            if let Some(order) = self.orderbook.get_mut(&key) {
+                order.set_price(stop);
+            }
+        }
+        for key in cancelled_keys {
+            if self.remove_order(&key).is_some() {
+                self.order_status.insert(key, OrderStatus::Cancelled);
+            }
+        }
         for key in removed_keys {
-            self.delete_order(key)
+            if let Some(order) = self.remove_order(&key) {
+                //Order holds the quantity that was remaining before this fill
+                let filled = match self.order_status.get(&key) {
+                    Some(OrderStatus::PartiallyFilled(prev)) => **prev + **order.get_shares(),
+                    _ => **order.get_shares(),
+                };
+                self.order_status.insert(key, OrderStatus::Filled);
+                self.activate_bracket(key, &filled.into());
+            }
         }
+        for (key, filled, unfilled) in partial_fills {
+            if let Some(order) = self.orderbook.get_mut(&key) {
+                order.set_shares(unfilled);
+            }
+            let total_filled = match self.order_status.get(&key) {
+                Some(OrderStatus::PartiallyFilled(prev)) => **prev + *filled,
+                _ => *filled,
+            };
+            self.order_status
+                .insert(key, OrderStatus::PartiallyFilled(total_filled.into()));
+        }
+        for key in expired_keys {
+            if let Some(order) = self.remove_order(&key) {
+                //Position was opened in part so the exits are still needed
+                if let Some(OrderStatus::PartiallyFilled(filled)) = self.order_status.get(&key) {
+                    let filled = filled.clone();
+                    self.activate_bracket(key, &filled);
+                }
+                self.pending_brackets.remove(&key);
+                self.order_status.insert(key, OrderStatus::Expired);
+                self.expired_buffer
+                    .push(OrderExpiry::new(key, order, now.clone()));
+            }
+        }
+        let orderbook = &self.orderbook;
+        self.active_since
+            .retain(|key, _| orderbook.contains_key(key));
         self.trade_log.extend(executed_trades.clone());
         //Update the buffer, which is flushed to broker, and the log which is held per-simulation
         self.trade_buffer.extend(executed_trades);
@@ -285,6 +792,13 @@ impl<D: DataSource> Exchange for DefaultExchange<D> {
         //Updating last_seen_quote with all the quotes seen on this date, potentially quite a
         //costly operation on every tick but this guarantees that missing prices don't cause a
         //panic that stops the simulation.
+        //Bars are valued at the close, quotes on the same date take priority
+        if let Some(bars) = self.data_source.get_bars() {
+            for bar in bars {
+                self.last_seen_quote
+                    .insert(bar.symbol.clone(), bar.quote_at(&bar.close));
+            }
+        }
         if let Some(quotes) = self.data_source.get_quotes() {
             for quote in quotes {
                 self.last_seen_quote
@@ -293,17 +807,35 @@ impl<D: DataSource> Exchange for DefaultExchange<D> {
         }
     }
 
-    fn delete_order(&mut self, order_id: DefaultExchangeOrderId) {
-        self.orderbook.remove(&order_id);
+    fn delete_order(&mut self, order_id: OrderId) {
+        if self.remove_order(&order_id).is_some() {
+            if let Some(OrderStatus::PartiallyFilled(filled)) = self.order_status.get(&order_id) {
+                let filled = filled.clone();
+                self.activate_bracket(order_id, &filled);
+            }
+            self.pending_brackets.remove(&order_id);
+            self.order_status.insert(order_id, OrderStatus::Cancelled);
+        }
     }
 
-    fn insert_order(&mut self, order: Order) -> DefaultExchangeOrderId {
+    fn modify_order(&mut self, order_id: OrderId, order: Order) -> bool {
         match self.ready_state {
             DefaultExchangeState::Ready => {
-                self.last += 1;
-                self.orderbook.insert(self.last, order);
-                self.last
+                if let Some(existing) = self.orderbook.get_mut(&order_id) {
+                    *existing = order;
+                    return true;
+                }
+                false
+            }
+            DefaultExchangeState::Waiting => {
+                panic!("called modify_order without first calling check");
             }
+        }
+    }
+
+    fn insert_order(&mut self, order: Order) -> OrderId {
+        match self.ready_state {
+            DefaultExchangeState::Ready => self.add_order(order),
             DefaultExchangeState::Waiting => {
                 //We panic here because if this happens then it is impossible for the simulation to
                 //continue and there is an error in the broker code
@@ -312,18 +844,66 @@ impl<D: DataSource> Exchange for DefaultExchange<D> {
         }
     }
 
+    fn insert_oco_group(&mut self, orders: Vec<Order>) -> Vec<OrderId> {
+        match self.ready_state {
+            DefaultExchangeState::Ready => self.add_oco_group(orders),
+            DefaultExchangeState::Waiting => {
+                panic!("called insert_oco_group without first calling check");
+            }
+        }
+    }
+
+    fn insert_bracket(&mut self, bracket: BracketOrder) -> OrderId {
+        match self.ready_state {
+            DefaultExchangeState::Ready => {
+                let entry_id = self.add_order(bracket.entry);
+                //Stop-loss is inserted first so it takes priority if both exits trigger on the
+                //same tick, this is the conservative assumption when the path of prices within
+                //the tick is unknown
+                self.pending_brackets
+                    .insert(entry_id, vec![bracket.stop_loss, bracket.take_profit]);
+                entry_id
+            }
+            DefaultExchangeState::Waiting => {
+                panic!("called insert_bracket without first calling check");
+            }
+        }
+    }
+
     fn clear(&mut self) {
+        for order_id in self.orderbook.keys() {
+            self.order_status.insert(*order_id, OrderStatus::Cancelled);
+        }
         self.orderbook = HashMap::new();
+        self.order_groups = HashMap::new();
+        self.pending_brackets = HashMap::new();
+    }
+
+    fn cancel_orders_by_symbol(&mut self, symbol: &str) {
+        //Cancelling a partially filled bracket entry activates the exits, so this repeats until
+        //the exits have also been cancelled
+        loop {
+            let to_remove: Vec<OrderId> = self
+                .orderbook
+                .iter()
+                .filter(|(_key, order)| order.get_symbol() == symbol)
+                .map(|(key, _order)| *key)
+                .collect();
+            if to_remove.is_empty() {
+                break;
+            }
+            for key in to_remove {
+                self.delete_order(key);
+            }
+        }
     }
 
     fn clear_pending_market_orders_by_symbol(&mut self, symbol: &str) {
         let mut to_remove = Vec::new();
         for (key, order) in self.orderbook.iter() {
             match order.get_order_type() {
-                OrderType::MarketBuy | OrderType::MarketSell => {
-                    if order.get_symbol() == symbol {
-                        to_remove.push(*key);
-                    }
+                OrderType::MarketBuy | OrderType::MarketSell if order.get_symbol() == symbol => {
+                    to_remove.push(*key);
                 }
                 _ => {}
             }
@@ -338,11 +918,15 @@ impl<D: DataSource> Exchange for DefaultExchange<D> {
 mod tests {
     use std::{collections::HashMap, rc::Rc};
 
-    use super::{DefaultExchange, DefaultExchangeBuilder};
-    use crate::broker::{Order, OrderType, Quote};
+    use super::slippage::SpreadProportional;
+    use super::{DefaultExchange, DefaultExchangeBuilder, FillPolicy};
+    use crate::broker::{
+        AssetClass, Bar, BracketOrder, Instrument, Order, OrderStatus, OrderType, Quote, Split,
+        TimeInForce, TradeType, Trail,
+    };
     use crate::clock::{Clock, ClockBuilder};
     use crate::exchange::Exchange;
-    use crate::input::{HashMapInput, HashMapInputBuilder, QuotesHashMap};
+    use crate::input::{BarsHashMap, HashMapInput, HashMapInputBuilder, QuotesHashMap};
     use crate::types::DateTime;
 
     fn setup() -> (DefaultExchange<HashMapInput>, Clock) {
@@ -542,9 +1126,9 @@ mod tests {
         let order0 = Order::delayed(OrderType::LimitBuy, "ABC", 100.0, 105.0);
         let (mut exchange, clock) = setup();
 
-        let order_id = exchange.insert_order(order);
+        let order_id = exchange.insert_order(order0);
         exchange.delete_order(order_id);
-        exchange.insert_order(order0);
+        exchange.insert_order(order);
         exchange.finish();
 
         clock.borrow_mut().tick();
@@ -552,6 +1136,7 @@ mod tests {
 
         println!("{:?}", exchange.get_trade_log());
         assert_eq!(exchange.orderbook_size(), 1);
+        assert_eq!(exchange.get_trade_log().len(), 0);
     }
 
     #[test]
@@ -615,4 +1200,881 @@ mod tests {
         assert_eq!(exchange.get_trade_log().len(), 1);
         assert_eq!(exchange.orderbook_size(), 0);
     }
+
+    fn setup_volume() -> (DefaultExchange<HashMapInput>, Clock) {
+        let mut quotes: QuotesHashMap = HashMap::new();
+        for date in 100..104 {
+            quotes.insert(
+                DateTime::from(date),
+                vec![Quote::new_with_volume(101.00, 102.00, date, "ABC", 1000.0)],
+            );
+        }
+
+        let clock = ClockBuilder::with_length_in_seconds(100, 4)
+            .with_frequency(&crate::types::Frequency::Second)
+            .build();
+
+        let source = HashMapInputBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_quotes(quotes)
+            .build();
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source)
+            .with_participation_limit(0.1)
+            .build();
+
+        (exchange, Rc::clone(&clock))
+    }
+
+    #[test]
+    fn test_that_order_larger_than_participation_limit_fills_partially() {
+        let (mut exchange, clock) = setup_volume();
+
+        exchange.insert_order(Order::market(OrderType::MarketBuy, "ABC", 250.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        let trades = exchange.flush_buffer();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(*trades.first().unwrap().quantity, 100.0);
+        assert_eq!(exchange.orderbook_size(), 1);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        //Remainder of 50 shares fills on the last tick and the order leaves the book
+        let trades = exchange.get_trade_log();
+        assert_eq!(trades.len(), 3);
+        assert_eq!(*trades.last().unwrap().quantity, 50.0);
+        assert_eq!(exchange.orderbook_size(), 0);
+    }
+
+    #[test]
+    fn test_that_participation_limit_is_shared_between_orders_in_same_symbol() {
+        let (mut exchange, clock) = setup_volume();
+
+        let first = exchange.insert_order(Order::market(OrderType::MarketBuy, "ABC", 80.0));
+        let second = exchange.insert_order(Order::market(OrderType::MarketSell, "ABC", 80.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        //Oldest order fills in full, newer order gets what remains of the limit
+        assert!(exchange.get_order(&first).is_none());
+        assert_eq!(**exchange.get_order(&second).unwrap().get_shares(), 60.0);
+        let trades = exchange.get_trade_log();
+        assert_eq!(trades.len(), 2);
+    }
+
+    #[test]
+    fn test_that_quote_without_volume_is_not_limited() {
+        let (mut exchange, clock) = setup();
+        exchange.participation_limit = Some(0.1);
+
+        exchange.insert_order(Order::market(OrderType::MarketBuy, "ABC", 10_000.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        assert_eq!(exchange.get_trade_log().len(), 1);
+        assert_eq!(exchange.orderbook_size(), 0);
+    }
+
+    #[test]
+    fn test_that_participation_limit_is_rounded_to_lot_size() {
+        let clock = ClockBuilder::with_length_in_seconds(100, 2)
+            .with_frequency(&crate::types::Frequency::Second)
+            .build();
+        let mut quotes: QuotesHashMap = HashMap::new();
+        for date in 100..102 {
+            quotes.insert(
+                DateTime::from(date),
+                vec![Quote::new_with_volume(101.00, 102.00, date, "BTC", 3.2)],
+            );
+        }
+        let source = HashMapInputBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_quotes(quotes)
+            .build();
+        let mut exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source)
+            .with_participation_limit(0.1)
+            .with_instruments(vec![Instrument::new_fractional("BTC", 0.1)])
+            .build();
+
+        exchange.insert_order(Order::market(OrderType::MarketBuy, "BTC", 1.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        //Limit of 0.32 is rounded down to 0.3 rather than to zero
+        let trades = exchange.get_trade_log();
+        assert_eq!(trades.len(), 1);
+        assert!((*trades.first().unwrap().quantity - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_that_slippage_model_changes_execution_price() {
+        let mut quotes: QuotesHashMap = HashMap::new();
+        quotes.insert(
+            DateTime::from(100),
+            vec![Quote::new(100.00, 102.00, 100, "ABC")],
+        );
+        quotes.insert(
+            DateTime::from(101),
+            vec![Quote::new(100.00, 102.00, 101, "ABC")],
+        );
+
+        let clock = ClockBuilder::with_length_in_seconds(100, 2)
+            .with_frequency(&crate::types::Frequency::Second)
+            .build();
+
+        let source = HashMapInputBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_quotes(quotes)
+            .build();
+
+        let mut exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source)
+            .with_slippage(SpreadProportional::new(0.5))
+            .build();
+
+        exchange.insert_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        exchange.insert_order(Order::market(OrderType::MarketSell, "ABC", 10.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        let trades = exchange.get_trade_log();
+        let buy = trades
+            .iter()
+            .find(|t| matches!(t.typ, TradeType::Buy))
+            .unwrap();
+        let sell = trades
+            .iter()
+            .find(|t| matches!(t.typ, TradeType::Sell))
+            .unwrap();
+        assert_eq!(*buy.value, 1030.0);
+        assert_eq!(*sell.value, 990.0);
+    }
+
+    #[test]
+    fn test_that_order_status_tracks_partial_fills() {
+        let (mut exchange, clock) = setup_volume();
+
+        let order_id = exchange.insert_order(Order::market(OrderType::MarketBuy, "ABC", 150.0));
+        assert_eq!(
+            exchange.get_order_status(&order_id),
+            Some(&OrderStatus::Pending)
+        );
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            exchange.get_order_status(&order_id),
+            Some(&OrderStatus::PartiallyFilled(100.0.into()))
+        );
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            exchange.get_order_status(&order_id),
+            Some(&OrderStatus::Filled)
+        );
+    }
+
+    #[test]
+    fn test_that_good_till_date_order_expires_after_date() {
+        let (mut exchange, clock) = setup();
+
+        //Stop won't trigger at these prices
+        let mut order = Order::delayed(OrderType::StopBuy, "ABC", 100.0, 200.0);
+        order.set_time_in_force(TimeInForce::GoodTillDate(101.into()));
+        let order_id = exchange.insert_order(order);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.orderbook_size(), 1);
+        assert!(exchange.flush_expired().is_empty());
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.orderbook_size(), 0);
+        assert_eq!(
+            exchange.get_order_status(&order_id),
+            Some(&OrderStatus::Expired)
+        );
+        let expired = exchange.flush_expired();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired.first().unwrap().order_id, order_id);
+    }
+
+    #[test]
+    fn test_that_day_order_expires_on_next_day() {
+        let mut quotes: QuotesHashMap = HashMap::new();
+        let clock = ClockBuilder::with_length_in_days(0, 3)
+            .with_frequency(&crate::types::Frequency::Daily)
+            .build();
+        for date in clock.borrow().peek() {
+            quotes.insert(date.clone(), vec![Quote::new(101.00, 102.00, date, "ABC")]);
+        }
+
+        let source = HashMapInputBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_quotes(quotes)
+            .build();
+
+        let mut exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source)
+            .build();
+
+        let mut order = Order::delayed(OrderType::StopBuy, "ABC", 100.0, 200.0);
+        order.set_time_in_force(TimeInForce::Day);
+        let order_id = exchange.insert_order(order);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            exchange.get_order_status(&order_id),
+            Some(&OrderStatus::Pending)
+        );
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            exchange.get_order_status(&order_id),
+            Some(&OrderStatus::Expired)
+        );
+    }
+
+    #[test]
+    fn test_that_immediate_or_cancel_expires_unfilled_quantity() {
+        let (mut exchange, clock) = setup_volume();
+
+        let mut order = Order::market(OrderType::MarketBuy, "ABC", 150.0);
+        order.set_time_in_force(TimeInForce::ImmediateOrCancel);
+        let order_id = exchange.insert_order(order);
+        let mut order = Order::delayed(OrderType::StopBuy, "ABC", 100.0, 200.0);
+        order.set_time_in_force(TimeInForce::ImmediateOrCancel);
+        exchange.insert_order(order);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        assert_eq!(exchange.get_trade_log().len(), 1);
+        assert_eq!(*exchange.get_trade_log().first().unwrap().quantity, 100.0);
+        assert_eq!(
+            exchange.get_order_status(&order_id),
+            Some(&OrderStatus::Expired)
+        );
+        let expired = exchange.flush_expired();
+        assert_eq!(expired.len(), 2);
+        assert_eq!(exchange.orderbook_size(), 0);
+    }
+
+    #[test]
+    fn test_that_fill_or_kill_does_not_fill_partially() {
+        let (mut exchange, clock) = setup_volume();
+
+        let mut order = Order::market(OrderType::MarketBuy, "ABC", 150.0);
+        order.set_time_in_force(TimeInForce::FillOrKill);
+        let killed = exchange.insert_order(order);
+        let mut order = Order::market(OrderType::MarketBuy, "ABC", 50.0);
+        order.set_time_in_force(TimeInForce::FillOrKill);
+        let filled = exchange.insert_order(order);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        assert_eq!(
+            exchange.get_order_status(&killed),
+            Some(&OrderStatus::Expired)
+        );
+        assert_eq!(
+            exchange.get_order_status(&filled),
+            Some(&OrderStatus::Filled)
+        );
+        assert_eq!(exchange.get_trade_log().len(), 1);
+    }
+
+    fn setup_trend(bids: Vec<f64>) -> (DefaultExchange<HashMapInput>, Clock) {
+        setup_trend_with_volume(bids, None)
+    }
+
+    fn setup_trend_with_volume(
+        bids: Vec<f64>,
+        volume: Option<f64>,
+    ) -> (DefaultExchange<HashMapInput>, Clock) {
+        let mut quotes: QuotesHashMap = HashMap::new();
+        let clock = ClockBuilder::with_length_in_seconds(100, bids.len() as i64)
+            .with_frequency(&crate::types::Frequency::Second)
+            .build();
+        for (date, bid) in clock.borrow().peek().zip(bids) {
+            let mut quote = Quote::new(bid, bid + 1.0, date.clone(), "ABC");
+            quote.volume = volume;
+            quotes.insert(date, vec![quote]);
+        }
+
+        let source = HashMapInputBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_quotes(quotes)
+            .build();
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source)
+            .build();
+        (exchange, clock)
+    }
+
+    #[test]
+    fn test_that_trailing_stop_sell_ratchets_with_price() {
+        let (mut exchange, clock) = setup_trend(vec![100.0, 100.0, 110.0, 105.0, 99.0]);
+
+        let order = Order::trailing(
+            OrderType::TrailingStopSell,
+            "ABC",
+            100.0,
+            Trail::Absolute(10.0.into()),
+        );
+        let order_id = exchange.insert_order(order);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            **exchange
+                .get_order(&order_id)
+                .unwrap()
+                .get_price()
+                .as_ref()
+                .unwrap(),
+            90.0
+        );
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            **exchange
+                .get_order(&order_id)
+                .unwrap()
+                .get_price()
+                .as_ref()
+                .unwrap(),
+            100.0
+        );
+        exchange.finish();
+
+        //Price falls but stop doesn't move back and isn't triggered
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            **exchange
+                .get_order(&order_id)
+                .unwrap()
+                .get_price()
+                .as_ref()
+                .unwrap(),
+            100.0
+        );
+        assert_eq!(exchange.get_trade_log().len(), 0);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.get_trade_log().len(), 1);
+        assert_eq!(
+            exchange.get_order_status(&order_id),
+            Some(&OrderStatus::Filled)
+        );
+    }
+
+    #[test]
+    fn test_that_trailing_stop_ratchets_when_volume_is_capped() {
+        let (mut exchange, clock) = setup_trend_with_volume(vec![100.0, 100.0, 110.0], Some(5.0));
+        //Limit rounds down to zero shares so the order can't fill
+        exchange.participation_limit = Some(0.1);
+
+        let order = Order::trailing(
+            OrderType::TrailingStopSell,
+            "ABC",
+            100.0,
+            Trail::Absolute(10.0.into()),
+        );
+        let order_id = exchange.insert_order(order);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            **exchange
+                .get_order(&order_id)
+                .unwrap()
+                .get_price()
+                .as_ref()
+                .unwrap(),
+            100.0
+        );
+        assert_eq!(exchange.get_trade_log().len(), 0);
+    }
+
+    #[test]
+    fn test_that_trailing_stop_buy_with_percentage_trail_triggers() {
+        let (mut exchange, clock) = setup_trend(vec![100.0, 100.0, 80.0, 90.0]);
+
+        let order = Order::trailing(
+            OrderType::TrailingStopBuy,
+            "ABC",
+            100.0,
+            Trail::Percentage(0.1),
+        );
+        let order_id = exchange.insert_order(order);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        exchange.finish();
+
+        //Ask falls to 81 so the stop ratchets down to 89.1
+        clock.borrow_mut().tick();
+        exchange.check();
+        let stop = **exchange
+            .get_order(&order_id)
+            .unwrap()
+            .get_price()
+            .as_ref()
+            .unwrap();
+        assert!((stop - 89.1).abs() < 1e-9);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.get_trade_log().len(), 1);
+    }
+
+    #[test]
+    fn test_that_oco_group_cancels_sibling_when_one_order_executes() {
+        let (mut exchange, clock) = setup();
+
+        let order_ids = exchange.insert_oco_group(vec![
+            Order::delayed(OrderType::StopBuy, "ABC", 100.0, 105.0),
+            Order::delayed(OrderType::StopSell, "ABC", 100.0, 95.0),
+        ]);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.orderbook_size(), 2);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.get_trade_log().len(), 1);
+        assert_eq!(exchange.orderbook_size(), 0);
+        assert_eq!(
+            exchange.get_order_status(&order_ids[0]),
+            Some(&OrderStatus::Filled)
+        );
+        assert_eq!(
+            exchange.get_order_status(&order_ids[1]),
+            Some(&OrderStatus::Cancelled)
+        );
+    }
+
+    #[test]
+    fn test_that_oco_group_executes_one_order_when_both_trigger() {
+        let (mut exchange, clock) = setup();
+
+        exchange.insert_oco_group(vec![
+            Order::delayed(OrderType::StopBuy, "ABC", 100.0, 103.0),
+            Order::delayed(OrderType::StopBuy, "ABC", 100.0, 104.0),
+        ]);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.get_trade_log().len(), 1);
+        assert_eq!(exchange.orderbook_size(), 0);
+    }
+
+    #[test]
+    fn test_that_bracket_exits_are_inserted_after_entry_executes() {
+        let (mut exchange, clock) = setup_trend(vec![100.0, 100.0, 90.0]);
+
+        let bracket = BracketOrder::new(
+            Order::market(OrderType::MarketBuy, "ABC", 100.0),
+            110.0,
+            95.0,
+        );
+        let entry_id = exchange.insert_bracket(bracket);
+        assert_eq!(exchange.orderbook_size(), 1);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(
+            exchange.get_order_status(&entry_id),
+            Some(&OrderStatus::Filled)
+        );
+        assert_eq!(exchange.orderbook_size(), 2);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        let trades = exchange.get_trade_log();
+        assert_eq!(trades.len(), 2);
+        //Stop-loss executed at the bid and the take-profit was cancelled
+        assert!(matches!(trades.last().unwrap().typ, TradeType::Sell));
+        assert_eq!(*trades.last().unwrap().value, 9000.0);
+        assert_eq!(exchange.orderbook_size(), 0);
+    }
+
+    #[test]
+    fn test_that_cancelled_bracket_entry_does_not_insert_exits() {
+        let (mut exchange, clock) = setup();
+
+        let bracket = BracketOrder::new(
+            Order::delayed(OrderType::StopBuy, "ABC", 100.0, 200.0),
+            210.0,
+            190.0,
+        );
+        let entry_id = exchange.insert_bracket(bracket);
+        exchange.delete_order(entry_id);
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.orderbook_size(), 0);
+        assert_eq!(exchange.get_trade_log().len(), 0);
+    }
+
+    fn setup_bars() -> (DefaultExchange<HashMapInput>, Clock) {
+        let mut bars: BarsHashMap = HashMap::new();
+        bars.insert(
+            DateTime::from(100),
+            vec![Bar::new(100.0, 102.0, 99.0, 101.0, 100, "ABC")],
+        );
+        bars.insert(
+            DateTime::from(101),
+            vec![Bar::new(101.0, 104.0, 95.0, 103.0, 101, "ABC")],
+        );
+        //Gaps down through the range of the previous bar
+        bars.insert(
+            DateTime::from(102),
+            vec![Bar::new(90.0, 92.0, 88.0, 91.0, 102, "ABC")],
+        );
+
+        let clock = ClockBuilder::with_length_in_seconds(100, 3)
+            .with_frequency(&crate::types::Frequency::Second)
+            .build();
+
+        let source = HashMapInputBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_bars(bars)
+            .build();
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source)
+            .build();
+
+        (exchange, Rc::clone(&clock))
+    }
+
+    #[test]
+    fn test_that_market_on_open_and_close_fill_at_bar_prices() {
+        let (mut exchange, clock) = setup_bars();
+
+        let open = exchange.insert_order(Order::market(OrderType::MarketOnOpenBuy, "ABC", 10.0));
+        let close = exchange.insert_order(Order::market(OrderType::MarketOnCloseSell, "ABC", 10.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        assert_eq!(exchange.get_order_status(&open), Some(&OrderStatus::Filled));
+        assert_eq!(
+            exchange.get_order_status(&close),
+            Some(&OrderStatus::Filled)
+        );
+        let trades = exchange.get_trade_log();
+        let buy = trades
+            .iter()
+            .find(|t| matches!(t.typ, TradeType::Buy))
+            .unwrap();
+        let sell = trades
+            .iter()
+            .find(|t| matches!(t.typ, TradeType::Sell))
+            .unwrap();
+        assert_eq!(*buy.value, 1010.0);
+        assert_eq!(*sell.value, 1030.0);
+    }
+
+    #[test]
+    fn test_that_limit_and_stop_orders_are_checked_against_bar_range() {
+        let (mut exchange, clock) = setup_bars();
+
+        //Low of the bar is 95 so this fills at the limit
+        exchange.insert_order(Order::delayed(OrderType::LimitBuy, "ABC", 10.0, 96.0));
+        //High of the bar is 104 so this doesn't trigger
+        exchange.insert_order(Order::delayed(OrderType::StopBuy, "ABC", 10.0, 105.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        let trades = exchange.get_trade_log();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(*trades.first().unwrap().value, 960.0);
+        assert_eq!(exchange.orderbook_size(), 1);
+    }
+
+    #[test]
+    fn test_that_stop_fills_at_open_when_bar_gaps_through_stop() {
+        let (mut exchange, clock) = setup_bars();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        exchange.insert_order(Order::delayed(OrderType::StopSell, "ABC", 10.0, 94.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        let trades = exchange.get_trade_log();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(*trades.first().unwrap().value, 900.0);
+        //Bars are used to value positions when there is no quote
+        assert_eq!(*exchange.get_quote("ABC").unwrap().bid, 91.0);
+    }
+    //Price falls through every order on the second tick. Returns the fill price of each order,
+    //keyed by the number of shares in the order.
+    fn get_fill_prices(fill_policy: FillPolicy, with_bars: bool) -> HashMap<u32, f64> {
+        let clock = ClockBuilder::with_length_in_seconds(100, 2)
+            .with_frequency(&crate::types::Frequency::Second)
+            .build();
+
+        let mut source = HashMapInputBuilder::new();
+        source.with_clock(Rc::clone(&clock));
+        if with_bars {
+            let mut bars: BarsHashMap = HashMap::new();
+            bars.insert(
+                DateTime::from(100),
+                vec![Bar::new(101.0, 102.0, 100.0, 101.0, 100, "ABC")],
+            );
+            bars.insert(
+                DateTime::from(101),
+                vec![Bar::new(96.0, 97.0, 90.0, 92.0, 101, "ABC")],
+            );
+            source.with_bars(bars);
+        } else {
+            let mut quotes: QuotesHashMap = HashMap::new();
+            quotes.insert(
+                DateTime::from(100),
+                vec![Quote::new(101.00, 102.00, 100, "ABC")],
+            );
+            quotes.insert(
+                DateTime::from(101),
+                vec![Quote::new(95.00, 96.00, 101, "ABC")],
+            );
+            source.with_quotes(quotes);
+        }
+
+        let mut exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source.build())
+            .with_fill_policy(fill_policy)
+            .build();
+
+        exchange.check();
+        exchange.insert_order(Order::delayed(OrderType::LimitBuy, "ABC", 1.0, 98.0));
+        exchange.insert_order(Order::delayed(OrderType::StopSell, "ABC", 2.0, 99.0));
+        exchange.insert_order(Order::delayed(OrderType::LimitSell, "ABC", 3.0, 94.0));
+        exchange.insert_order(Order::delayed(OrderType::StopBuy, "ABC", 4.0, 90.0));
+        //Never triggered
+        exchange.insert_order(Order::delayed(OrderType::LimitBuy, "ABC", 5.0, 89.0));
+        exchange.insert_order(Order::delayed(OrderType::StopBuy, "ABC", 6.0, 110.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+        assert_eq!(exchange.orderbook_size(), 2);
+
+        exchange
+            .get_trade_log()
+            .iter()
+            .map(|trade| (*trade.quantity as u32, *trade.value / *trade.quantity))
+            .collect()
+    }
+
+    #[test]
+    fn test_that_quote_fill_policy_fills_at_quote() {
+        let prices = get_fill_prices(FillPolicy::Quote, false);
+        assert_eq!(prices.len(), 4);
+        //Limit buy gets a better price than the limit, stops execute through the stop price
+        assert_eq!(prices[&1], 96.0);
+        assert_eq!(prices[&2], 95.0);
+        assert_eq!(prices[&3], 95.0);
+        assert_eq!(prices[&4], 96.0);
+    }
+
+    #[test]
+    fn test_that_order_price_fill_policy_fills_at_order_price() {
+        let prices = get_fill_prices(FillPolicy::OrderPrice, false);
+        assert_eq!(prices.len(), 4);
+        assert_eq!(prices[&1], 98.0);
+        assert_eq!(prices[&2], 99.0);
+        assert_eq!(prices[&3], 94.0);
+        assert_eq!(prices[&4], 90.0);
+    }
+
+    #[test]
+    fn test_that_gap_aware_fill_policy_fills_stops_at_worse_price() {
+        let prices = get_fill_prices(FillPolicy::GapAware, false);
+        assert_eq!(prices.len(), 4);
+        assert_eq!(prices[&1], 98.0);
+        assert_eq!(prices[&2], 95.0);
+        assert_eq!(prices[&3], 94.0);
+        assert_eq!(prices[&4], 96.0);
+    }
+
+    #[test]
+    fn test_that_fill_policy_is_applied_to_bars() {
+        let prices = get_fill_prices(FillPolicy::Quote, true);
+        assert_eq!(prices.len(), 4);
+        assert_eq!(prices[&1], 96.0);
+        assert_eq!(prices[&2], 96.0);
+        assert_eq!(prices[&3], 96.0);
+        assert_eq!(prices[&4], 96.0);
+
+        let prices = get_fill_prices(FillPolicy::OrderPrice, true);
+        assert_eq!(prices[&1], 98.0);
+        assert_eq!(prices[&2], 99.0);
+        assert_eq!(prices[&3], 94.0);
+        assert_eq!(prices[&4], 90.0);
+
+        //Bar opened through every order
+        let prices = get_fill_prices(FillPolicy::GapAware, true);
+        assert_eq!(prices[&1], 98.0);
+        assert_eq!(prices[&2], 96.0);
+        assert_eq!(prices[&3], 94.0);
+        assert_eq!(prices[&4], 96.0);
+    }
+
+    #[test]
+    fn test_that_trade_value_includes_instrument_multiplier() {
+        let (_exchange, clock) = setup();
+        let mut future = Instrument::new("ABC");
+        future.asset_class = AssetClass::Future;
+        future.multiplier = 50.0;
+
+        let source = HashMapInputBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_quotes(
+                vec![(
+                    DateTime::from(101),
+                    vec![Quote::new(101.00, 102.00, 101, "ABC")],
+                )]
+                .into_iter()
+                .collect(),
+            )
+            .build();
+        let mut exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source)
+            .with_instruments(vec![future])
+            .build();
+
+        exchange.insert_order(Order::market(OrderType::MarketBuy, "ABC", 2.0));
+        exchange.finish();
+
+        clock.borrow_mut().tick();
+        exchange.check();
+
+        let trades = exchange.get_trade_log();
+        assert_eq!(*trades.first().unwrap().value, 10_200.0);
+        assert!(exchange.is_tradable("ABC"));
+        assert!(!exchange.is_tradable("BCD"));
+    }
+
+    #[test]
+    fn test_that_split_restates_orders_and_last_price() {
+        let clock = ClockBuilder::with_length_in_seconds(100, 2)
+            .with_frequency(&crate::types::Frequency::Second)
+            .build();
+        let mut quotes: QuotesHashMap = HashMap::new();
+        quotes.insert(
+            DateTime::from(100),
+            vec![Quote::new(101.00, 102.00, 100, "ABC")],
+        );
+        let source = HashMapInputBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_quotes(quotes)
+            .build();
+        let mut exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source)
+            .build();
+        exchange.check();
+        //Split takes effect on a date without a quote so the last seen price is used
+        clock.borrow_mut().tick();
+
+        let limit = exchange.insert_order(Order::delayed(OrderType::LimitSell, "ABC", 10.0, 200.0));
+        let trailing = exchange.insert_order(Order::trailing(
+            OrderType::TrailingStopSell,
+            "ABC",
+            10.0,
+            Trail::Absolute(10.0.into()),
+        ));
+        let small = exchange.insert_order(Order::delayed(OrderType::LimitSell, "ABC", 5.0, 200.0));
+
+        exchange.apply_split(&Split::new("ABC", 2.0, 101));
+        let order = exchange.get_order(&limit).unwrap();
+        assert_eq!(**order.get_shares(), 20.0);
+        assert_eq!(**order.get_price().as_ref().unwrap(), 100.0);
+        let order = exchange.get_order(&trailing).unwrap();
+        assert_eq!(order.get_trail(), &Some(Trail::Absolute(5.0.into())));
+        assert_eq!(*exchange.get_quote("ABC").unwrap().bid, 50.5);
+
+        //Order for 10 shares after the first split rounds down to zero
+        exchange.apply_split(&Split::new("ABC", 0.05, 101));
+        assert_eq!(**exchange.get_order(&limit).unwrap().get_shares(), 1.0);
+        assert!(matches!(
+            exchange.get_order_status(&small),
+            Some(OrderStatus::Cancelled)
+        ));
+    }
 }
@@ -0,0 +1,198 @@
+use std::fmt::Debug;
+
+use crate::broker::{Quote, TradeType};
+use crate::types::{PortfolioQty, Price};
+
+///Models the difference between the quoted price and the price that an order executes at.
+///
+///Implementations take the side of the market that the order trades against and return a price
+///that is no better than the quote: buys execute at or above the ask, sells at or below the bid.
+///Slippage is applied by [DefaultExchange](super::DefaultExchange) to every trade, so the
+///implementation will also see partial fills.
+pub trait SlippageModel: Debug {
+    fn execution_price(
+        &self,
+        quote: &Quote,
+        shares: &PortfolioQty,
+        trade_type: &TradeType,
+    ) -> Price;
+}
+
+fn adjust(quote: &Quote, trade_type: &TradeType, pct: f64) -> Price {
+    match trade_type {
+        TradeType::Buy => Price::from(*quote.ask * (1.0 + pct)),
+        TradeType::Sell => Price::from(*quote.bid * (1.0 - pct)),
+    }
+}
+
+///Moves the execution price by a fixed number of basis points away from the quote.
+///
+///let slippage = FixedBasisPoints::new(5.0);
+#[derive(Clone, Debug)]
+pub struct FixedBasisPoints {
+    bps: f64,
+}
+
+impl FixedBasisPoints {
+    pub fn new(bps: f64) -> Self {
+        if bps < 0.0 {
+            panic!("Slippage cannot be negative");
+        }
+        Self { bps }
+    }
+}
+
+impl SlippageModel for FixedBasisPoints {
+    fn execution_price(
+        &self,
+        quote: &Quote,
+        _shares: &PortfolioQty,
+        trade_type: &TradeType,
+    ) -> Price {
+        adjust(quote, trade_type, self.bps / 10_000.0)
+    }
+}
+
+///Moves the execution price away from the quote by a proportion of the bid/ask spread. Securities
+///with wider spreads, which tend to be less liquid, will have more slippage.
+///
+///let slippage = SpreadProportional::new(0.5);
+#[derive(Clone, Debug)]
+pub struct SpreadProportional {
+    proportion: f64,
+}
+
+impl SpreadProportional {
+    pub fn new(proportion: f64) -> Self {
+        if proportion < 0.0 {
+            panic!("Slippage cannot be negative");
+        }
+        Self { proportion }
+    }
+}
+
+impl SlippageModel for SpreadProportional {
+    fn execution_price(
+        &self,
+        quote: &Quote,
+        _shares: &PortfolioQty,
+        trade_type: &TradeType,
+    ) -> Price {
+        let slippage = (*quote.ask - *quote.bid).abs() * self.proportion;
+        match trade_type {
+            TradeType::Buy => Price::from(*quote.ask + slippage),
+            TradeType::Sell => Price::from(*quote.bid - slippage),
+        }
+    }
+}
+
+///Square-root market impact model: price moves against the order by
+///`coefficient * sqrt(shares / volume)` as a fraction of the price. Coefficient is typically set to
+///the daily volatility of the security.
+///
+///Impact is only calculated against quotes with volume, trades against quotes without volume
+///execute at the quote. Impact on sells is capped so that the execution price stays positive.
+///
+///let slippage = SquareRootImpact::new(0.02);
+#[derive(Clone, Debug)]
+pub struct SquareRootImpact {
+    coefficient: f64,
+}
+
+impl SquareRootImpact {
+    const MAX_SELL_IMPACT: f64 = 0.99;
+
+    pub fn new(coefficient: f64) -> Self {
+        if coefficient < 0.0 {
+            panic!("Slippage cannot be negative");
+        }
+        Self { coefficient }
+    }
+}
+
+impl SlippageModel for SquareRootImpact {
+    fn execution_price(
+        &self,
+        quote: &Quote,
+        shares: &PortfolioQty,
+        trade_type: &TradeType,
+    ) -> Price {
+        let impact = match quote.volume {
+            Some(volume) if volume > 0.0 => self.coefficient * (shares.abs() / volume).sqrt(),
+            _ => 0.0,
+        };
+        //Impact is unbounded so a large sell against small volume would otherwise price at or
+        //below zero
+        let impact = match trade_type {
+            TradeType::Buy => impact,
+            TradeType::Sell => impact.min(Self::MAX_SELL_IMPACT),
+        };
+        adjust(quote, trade_type, impact)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixedBasisPoints, SlippageModel, SpreadProportional, SquareRootImpact};
+    use crate::broker::{Quote, TradeType};
+    use crate::types::PortfolioQty;
+
+    #[test]
+    fn test_that_fixed_bps_moves_price_against_order() {
+        let quote = Quote::new(100.0, 101.0, 100, "ABC");
+        let slippage = FixedBasisPoints::new(10.0);
+        let shares = PortfolioQty::from(100.0);
+
+        let buy = slippage.execution_price(&quote, &shares, &TradeType::Buy);
+        let sell = slippage.execution_price(&quote, &shares, &TradeType::Sell);
+        assert!((*buy - 101.101).abs() < 1e-9);
+        assert!((*sell - 99.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_that_spread_proportional_scales_with_spread() {
+        let quote = Quote::new(100.0, 102.0, 100, "ABC");
+        let slippage = SpreadProportional::new(0.5);
+        let shares = PortfolioQty::from(100.0);
+
+        assert_eq!(
+            *slippage.execution_price(&quote, &shares, &TradeType::Buy),
+            103.0
+        );
+        assert_eq!(
+            *slippage.execution_price(&quote, &shares, &TradeType::Sell),
+            99.0
+        );
+    }
+
+    #[test]
+    fn test_that_square_root_impact_grows_with_order_size() {
+        let quote = Quote::new_with_volume(100.0, 100.0, 100, "ABC", 10_000.0);
+        let slippage = SquareRootImpact::new(0.1);
+
+        let small = slippage.execution_price(&quote, &PortfolioQty::from(100.0), &TradeType::Buy);
+        let large = slippage.execution_price(&quote, &PortfolioQty::from(2500.0), &TradeType::Buy);
+        assert!((*small - 101.0).abs() < 1e-9);
+        assert!((*large - 105.0).abs() < 1e-9);
+
+        //No volume so impact cannot be calculated
+        let quote = Quote::new(100.0, 100.0, 100, "ABC");
+        let price = slippage.execution_price(&quote, &PortfolioQty::from(2500.0), &TradeType::Sell);
+        assert_eq!(*price, 100.0);
+    }
+
+    #[test]
+    fn test_that_square_root_impact_keeps_sell_price_positive() {
+        let quote = Quote::new_with_volume(100.0, 100.0, 100, "ABC", 100.0);
+        let slippage = SquareRootImpact::new(0.1);
+
+        //Uncapped impact would be 0.1 * sqrt(1_000_000 / 100) = 10
+        let shares = PortfolioQty::from(1_000_000.0);
+        let sell = slippage.execution_price(&quote, &shares, &TradeType::Sell);
+        assert!(*sell > 0.0);
+        assert!((*sell - 1.0).abs() < 1e-9);
+
+        let buy = slippage.execution_price(&quote, &shares, &TradeType::Buy);
+        assert!((*buy - 1100.0).abs() < 1e-9);
+    }
+}
@@ -3,11 +3,12 @@ use rand::thread_rng;
 use std::collections::HashMap;
 use std::rc::Rc;
 
-use crate::broker::{Dividend, Quote};
+use crate::broker::{Bar, CorporateAction, Dividend, FxRate, InterestRate, Quote};
 use crate::clock::Clock;
 use crate::types::DateTime;
 
-///Retrieves price and dividends for symbol/symbols.
+///Retrieves price, dividends, corporate actions, exchange rates, and interest rates for
+///symbol/symbols. Prices can be provided as quotes, bars, or both.
 ///
 ///Whilst this trait is created with backtests in mind, the calling pattern should match that used
 ///in live-trading systems. All system time data is stored within structs implementing this trait
@@ -16,7 +17,12 @@ use crate::types::DateTime;
 pub trait DataSource: Clone {
     fn get_quote(&self, symbol: &str) -> Option<&Quote>;
     fn get_quotes(&self) -> Option<&Vec<Quote>>;
+    fn get_bar(&self, symbol: &str) -> Option<&Bar>;
+    fn get_bars(&self) -> Option<&Vec<Bar>>;
     fn get_dividends(&self) -> Option<&Vec<Dividend>>;
+    fn get_corporate_actions(&self) -> Option<&Vec<CorporateAction>>;
+    fn get_fx_rates(&self) -> Option<&Vec<FxRate>>;
+    fn get_interest_rates(&self) -> Option<&Vec<InterestRate>>;
 }
 
 ///Implementation of [DataSource trait that wraps around a HashMap. Time is kept with reference to
@@ -24,12 +30,20 @@ pub trait DataSource: Clone {
 #[derive(Clone, Debug)]
 pub struct HashMapInput {
     quotes: QuotesHashMap,
+    bars: BarsHashMap,
     dividends: DividendsHashMap,
+    corporate_actions: CorporateActionsHashMap,
+    fx_rates: FxRatesHashMap,
+    interest_rates: InterestRatesHashMap,
     clock: Clock,
 }
 
 pub type QuotesHashMap = HashMap<DateTime, Vec<Quote>>;
+pub type BarsHashMap = HashMap<DateTime, Vec<Bar>>;
 pub type DividendsHashMap = HashMap<DateTime, Vec<Dividend>>;
+pub type CorporateActionsHashMap = HashMap<DateTime, Vec<CorporateAction>>;
+pub type FxRatesHashMap = HashMap<DateTime, Vec<FxRate>>;
+pub type InterestRatesHashMap = HashMap<DateTime, Vec<InterestRate>>;
 
 impl DataSource for HashMapInput {
     fn get_quote(&self, symbol: &str) -> Option<&Quote> {
@@ -49,28 +63,69 @@ impl DataSource for HashMapInput {
         self.quotes.get(&curr_date)
     }
 
+    fn get_bar(&self, symbol: &str) -> Option<&Bar> {
+        let curr_date = self.clock.borrow().now();
+        if let Some(bars) = self.bars.get(&curr_date) {
+            for bar in bars {
+                if bar.symbol.eq(symbol) {
+                    return Some(bar);
+                }
+            }
+        }
+        None
+    }
+
+    fn get_bars(&self) -> Option<&Vec<Bar>> {
+        let curr_date = self.clock.borrow().now();
+        self.bars.get(&curr_date)
+    }
+
     fn get_dividends(&self) -> Option<&Vec<Dividend>> {
         let curr_date = self.clock.borrow().now();
         self.dividends.get(&curr_date)
     }
+
+    fn get_corporate_actions(&self) -> Option<&Vec<CorporateAction>> {
+        let curr_date = self.clock.borrow().now();
+        self.corporate_actions.get(&curr_date)
+    }
+
+    fn get_fx_rates(&self) -> Option<&Vec<FxRate>> {
+        let curr_date = self.clock.borrow().now();
+        self.fx_rates.get(&curr_date)
+    }
+
+    fn get_interest_rates(&self) -> Option<&Vec<InterestRate>> {
+        let curr_date = self.clock.borrow().now();
+        self.interest_rates.get(&curr_date)
+    }
 }
 
-//Can run without dividends but users of struct must initialise date and must set quotes
+//Can run without dividends, corporate actions, fx rates, or interest rates but users of struct
+//must initialise date and must set quotes or bars
 pub struct HashMapInputBuilder {
     quotes: Option<QuotesHashMap>,
+    bars: Option<BarsHashMap>,
     dividends: DividendsHashMap,
+    corporate_actions: CorporateActionsHashMap,
+    fx_rates: FxRatesHashMap,
+    interest_rates: InterestRatesHashMap,
     clock: Option<Clock>,
 }
 
 impl HashMapInputBuilder {
     pub fn build(&self) -> HashMapInput {
-        if self.clock.is_none() || self.quotes.is_none() {
-            panic!("HashMapInput type must have quotes and must have date initialised");
+        if self.clock.is_none() || (self.quotes.is_none() && self.bars.is_none()) {
+            panic!("HashMapInput type must have quotes or bars and must have date initialised");
         }
 
         HashMapInput {
-            quotes: self.quotes.as_ref().unwrap().clone(),
+            quotes: self.quotes.clone().unwrap_or_default(),
+            bars: self.bars.clone().unwrap_or_default(),
             dividends: self.dividends.clone(),
+            corporate_actions: self.corporate_actions.clone(),
+            fx_rates: self.fx_rates.clone(),
+            interest_rates: self.interest_rates.clone(),
             clock: self.clock.as_ref().unwrap().clone(),
         }
     }
@@ -80,11 +135,34 @@ impl HashMapInputBuilder {
         self
     }
 
+    pub fn with_bars(&mut self, bars: BarsHashMap) -> &mut Self {
+        self.bars = Some(bars);
+        self
+    }
+
     pub fn with_dividends(&mut self, dividends: DividendsHashMap) -> &mut Self {
         self.dividends = dividends;
         self
     }
 
+    pub fn with_corporate_actions(
+        &mut self,
+        corporate_actions: CorporateActionsHashMap,
+    ) -> &mut Self {
+        self.corporate_actions = corporate_actions;
+        self
+    }
+
+    pub fn with_fx_rates(&mut self, fx_rates: FxRatesHashMap) -> &mut Self {
+        self.fx_rates = fx_rates;
+        self
+    }
+
+    pub fn with_interest_rates(&mut self, interest_rates: InterestRatesHashMap) -> &mut Self {
+        self.interest_rates = interest_rates;
+        self
+    }
+
     pub fn with_clock(&mut self, clock: Clock) -> &mut Self {
         self.clock = Some(clock);
         self
@@ -93,7 +171,11 @@ impl HashMapInputBuilder {
     pub fn new() -> Self {
         Self {
             quotes: None,
+            bars: None,
             dividends: HashMap::new(),
+            corporate_actions: HashMap::new(),
+            fx_rates: HashMap::new(),
+            interest_rates: HashMap::new(),
             clock: None,
         }
     }
@@ -1,3 +1,4 @@
+pub mod accounts;
 pub mod broker;
 pub mod clock;
 pub mod exchange;
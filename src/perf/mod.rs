@@ -26,35 +26,33 @@ struct CalculationAlgos;
 
 impl CalculationAlgos {
     ///Returns a tuple containing (max drawdown, position of drawdown start, end position)
-    fn maxdd(values: &Vec<f64>) -> (f64, usize, usize) {
+    fn maxdd(values: &[f64]) -> (f64, usize, usize) {
         let mut maxdd = 0.0;
         let mut peak = 0.0;
         let mut peak_pos: usize = 0;
         let mut trough = 0.0;
         let mut trough_pos: usize = 0;
         let mut t2;
-        let mut pos: usize = 0;
 
-        for t1 in values {
+        for (pos, t1) in values.iter().enumerate() {
             if t1 > &peak {
                 peak = *t1;
-                peak_pos = pos.clone();
+                peak_pos = pos;
                 trough = peak;
-                trough_pos = peak_pos.clone();
+                trough_pos = peak_pos;
             } else if t1 < &trough {
                 trough = *t1;
-                trough_pos = pos.clone();
+                trough_pos = pos;
                 t2 = (trough / peak) - 1.0;
                 if t2 < maxdd {
                     maxdd = t2
                 }
             }
-            pos += 1;
         }
         (maxdd, peak_pos, trough_pos)
     }
 
-    fn var(values: &Vec<f64>) -> f64 {
+    fn var(values: &[f64]) -> f64 {
         let count = values.len();
         let mean: f64 = values.iter().sum::<f64>() / (count as f64);
         let squared_diffs: Vec<f64> = values
@@ -66,7 +64,7 @@ impl CalculationAlgos {
         sum_of_diff / (count as f64)
     }
 
-    fn vol(values: &Vec<f64>) -> f64 {
+    fn vol(values: &[f64]) -> f64 {
         //Accepts returns not raw portfolio values
         CalculationAlgos::var(values).sqrt()
     }
@@ -98,12 +96,12 @@ impl PortfolioCalculations {
         }
     }
 
-    fn get_vol(rets: &Vec<f64>, freq: &Frequency) -> f64 {
-        let vol = CalculationAlgos::vol(&rets);
+    fn get_vol(rets: &[f64], freq: &Frequency) -> f64 {
+        let vol = CalculationAlgos::vol(rets);
         PortfolioCalculations::annualize_volatility(vol, freq)
     }
 
-    fn get_sharpe(rets: &Vec<f64>, log_rets: &Vec<f64>, days: i32, freq: &Frequency) -> f64 {
+    fn get_sharpe(rets: &[f64], log_rets: &[f64], days: i32, freq: &Frequency) -> f64 {
         let vol = PortfolioCalculations::get_vol(rets, freq);
         let ret = PortfolioCalculations::get_cagr(log_rets, days, freq);
         if vol == 0.0 {
@@ -116,7 +114,7 @@ impl PortfolioCalculations {
         ret / vol
     }
 
-    fn get_maxdd(rets: &Vec<f64>) -> (f64, usize, usize) {
+    fn get_maxdd(rets: &[f64]) -> (f64, usize, usize) {
         //Adds N to the runtime, can run faster but it isn't worth the time atm
         let mut values_with_cashflows = vec![100_000.0];
         for i in rets {
@@ -128,18 +126,18 @@ impl PortfolioCalculations {
         CalculationAlgos::maxdd(&values_with_cashflows)
     }
 
-    fn get_cagr(log_rets: &Vec<f64>, days: i32, freq: &Frequency) -> f64 {
+    fn get_cagr(log_rets: &[f64], days: i32, freq: &Frequency) -> f64 {
         let ret = PortfolioCalculations::get_portfolio_return(log_rets);
         PortfolioCalculations::annualize_returns(ret, days, freq)
     }
 
-    fn get_portfolio_return(log_rets: &Vec<f64>) -> f64 {
+    fn get_portfolio_return(log_rets: &[f64]) -> f64 {
         let sum_log_rets: f64 = log_rets.iter().sum();
         sum_log_rets.exp() - 1.0
     }
 
     fn get_returns(
-        portfolio_values: &Vec<f64>,
+        portfolio_values: &[f64],
         cash_flows: &[f64],
         inflation: &[f64],
         is_log: bool,
@@ -159,12 +157,11 @@ impl PortfolioCalculations {
 
                 let inflation_value = inflation.get(i).unwrap();
 
-                let ret: f64;
-                if capital == 0.0 {
-                    ret = 0.0
+                let ret: f64 = if capital == 0.0 {
+                    0.0
                 } else {
-                    ret = ((1.0 + (gain / capital)) / (1.0 + *inflation_value)) - 1.0;
-                }
+                    ((1.0 + (gain / capital)) / (1.0 + *inflation_value)) - 1.0
+                };
 
                 if is_log {
                     let log_ret = (1.0 + ret).ln();
@@ -203,7 +200,7 @@ impl PerformanceCalculator {
             }
         }
 
-        let inflation: Vec<f64> = states.iter().map(|v| v.inflation.clone()).collect();
+        let inflation: Vec<f64> = states.iter().map(|v| v.inflation).collect();
 
         let returns =
             PortfolioCalculations::get_returns(&total_values, &cash_flows, &inflation, false);
@@ -241,8 +238,8 @@ impl PerformanceCalculator {
             returns,
             dates: dates.clone(),
             cash_flows,
-            first_date: dates.first().unwrap().clone(),
-            last_date: dates.last().unwrap().clone(),
+            first_date: *dates.first().unwrap(),
+            last_date: *dates.last().unwrap(),
             dd_start_date,
             dd_end_date,
             best_return,
@@ -394,25 +391,29 @@ mod tests {
             date: 100.into(),
             portfolio_value: 100.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap1 = StrategySnapshot {
             date: 101.into(),
             portfolio_value: 121.0.into(),
             net_cash_flow: 10.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap2 = StrategySnapshot {
             date: 102.into(),
             portfolio_value: 126.9.into(),
             net_cash_flow: 30.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap3 = StrategySnapshot {
             date: 103.into(),
             portfolio_value: 150.59.into(),
             net_cash_flow: 40.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let with_cash_flows = vec![snap0, snap1, snap2, snap3];
 
@@ -420,25 +421,29 @@ mod tests {
             date: 100.into(),
             portfolio_value: 100.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap4 = StrategySnapshot {
             date: 101.into(),
             portfolio_value: 110.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap5 = StrategySnapshot {
             date: 102.into(),
             portfolio_value: 99.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap6 = StrategySnapshot {
             date: 103.into(),
             portfolio_value: 108.9.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let without_cash_flows = vec![snap3, snap4, snap5, snap6];
 
@@ -459,19 +464,22 @@ mod tests {
             date: 100.into(),
             portfolio_value: 100.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap2 = StrategySnapshot {
             date: 101.into(),
             portfolio_value: 110.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.10.into(),
+            inflation: 0.10,
+            fees: 0.0.into(),
         };
         let snap3 = StrategySnapshot {
             date: 102.into(),
             portfolio_value: 121.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.10.into(),
+            inflation: 0.10,
+            fees: 0.0.into(),
         };
 
         let with_inflation = vec![snap1, snap2, snap3];
@@ -488,22 +496,25 @@ mod tests {
             date: 100.into(),
             portfolio_value: 0.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap2 = StrategySnapshot {
             date: 101.into(),
             portfolio_value: 0.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap3 = StrategySnapshot {
             date: 102.into(),
             portfolio_value: 0.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
 
-        let with_zeros= vec![snap1, snap2, snap3];
+        let with_zeros = vec![snap1, snap2, snap3];
 
         let perf = PerformanceCalculator::calculate(Frequency::Yearly, with_zeros);
 
@@ -517,19 +528,22 @@ mod tests {
             date: 100.into(),
             portfolio_value: 110.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap2 = StrategySnapshot {
             date: 101.into(),
             portfolio_value: 90.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
         let snap3 = StrategySnapshot {
             date: 102.into(),
             portfolio_value: 110.0.into(),
             net_cash_flow: 0.0.into(),
-            inflation: 0.0.into(),
+            inflation: 0.0,
+            fees: 0.0.into(),
         };
 
         let snaps = vec![snap1, snap2, snap3];
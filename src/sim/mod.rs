@@ -1,20 +1,36 @@
 use core::panic;
 use log::info;
+use std::collections::{HashMap, HashSet};
 
+use crate::broker::cgt::UkCapitalGains;
+use crate::broker::lots::{LotMethod, LotSelection, RealisedGain, TaxLot, TaxLots};
+use crate::broker::pnl::PnlLedger;
 use crate::broker::record::BrokerLog;
+use crate::broker::wash::{WashSale, WashSales};
 use crate::broker::{
-    BacktestBroker, BrokerCalculations, BrokerCashEvent, BrokerCost, BrokerEvent, DividendPayment,
-    EventLog, GetsQuote, Order, OrderType, Quote, Trade, TradeType, TransferCash,
+    AccountFee, BacktestBroker, BracketOrder, BrokerCalculations, BrokerCashEvent, BrokerCost,
+    BrokerEvent, BrokerRecordedEvent, CashInterest, CashInterestRate, CorporateAction,
+    DividendPayment, DividendReinvestment, EventLog, FeeCharge, ForcedLiquidation, FxRate,
+    GetsQuote, Instrument, InterestPayment, Margin, MarginCall, Order, OrderExpiry, OrderId,
+    OrderStatus, OrderType, Quote, Trade, TradeType, TransferCash, WithholdingTax,
 };
 use crate::exchange::{DefaultExchange, Exchange};
 use crate::input::DataSource;
-use crate::types::{CashValue, PortfolioHoldings, PortfolioQty, Price};
+use crate::types::{CashValue, Currency, DateTime, PortfolioHoldings, PortfolioQty, Price};
 
 pub struct SimulatedBrokerBuilder<T: DataSource> {
     //Cannot run without data but can run with empty trade_costs
     data: Option<T>,
     trade_costs: Vec<BrokerCost>,
     exchange: Option<DefaultExchange<T>>,
+    short_selling: bool,
+    margin: Option<Margin>,
+    base_currency: Currency,
+    dividend_reinvestment: DividendReinvestment,
+    withholding_tax: Option<WithholdingTax>,
+    cash_interest: Option<CashInterest>,
+    account_fees: Vec<AccountFee>,
+    lot_method: LotMethod,
 }
 
 impl<T: DataSource> SimulatedBrokerBuilder<T> {
@@ -30,17 +46,43 @@ impl<T: DataSource> SimulatedBrokerBuilder<T> {
         let holdings = PortfolioHoldings::new();
         let log = BrokerLog::new();
 
-        SimulatedBroker {
+        let exchange = self.exchange.as_ref().unwrap();
+        let mut brkr = SimulatedBroker {
             data: self.data.as_ref().unwrap().clone(),
             //Intialised as invalid so errors throw if client tries to run before init
             holdings,
             cash: CashValue::from(0.0),
             log,
             trade_costs: self.trade_costs.clone(),
-            exchange: self.exchange.as_ref().unwrap().clone(),
+            exchange: exchange.clone(),
             //Initialized as ready because there is no state to catch up with when we create it
             ready_state: SimulatedBrokerReadyState::Ready,
-        }
+            short_selling: self.short_selling,
+            margin: self.margin.clone(),
+            //Broker starts in Ready state, so the build date is treated as the first check
+            last_check: Some(exchange.now()),
+            base_currency: self.base_currency,
+            foreign_cash: HashMap::new(),
+            fx_rates: HashMap::new(),
+            fx_history: Vec::new(),
+            dividend_reinvestment: self.dividend_reinvestment.clone(),
+            withholding_tax: self.withholding_tax.clone(),
+            pending_dividends: Vec::new(),
+            cash_interest: self.cash_interest.clone(),
+            interest_rates: HashMap::new(),
+            accrued_interest: HashMap::new(),
+            account_fees: self.account_fees.clone(),
+            accrued_fees: vec![0.0; self.account_fees.len()],
+            lots: TaxLots::new(self.lot_method.clone()),
+            pnl: PnlLedger::new(&self.base_currency),
+            lot_method: self.lot_method.clone(),
+            pending_reinvestments: Vec::new(),
+            reinvestment_orders: HashSet::new(),
+        };
+        //Broker starts in Ready state so needs the rates for the first date before check
+        brkr.update_fx_rates();
+        brkr.update_interest_rates();
+        brkr
     }
 
     pub fn with_exchange(&mut self, exchange: DefaultExchange<T>) -> &mut Self {
@@ -58,11 +100,72 @@ impl<T: DataSource> SimulatedBrokerBuilder<T> {
         self
     }
 
+    //Short selling is disabled by default, sell orders greater than the current holding will be
+    //rejected
+    pub fn with_short_selling(&mut self, short_selling: bool) -> &mut Self {
+        self.short_selling = short_selling;
+        self
+    }
+
+    //Without margin, the broker cannot borrow and positions must be fully funded with cash
+    pub fn with_margin(&mut self, margin: Margin) -> &mut Self {
+        self.margin = Some(margin);
+        self
+    }
+
+    //Deposits, withdrawals, and valuations are in the base currency, defaults to the currency
+    //of quotes created without a currency
+    pub fn with_base_currency(&mut self, base_currency: impl Into<Currency>) -> &mut Self {
+        self.base_currency = base_currency.into();
+        self
+    }
+
+    //Dividends are credited to cash by default
+    pub fn with_dividend_reinvestment(
+        &mut self,
+        dividend_reinvestment: DividendReinvestment,
+    ) -> &mut Self {
+        self.dividend_reinvestment = dividend_reinvestment;
+        self
+    }
+
+    //Without withholding tax, dividends are paid gross
+    pub fn with_withholding_tax(&mut self, withholding_tax: WithholdingTax) -> &mut Self {
+        self.withholding_tax = Some(withholding_tax);
+        self
+    }
+
+    //Without cash interest, cash balances earn nothing
+    pub fn with_cash_interest(&mut self, cash_interest: CashInterest) -> &mut Self {
+        self.cash_interest = Some(cash_interest);
+        self
+    }
+
+    //Fees charged on the account, as opposed to trade costs which are charged on every trade
+    pub fn with_account_fees(&mut self, account_fees: Vec<AccountFee>) -> &mut Self {
+        self.account_fees = account_fees;
+        self
+    }
+
+    //Tax lots are relieved FIFO by default
+    pub fn with_lot_method(&mut self, lot_method: LotMethod) -> &mut Self {
+        self.lot_method = lot_method;
+        self
+    }
+
     pub fn new() -> Self {
         SimulatedBrokerBuilder {
             data: None,
             trade_costs: Vec::new(),
             exchange: None,
+            short_selling: false,
+            margin: None,
+            base_currency: Currency::default(),
+            dividend_reinvestment: DividendReinvestment::default(),
+            withholding_tax: None,
+            cash_interest: None,
+            account_fees: Vec::new(),
+            lot_method: LotMethod::default(),
         }
     }
 }
@@ -82,12 +185,17 @@ impl<T: DataSource> Default for SimulatedBrokerBuilder<T> {
 ///immediately but is sent to an exchange that executes the order in the next period for which
 ///there is a price. This structure ensures that clients cannot lookahead.
 ///
-///Supports multiple `BrokerCost` models defined in broker/mod.rs: Flat, PerShare, PctOfValue.
+///Supports multiple `BrokerCost` models defined in broker/mod.rs: Flat, PerShare, PctOfValue,
+///Tiered, BuyTax, and costs with minimum/maximum bounds or that apply to a single exchange. Costs
+///are deducted from cash when the trade settles and are recorded on the `Trade`.
 ///
-///Cash balance held in single currency, which is assumed to be the same currency used in all
-///quotes found in the implementation of `DataSource` passed to `SimulatedBroker`. Cash balance can
-///be negative due to the non-immediate execution of trades. Broker will try to re-balance
-///automatically.
+///Cash is held in a base currency, which all valuations are reported in, and in a balance for
+///every other currency that the broker has traded. Trades and dividends settle in the currency
+///of the quote. If the purchase of a security takes the balance in that currency below zero, the
+///shortfall is converted from the base currency at the latest `FxRate` from the `DataSource`.
+///Positive balances in other currencies are held until they are converted by the client. Cash
+///balance can be negative due to the non-immediate execution of trades. Broker will try to
+///re-balance automatically.
 ///
 ///If series has a lot of volatility between periods, this will cause unexpected outcomes as
 ///the broker tries to continuously rebalance the negative cash balance.
@@ -97,6 +205,27 @@ impl<T: DataSource> Default for SimulatedBrokerBuilder<T> {
 ///
 ///Keeps an internal log of trades executed and dividends received/paid. The events supported by
 ///the `BrokerLog` are stored in the `BrokerRecordedEvent` enum in broker/mod.rs.
+///
+///If short selling is enabled, sell orders can take holdings below zero. Short positions are
+///valued at the ask and the proceeds of the sale are held in cash as collateral: without margin,
+///short positions must be fully covered by cash.
+///
+///With a `Margin` account, the broker can borrow cash to open positions worth more than the
+///equity in the account. Interest on the loan is charged when the broker is checked. Negative cash
+///balances are not rebalanced but if equity falls below the maintenance margin then the broker
+///issues a `MarginCall` and liquidates a proportion of every position to restore the initial
+///margin.
+///
+///With `DividendReinvestment`, dividends are paid into cash and then used to buy the paying
+///security with a market order that executes on the next tick. Trades that reinvest dividends are
+///recorded in the `BrokerLog` as `DividendReinvested` rather than `TradeCompleted`.
+///
+///With `CashInterest`, positive cash balances in each currency earn interest that is accrued when
+///the broker is checked and credited at the end of every period. `AccountFee`s are deducted from
+///cash on a similar schedule, based on the total value of the account.
+///
+///Every trade opens or relieves tax lots, which are rebuilt from the `BrokerLog` on request. The
+///`LotMethod` sets which lots are relieved when a position is reduced.
 #[derive(Clone, Debug)]
 pub struct SimulatedBroker<T: DataSource> {
     //We have overlapping functionality because we are storing
@@ -107,6 +236,35 @@ pub struct SimulatedBroker<T: DataSource> {
     trade_costs: Vec<BrokerCost>,
     exchange: DefaultExchange<T>,
     ready_state: SimulatedBrokerReadyState,
+    short_selling: bool,
+    margin: Option<Margin>,
+    last_check: Option<DateTime>,
+    base_currency: Currency,
+    foreign_cash: HashMap<Currency, CashValue>,
+    //Last seen rate for each pair, so that valuations continue if the source is missing rates
+    fx_rates: HashMap<(Currency, Currency), f64>,
+    //Every rate seen, so that past trades can be converted at the rate on the date of the trade
+    fx_history: Vec<FxRate>,
+    dividend_reinvestment: DividendReinvestment,
+    withholding_tax: Option<WithholdingTax>,
+    //Dividends that have passed the ex-date but have not been paid
+    pending_dividends: Vec<DividendPayment>,
+    cash_interest: Option<CashInterest>,
+    //Last seen rate for each currency, from the source
+    interest_rates: HashMap<Currency, f64>,
+    //Interest earned in each currency that has not yet been credited
+    accrued_interest: HashMap<Currency, f64>,
+    account_fees: Vec<AccountFee>,
+    //Fee accrued for each of the account fees that has not yet been charged
+    accrued_fees: Vec<f64>,
+    //Updated as each event is recorded so that profit can be read without replaying the log
+    lots: TaxLots,
+    pnl: PnlLedger,
+    lot_method: LotMethod,
+    //Orders created when dividends are paid, sent to the exchange after it has been checked so
+    //that they execute on the next tick
+    pending_reinvestments: Vec<Order>,
+    reinvestment_orders: HashSet<OrderId>,
 }
 
 impl<T: DataSource> SimulatedBroker<T> {
@@ -114,6 +272,346 @@ impl<T: DataSource> SimulatedBroker<T> {
         self.log.cost_basis(symbol)
     }
 
+    pub fn margin_calls(&self) -> Vec<MarginCall> {
+        self.log.margin_calls()
+    }
+
+    pub fn expired_orders(&self) -> Vec<OrderExpiry> {
+        self.log.expired_orders()
+    }
+
+    pub fn corporate_actions(&self) -> Vec<CorporateAction> {
+        self.log.corporate_actions()
+    }
+
+    pub fn dividend_reinvestments(&self) -> Vec<Trade> {
+        self.log.dividend_reinvestments()
+    }
+
+    pub fn interest_paid(&self) -> Vec<InterestPayment> {
+        self.log.interest_paid()
+    }
+
+    pub fn fees(&self) -> Vec<FeeCharge> {
+        self.log.fees()
+    }
+
+    pub fn tax_lots(&self) -> &TaxLots {
+        &self.lots
+    }
+
+    pub fn open_lots(&self, symbol: &str) -> Vec<TaxLot> {
+        self.lots.open_lots(symbol)
+    }
+
+    pub fn realised_gains(&self) -> Vec<RealisedGain> {
+        self.lots.realised()
+    }
+
+    //Lots are relieved in the order given when the position is next reduced, only used when the
+    //broker relieves lots by specific identification
+    pub fn select_lots(&mut self, symbol: &str, lot_ids: Vec<u64>) {
+        info!("BROKER: Selected lots {:?} of {:?}", lot_ids, symbol);
+        self.record(LotSelection::new(symbol, lot_ids, self.exchange.now()));
+    }
+
+    //Disposals matched under HMRC share matching rules, independent of the lot method, in sterling
+    pub fn uk_capital_gains(&self) -> UkCapitalGains {
+        self.log.uk_capital_gains(&self.fx_history)
+    }
+
+    //Losses disallowed under the US wash sale rule, with the cost of open lots adjusted. Lots and
+    //gains held by the broker are not adjusted, the adjusted view is built from the log on request.
+    pub fn wash_sales(&self) -> WashSales {
+        self.log.wash_sales(self.lot_method.clone())
+    }
+
+    pub fn pnl_ledger(&self) -> &PnlLedger {
+        &self.pnl
+    }
+
+    //Profit on the open lots of symbol if they were closed at the current quote, in the base
+    //currency and net of the costs paid to open the lots. Only available at the current date as
+    //past prices aren't kept.
+    pub fn unrealised_pnl(&self, symbol: &str) -> Option<CashValue> {
+        let value = self.get_position_value(symbol)?;
+        let quote = self.get_quote(symbol)?;
+        let rate = self.get_fx_rate(&quote.currency)?;
+        //Cost of short lots is the proceeds of the sale, and the position value is negative
+        let cost: f64 = self
+            .open_lots(symbol)
+            .iter()
+            .map(|lot| lot.cost.copysign(*lot.quantity))
+            .sum();
+        Some(CashValue::from(*value - cost * rate))
+    }
+
+    //Realised and unrealised profit on symbol at the current date, including dividends. Realised
+    //profit in other currencies is converted into the base currency at the current rate. Realised
+    //profit at a past date is available from the ledger.
+    pub fn position_pnl(&self, symbol: &str) -> CashValue {
+        let realised = self.pnl.symbol_total(symbol, &self.exchange.now());
+        let unrealised = self.unrealised_pnl(symbol).unwrap_or_default();
+        CashValue::from(self.total_in_base_currency(&realised) + *unrealised)
+    }
+
+    //Realised and unrealised profit across the account at the current date, net of fees and
+    //interest
+    pub fn total_pnl(&self) -> CashValue {
+        let realised = self.pnl.total(&self.exchange.now());
+        let realised = self.total_in_base_currency(&realised);
+        let unrealised: f64 = self
+            .get_positions()
+            .iter()
+            .filter_map(|symbol| self.unrealised_pnl(symbol))
+            .map(|pnl| *pnl)
+            .sum();
+        CashValue::from(realised + unrealised)
+    }
+
+    //Sum of all account fees charged
+    pub fn total_fees(&self) -> CashValue {
+        CashValue::from(self.fees().iter().map(|charge| *charge.value).sum::<f64>())
+    }
+
+    //Cash held in a single currency, without conversion
+    pub fn get_cash_balance_in(&self, currency: &Currency) -> CashValue {
+        if *currency == self.base_currency {
+            return self.cash.clone();
+        }
+        self.foreign_cash.get(currency).cloned().unwrap_or_default()
+    }
+
+    //Converts value, in the from currency, into the to currency at the latest rate. Fails if the
+    //broker has no rate or holds less than value in the from currency.
+    pub fn convert_cash(&mut self, from: &Currency, to: &Currency, value: &f64) -> BrokerCashEvent {
+        if let SimulatedBrokerReadyState::Invalid = self.ready_state {
+            panic!("Attempted to convert cash before state update");
+        }
+        let converted = match self.convert(value, from, to) {
+            Some(converted) => converted,
+            None => return BrokerCashEvent::ConversionFailure(CashValue::from(*value)),
+        };
+        if *self.get_cash_balance_in(from) < *value {
+            info!(
+                "BROKER: Failed to convert {:?} {:?} into {:?}",
+                value, from, to
+            );
+            return BrokerCashEvent::ConversionFailure(CashValue::from(*value));
+        }
+        info!(
+            "BROKER: Converted {:?} {:?} into {:?} {:?}",
+            value, from, converted, to
+        );
+        self.adjust_cash_in(from, -value);
+        self.adjust_cash_in(to, converted);
+        BrokerCashEvent::ConversionSuccess(CashValue::from(converted))
+    }
+
+    //Values without a rate are left out of the total
+    fn total_in_base_currency(&self, totals: &HashMap<Currency, CashValue>) -> f64 {
+        totals
+            .iter()
+            .filter_map(|(currency, value)| self.convert(value, currency, &self.base_currency))
+            .sum()
+    }
+
+    fn convert(&self, value: &f64, from: &Currency, to: &Currency) -> Option<f64> {
+        if from == to {
+            return Some(*value);
+        }
+        if let Some(rate) = self.fx_rates.get(&(*from, *to)) {
+            return Some(value * rate);
+        }
+        if let Some(rate) = self.fx_rates.get(&(*to, *from)) {
+            return Some(value / rate);
+        }
+        None
+    }
+
+    //Every event goes through here so that lots and the ledger stay in line with the log
+    fn record<E: Into<BrokerRecordedEvent>>(&mut self, event: E) {
+        let event: BrokerRecordedEvent = event.into();
+        let realised = self.lots.apply(&event);
+        self.pnl.apply(&event, &realised);
+        self.log.record(event);
+    }
+
+    fn adjust_cash_in(&mut self, currency: &Currency, change: f64) {
+        if *currency == self.base_currency {
+            self.cash = CashValue::from(*self.cash + change);
+        } else {
+            let curr = self.get_cash_balance_in(currency);
+            self.foreign_cash
+                .insert(*currency, CashValue::from(*curr + change));
+        }
+    }
+
+    //Checks that the client can afford the order and that the order makes sense, orders are
+    //checked against the current price so may still fail or leave a negative balance on execution
+    fn validate_order(&self, order: &Order) -> bool {
+        let quote = match self.get_quote(order.get_symbol()) {
+            Some(quote) => quote,
+            None => {
+                info!(
+                    "BROKER: No price for {:?}, unable to send order",
+                    order.get_symbol()
+                );
+                return false;
+            }
+        };
+        //Open and close prices only come from bars, so these orders would never execute
+        let is_auction = matches!(
+            order.get_order_type(),
+            OrderType::MarketOnOpenBuy
+                | OrderType::MarketOnOpenSell
+                | OrderType::MarketOnCloseBuy
+                | OrderType::MarketOnCloseSell
+        );
+        if is_auction && self.data.get_bar(order.get_symbol()).is_none() {
+            info!(
+                "BROKER: No bar for {:?}, unable to send {:?} order",
+                order.get_symbol(),
+                order.get_order_type()
+            );
+            return false;
+        }
+        let price = if order.get_order_type().is_buy() {
+            &quote.ask
+        } else {
+            &quote.bid
+        };
+        //Cash checks are in the base currency, so we cannot trade without a rate
+        let multiplier = self.get_multiplier(order.get_symbol());
+        let price = match self.get_fx_rate(&quote.currency) {
+            Some(rate) => Price::from(**price * rate * multiplier),
+            None => {
+                info!(
+                    "BROKER: No fx rate for {:?}, unable to send order for {:?}",
+                    quote.currency,
+                    order.get_symbol()
+                );
+                return false;
+            }
+        };
+
+        if let Err(_err) = BrokerCalculations::client_has_sufficient_cash(order, &price, self) {
+            info!(
+                "BROKER: Unable to send {:?} order for {:?} shares of {:?} to exchange",
+                order.get_order_type(),
+                order.get_shares(),
+                order.get_symbol()
+            );
+            return false;
+        }
+        if !self.short_selling {
+            if let Err(_err) =
+                BrokerCalculations::client_has_sufficient_holdings_for_sale(order, self)
+            {
+                info!(
+                    "BROKER: Unable to send {:?} order for {:?} shares of {:?} to exchange",
+                    order.get_order_type(),
+                    order.get_shares(),
+                    order.get_symbol()
+                );
+                return false;
+            }
+        }
+        if let Err(_err) = BrokerCalculations::client_is_issuing_nonsense_order(order, self) {
+            info!(
+                "BROKER: Unable to send {:?} order for {:?} shares of {:?} to exchange",
+                order.get_order_type(),
+                order.get_shares(),
+                order.get_symbol()
+            );
+            return false;
+        }
+        true
+    }
+
+    fn update_fx_rates(&mut self) {
+        if let Some(fx_rates) = self.data.get_fx_rates() {
+            for fx_rate in fx_rates {
+                self.fx_rates
+                    .insert((fx_rate.from, fx_rate.to), fx_rate.rate);
+                self.fx_history.push(fx_rate.clone());
+            }
+        }
+    }
+
+    fn update_interest_rates(&mut self) {
+        if let Some(interest_rates) = self.data.get_interest_rates() {
+            for interest_rate in interest_rates {
+                self.interest_rates
+                    .insert(interest_rate.currency, interest_rate.rate);
+            }
+        }
+    }
+
+    //Interest is accrued on the balances held since the last check at the rates in effect over
+    //that period, so rates are only updated after accrual
+    fn pay_cash_interest(&mut self) {
+        if let (Some(cash_interest), Some(last_check)) =
+            (self.cash_interest.clone(), self.last_check.clone())
+        {
+            let now = self.exchange.now();
+            let mut balances = vec![(self.base_currency, *self.cash)];
+            balances.extend(
+                self.foreign_cash
+                    .iter()
+                    .map(|(currency, balance)| (*currency, **balance)),
+            );
+            for (currency, balance) in balances {
+                if balance <= 0.0 {
+                    continue;
+                }
+                let rate = match cash_interest.rate {
+                    CashInterestRate::Fixed(rate) => Some(rate),
+                    CashInterestRate::DataSource => self.interest_rates.get(&currency).copied(),
+                };
+                if let Some(rate) = rate {
+                    *self.accrued_interest.entry(currency).or_default() +=
+                        CashInterest::interest(&balance, rate, *now - *last_check);
+                }
+            }
+
+            if !now.is_same_period(&last_check, &cash_interest.frequency) {
+                for (currency, interest) in std::mem::take(&mut self.accrued_interest) {
+                    info!(
+                        "BROKER: Paid {:?} {:?} interest on cash",
+                        interest, currency
+                    );
+                    self.adjust_cash_in(&currency, interest);
+                    self.record(BrokerRecordedEvent::InterestPaid(
+                        InterestPayment::new_with_currency(interest, now.clone(), currency),
+                    ));
+                }
+            }
+        }
+        self.update_interest_rates();
+    }
+
+    //Purchases in other currencies are funded from the base currency
+    fn fund_foreign_cash(&mut self) {
+        let base_currency = self.base_currency;
+        let shortfalls: Vec<(Currency, f64)> = self
+            .foreign_cash
+            .iter()
+            .filter(|(_currency, value)| ***value < 0.0)
+            .map(|(currency, value)| (*currency, value.abs()))
+            .collect();
+        for (currency, shortfall) in shortfalls {
+            if let Some(cost) = self.convert(&shortfall, &currency, &base_currency) {
+                info!(
+                    "BROKER: Converting {:?} {:?} to cover shortfall in {:?}",
+                    cost, base_currency, currency
+                );
+                self.debit_force(&cost);
+                self.adjust_cash_in(&currency, shortfall);
+            }
+        }
+    }
+
     //Contains tasks that should be run on every iteration of the simulation irregardless of the
     //state on the client.
     pub fn check(&mut self) {
@@ -126,14 +624,29 @@ impl<T: DataSource> SimulatedBroker<T> {
             SimulatedBrokerReadyState::Invalid => {
                 self.ready_state = SimulatedBrokerReadyState::Ready;
                 info!("BROKER: Moved into Ready state");
+                self.update_fx_rates();
+                self.pay_cash_interest();
+                self.apply_corporate_actions();
                 self.pay_dividends();
+                self.charge_margin_interest();
+                self.charge_account_fees();
                 self.exchange.check();
                 //Reconcile must come after check so we can immediately reconcile the state of the
                 //exchange with the broker
                 self.reconcile_exchange();
-                //Previous step can cause negative cash balance so we have to rebalance here, this
-                //is not instant so will never balance properly if the series is very volatile
-                self.rebalance_cash();
+                self.send_reinvestments();
+                self.fund_foreign_cash();
+                if self.margin.is_some() {
+                    //Negative cash balance is a loan, we only need to reduce positions if the
+                    //account has insufficient equity
+                    self.check_margin();
+                } else {
+                    //Previous step can cause negative cash balance so we have to rebalance here,
+                    //this is not instant so will never balance properly if the series is very
+                    //volatile
+                    self.rebalance_cash();
+                }
+                self.last_check = Some(self.exchange.now());
             }
             SimulatedBrokerReadyState::InsufficientCash => {
                 //Make sure that the exchange state matches this by removing all unexecuted orders
@@ -148,18 +661,47 @@ impl<T: DataSource> SimulatedBroker<T> {
     }
 
     fn reconcile_exchange(&mut self) {
+        for expiry in self.exchange.flush_expired() {
+            info!(
+                "BROKER: Order {:?} for {:?} shares of {:?} expired",
+                expiry.order_id,
+                expiry.order.get_shares(),
+                expiry.order.get_symbol()
+            );
+            self.record(expiry);
+        }
         //All trades executed since the last call to this function
         let executed_trades = self.exchange.flush_buffer();
-        for trade in &executed_trades {
+        for mut trade in executed_trades {
+            //Costs are settled with the trade, in the currency of the trade
+            trade.costs = self.get_trade_costs(&trade);
+            let costs = *trade.costs;
             //TODO: if cash is below zero, we end the simulation. In practice, this shouldn't cause
             //problems because the broker will be unable to fund any future trades but exiting
             //early will give less confusing output.
-            match trade.typ {
-                //Force debit so we can end up with negative cash here
-                TradeType::Buy => self.debit_force(&trade.value),
-                TradeType::Sell => self.credit(&trade.value),
-            };
-            self.log.record(trade.clone());
+            if trade.currency == self.base_currency {
+                match trade.typ {
+                    //Force debit so we can end up with negative cash here
+                    TradeType::Buy => self.debit_force(&(*trade.value + costs)),
+                    TradeType::Sell => {
+                        self.credit(&trade.value);
+                        self.debit_force(&costs)
+                    }
+                };
+            } else {
+                match trade.typ {
+                    TradeType::Buy => self.adjust_cash_in(&trade.currency, -(*trade.value + costs)),
+                    TradeType::Sell => self.adjust_cash_in(&trade.currency, *trade.value - costs),
+                };
+            }
+            let is_reinvestment = trade
+                .order_id
+                .is_some_and(|order_id| self.reinvestment_orders.contains(&order_id));
+            if is_reinvestment {
+                self.record(BrokerRecordedEvent::DividendReinvested(trade.clone()));
+            } else {
+                self.record(trade.clone());
+            }
 
             let default = PortfolioQty::from(0.0);
             let curr_position = self.get_position_qty(&trade.symbol).unwrap_or(&default);
@@ -171,6 +713,349 @@ impl<T: DataSource> SimulatedBroker<T> {
 
             self.update_holdings(&trade.symbol, PortfolioQty::from(updated));
         }
+        //Orders are only removed from the orderbook once they are fully executed or cancelled
+        let exchange = &self.exchange;
+        self.reinvestment_orders
+            .retain(|order_id| exchange.get_order(order_id).is_some());
+    }
+
+    fn send_reinvestments(&mut self) {
+        for order in std::mem::take(&mut self.pending_reinvestments) {
+            //Cash from the dividend may have been spent or the symbol may no longer trade
+            if !self.validate_order(&order) {
+                info!(
+                    "BROKER: Unable to reinvest dividend in {:?}, cash is held",
+                    order.get_symbol()
+                );
+                continue;
+            }
+            let shares = order.get_shares().clone();
+            let symbol = order.get_symbol().clone();
+            let order_id = self.exchange.insert_order(order);
+            info!(
+                "BROKER: Sent order {:?} to reinvest dividend in {:?} shares of {:?}",
+                order_id, shares, symbol
+            );
+            self.reinvestment_orders.insert(order_id);
+        }
+    }
+
+    //Actions are applied before the exchange is checked because prices on the date of the action
+    //already reflect it
+    fn apply_corporate_actions(&mut self) {
+        if let Some(actions) = self.data.get_corporate_actions() {
+            for action in actions.clone() {
+                //Orders are adjusted even if there is no position
+                match &action {
+                    CorporateAction::Split(split) => self.exchange.apply_split(split),
+                    CorporateAction::SpinOff(_) => (),
+                    _ => self.exchange.cancel_orders_by_symbol(action.symbol()),
+                }
+                //Recorded before trades so the cost basis moves to new positions first
+                self.record(action.clone());
+                let qty = match self.get_position_qty(action.symbol()) {
+                    Some(qty) => **qty,
+                    None => continue,
+                };
+                info!(
+                    "BROKER: Applying {:?} to holding of {:?}",
+                    action,
+                    action.symbol()
+                );
+                match &action {
+                    CorporateAction::Split(split) => {
+                        self.hold_shares(&split.symbol, qty * split.ratio, &split.date);
+                    }
+                    CorporateAction::CashMerger(merger) => {
+                        let currency = self
+                            .get_quote(&merger.symbol)
+                            .map(|quote| quote.currency)
+                            .unwrap_or(self.base_currency);
+                        self.close_position(&merger.symbol, *merger.price, currency, &merger.date);
+                    }
+                    CorporateAction::StockMerger(merger) => {
+                        self.update_holdings(&merger.symbol, PortfolioQty::from(0.0));
+                        let existing = self
+                            .get_position_qty(&merger.new_symbol)
+                            .map(|qty| **qty)
+                            .unwrap_or(0.0);
+                        self.hold_shares(
+                            &merger.new_symbol,
+                            existing + qty * merger.ratio,
+                            &merger.date,
+                        );
+                    }
+                    CorporateAction::SpinOff(spin_off) => {
+                        let existing = self
+                            .get_position_qty(&spin_off.new_symbol)
+                            .map(|qty| **qty)
+                            .unwrap_or(0.0);
+                        self.hold_shares(
+                            &spin_off.new_symbol,
+                            existing + qty * spin_off.ratio,
+                            &spin_off.date,
+                        );
+                    }
+                    CorporateAction::Delisting(delisting) => {
+                        let quote = self.get_quote(&delisting.symbol).cloned();
+                        match quote {
+                            Some(quote) if delisting.cash_out => {
+                                let price = if qty > 0.0 { *quote.bid } else { *quote.ask };
+                                self.close_position(
+                                    &delisting.symbol,
+                                    price,
+                                    quote.currency,
+                                    &delisting.date,
+                                );
+                            }
+                            //Writing off a short would book the proceeds of the sale as a gain
+                            //without the shares being returned, so the short is left open
+                            _ if qty < 0.0 => {
+                                info!(
+                                    "BROKER: Unable to buy back short position in delisted {:?}",
+                                    delisting.symbol
+                                );
+                            }
+                            _ => {
+                                info!("BROKER: Writing off position in {:?}", delisting.symbol);
+                                let currency = self
+                                    .get_instrument(&delisting.symbol)
+                                    .map(|instrument| instrument.currency)
+                                    .unwrap_or(self.base_currency);
+                                self.close_position(
+                                    &delisting.symbol,
+                                    0.0,
+                                    currency,
+                                    &delisting.date,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    //Shares that can't be held, because the instrument trades whole shares or in lots, are sold at
+    //the current price. If there is no price then the shares are held.
+    fn hold_shares(&mut self, symbol: &str, shares: f64, date: &DateTime) {
+        let held = match self.get_instrument(symbol) {
+            Some(instrument) => instrument.round_quantity(shares),
+            None => shares.trunc(),
+        };
+        let fraction = shares - held;
+        let quote = self.get_quote(symbol).cloned();
+        match quote {
+            Some(quote) if fraction != 0.0 => {
+                self.update_holdings(symbol, PortfolioQty::from(held));
+                //Short positions buy back the fraction
+                let price = if fraction > 0.0 {
+                    *quote.bid
+                } else {
+                    *quote.ask
+                };
+                info!(
+                    "BROKER: Paid cash in lieu of {:?} shares of {:?}",
+                    fraction, symbol
+                );
+                self.dispose(symbol, fraction, price, quote.currency, date);
+            }
+            _ => self.update_holdings(symbol, PortfolioQty::from(shares)),
+        }
+    }
+
+    fn close_position(&mut self, symbol: &str, price: f64, currency: Currency, date: &DateTime) {
+        if let Some(qty) = self.get_position_qty(symbol).cloned() {
+            self.update_holdings(symbol, PortfolioQty::from(0.0));
+            self.dispose(symbol, *qty, price, currency, date);
+        }
+    }
+
+    //Records the sale of shares outside of the exchange, negative shares are bought back
+    fn dispose(
+        &mut self,
+        symbol: &str,
+        shares: f64,
+        price: f64,
+        currency: Currency,
+        date: &DateTime,
+    ) {
+        let value = shares.abs() * price * self.get_multiplier(symbol);
+        let trade_type = if shares > 0.0 {
+            self.adjust_cash_in(&currency, value);
+            TradeType::Sell
+        } else {
+            self.adjust_cash_in(&currency, -value);
+            TradeType::Buy
+        };
+        self.record(Trade::new_with_currency(
+            symbol,
+            value,
+            shares.abs(),
+            date.clone(),
+            trade_type,
+            currency,
+        ));
+    }
+
+    fn pay_dividend(&mut self, payment: DividendPayment) {
+        info!(
+            "BROKER: Paid dividend of {:?} for {:?}, withheld {:?}",
+            payment.value, payment.symbol, payment.withheld
+        );
+        let cash_value = payment.value.clone();
+        if payment.currency != self.base_currency {
+            self.adjust_cash_in(&payment.currency, *cash_value);
+        } else if (*cash_value).lt(&0.0) {
+            //Short positions have to pay the dividend to the lender of the shares
+            self.debit_force(&cash_value.abs());
+        } else {
+            self.credit(&cash_value);
+        }
+        if *cash_value > 0.0 && self.dividend_reinvestment.reinvests(&payment.symbol) {
+            self.reinvest_dividend(&payment.symbol.clone(), *cash_value);
+        }
+        self.record(payment);
+    }
+
+    //Sized at the current ask after costs, so the order can be less than the dividend if the price
+    //rises before the order executes. Any remaining cash is held.
+    fn reinvest_dividend(&mut self, symbol: &str, value: f64) {
+        //Costs are in the base currency so the dividend and price are converted to calculate the
+        //shares
+        let (price, rate) = match self.get_quote(symbol) {
+            Some(quote) => match self.get_fx_rate(&quote.currency) {
+                Some(rate) => (*quote.ask * self.get_multiplier(symbol), rate),
+                None => return,
+            },
+            None => return,
+        };
+        if price <= 0.0 {
+            return;
+        }
+        let (budget, price) =
+            self.calc_trade_impact(symbol, &(value * rate), &(price * rate), true);
+        let raw_shares = (*budget / *price).max(0.0);
+        let shares = match self.get_instrument(symbol) {
+            Some(instrument) => instrument.round_quantity(raw_shares),
+            None => raw_shares.floor(),
+        };
+        if shares > 0.0 {
+            self.pending_reinvestments
+                .push(Order::market(OrderType::MarketBuy, symbol, shares));
+        }
+    }
+
+    fn charge_margin_interest(&mut self) {
+        if let Some(margin) = &self.margin {
+            if let Some(last_check) = &self.last_check {
+                if *self.cash < 0.0 {
+                    let now = self.exchange.now();
+                    let interest = margin.interest(&self.cash.abs(), *now - **last_check);
+                    info!("BROKER: Charged {:?} interest on margin loan", interest);
+                    self.debit_force(&interest);
+                    self.record(BrokerRecordedEvent::MarginInterestCharged(
+                        InterestPayment::new(interest, now),
+                    ));
+                }
+            }
+        }
+    }
+
+    //Percentage fees are accrued on the value at the start of the check, before any trades for
+    //this tick have executed
+    fn charge_account_fees(&mut self) {
+        if let Some(last_check) = self.last_check.clone() {
+            let now = self.exchange.now();
+            let value = *self.get_total_value();
+            let mut charges = Vec::new();
+            for (fee, accrued) in self.account_fees.iter().zip(self.accrued_fees.iter_mut()) {
+                *accrued += fee.accrue(&value, *now - *last_check);
+                if !now.is_same_period(&last_check, fee.frequency()) {
+                    charges.push(FeeCharge::new(
+                        fee.charge(std::mem::take(accrued)),
+                        now.clone(),
+                        fee.clone(),
+                    ));
+                }
+            }
+            for charge in charges {
+                if *charge.value <= 0.0 {
+                    continue;
+                }
+                info!("BROKER: Charged account fee of {:?}", charge.value);
+                self.debit_force(&charge.value);
+                self.record(charge);
+            }
+        }
+    }
+
+    fn check_margin(&mut self) {
+        //Unwrap is safe because this is only called when the broker has margin
+        let margin = self.margin.clone().unwrap();
+        let gross_exposure = self.get_gross_exposure();
+        if (*gross_exposure).eq(&0.0) {
+            return;
+        }
+        let equity = self.get_total_value();
+        if *equity >= margin.maintenance * *gross_exposure {
+            return;
+        }
+
+        let now = self.exchange.now();
+        info!(
+            "BROKER: Margin call with equity of {:?} against positions of {:?}",
+            equity, gross_exposure
+        );
+        self.record(MarginCall::new(
+            equity.clone(),
+            gross_exposure.clone(),
+            now.clone(),
+        ));
+        if *equity <= 0.0 {
+            info!("BROKER: Moved into InsufficientCash state");
+            self.ready_state = SimulatedBrokerReadyState::InsufficientCash;
+            return;
+        }
+
+        //Each position is reduced by the same proportion until the positions can be supported by
+        //the initial margin
+        let target_exposure = *equity / margin.initial;
+        let reduction = 1.0 - (target_exposure / *gross_exposure);
+        for symbol in self.get_positions() {
+            //Positions are taken from holdings so always have qty
+            let qty = **self.get_position_qty(&symbol).unwrap();
+            //Holding may not be a whole number of lots, so the most that can be sold is rounded
+            //down
+            let shares = match self.exchange.get_instrument(&symbol) {
+                Some(instrument) => instrument
+                    .round_quantity_up(qty.abs() * reduction)
+                    .min(instrument.round_quantity(qty.abs())),
+                None => (qty.abs() * reduction).ceil().min(qty.abs()),
+            };
+            let order_type = if qty > 0.0 {
+                OrderType::MarketSell
+            } else {
+                OrderType::MarketBuy
+            };
+            let order = Order::market(order_type, symbol.clone(), shares);
+            //Cash checks don't apply as the order reduces the position, but the order must still
+            //be one that the exchange can execute
+            if let Err(_err) = BrokerCalculations::client_is_issuing_nonsense_order(&order, self) {
+                info!(
+                    "BROKER: Unable to liquidate {:?} shares of {:?}",
+                    shares, symbol
+                );
+                continue;
+            }
+            self.exchange.clear_pending_market_orders_by_symbol(&symbol);
+            self.exchange.insert_order(order.clone());
+            info!(
+                "BROKER: Forced liquidation of {:?} shares of {:?}",
+                shares, symbol
+            );
+            self.record(ForcedLiquidation::new(order, now.clone()));
+        }
     }
 
     fn rebalance_cash(&mut self) {
@@ -223,7 +1108,13 @@ impl<T: DataSource> BacktestBroker for SimulatedBroker<T> {
     fn debit(&mut self, value: &f64) -> BrokerCashEvent {
         match self.ready_state {
             SimulatedBrokerReadyState::Ready => {
-                if value > &self.cash {
+                //Margin accounts can borrow cash up to the excess equity in the account
+                let available = if self.margin.is_some() {
+                    self.get_excess_equity()
+                } else {
+                    self.cash.clone()
+                };
+                if value > &available {
                     info!(
                         "BROKER: Debit failed of {:?} cash, current balance of {:?}",
                         value, self.cash
@@ -268,22 +1159,45 @@ impl<T: DataSource> BacktestBroker for SimulatedBroker<T> {
     }
 
     fn get_cash_balance(&self) -> CashValue {
-        self.cash.clone()
+        let mut value = *self.cash;
+        for (currency, foreign_value) in &self.foreign_cash {
+            //Without a rate, the balance cannot be valued and is excluded
+            if let Some(converted) = self.convert(foreign_value, currency, &self.base_currency) {
+                value += converted;
+            }
+        }
+        CashValue::from(value)
+    }
+
+    fn get_base_currency(&self) -> Currency {
+        self.base_currency
+    }
+
+    fn get_instrument(&self, symbol: &str) -> Option<&Instrument> {
+        self.exchange.get_instrument(symbol)
+    }
+
+    fn is_tradable(&self, symbol: &str) -> bool {
+        self.exchange.is_tradable(symbol)
+    }
+
+    fn get_fx_rate(&self, currency: &Currency) -> Option<f64> {
+        self.convert(&1.0, currency, &self.base_currency)
     }
 
     //This method used to mut because we needed to sort last prices on the broker, this has now
     //been moved to the exchange. The exchange is responsible for storing last prices for cases
     //when a quote is missing.
     fn get_position_value(&self, symbol: &str) -> Option<CashValue> {
-        //TODO: we need to introduce some kind of distinction between short and long
-        //      positions.
-
         if let Some(quote) = self.get_quote(symbol) {
-            //We only have long positions so we only need to look at the bid
-            let price = &quote.bid;
             if let Some(qty) = self.get_position_qty(symbol) {
-                let val = **price * **qty;
-                return Some(CashValue::from(val));
+                //Long positions are closed by selling at the bid, short positions by buying at the
+                //ask
+                let price = if **qty < 0.0 { &quote.ask } else { &quote.bid };
+                if let Some(rate) = self.get_fx_rate(&quote.currency) {
+                    let val = **price * **qty * rate * self.get_multiplier(symbol);
+                    return Some(CashValue::from(val));
+                }
             }
         }
         //This should only occur in cases when the client erroneously asks for a security with no
@@ -296,6 +1210,18 @@ impl<T: DataSource> BacktestBroker for SimulatedBroker<T> {
         self.log.cost_basis(symbol)
     }
 
+    //Calculated from the open lots so that profit agrees with the P&L ledger
+    fn get_position_profit(&self, symbol: &str) -> Option<CashValue> {
+        self.unrealised_pnl(symbol)
+    }
+
+    fn get_initial_margin(&self) -> f64 {
+        match &self.margin {
+            Some(margin) => margin.initial,
+            None => 1.0,
+        }
+    }
+
     fn get_position_qty(&self, symbol: &str) -> Option<&PortfolioQty> {
         self.holdings.get(symbol)
     }
@@ -332,18 +1258,48 @@ impl<T: DataSource> BacktestBroker for SimulatedBroker<T> {
         }
     }
 
+    //Costs are set in the base currency, so the trade is valued in the base currency to calculate
+    //the cost which is then converted back into the currency of the trade
     fn get_trade_costs(&self, trade: &Trade) -> CashValue {
+        let exchange = self
+            .get_instrument(&trade.symbol)
+            .and_then(|instrument| instrument.exchange.as_ref());
+        //Orders can't be sent without a rate and the latest rate is kept, so this should always
+        //find a rate
+        let rate = match self.get_fx_rate(&trade.currency) {
+            Some(rate) => rate,
+            None => {
+                info!(
+                    "BROKER: No fx rate for {:?}, costs calculated in the currency of the trade",
+                    trade.currency
+                );
+                1.0
+            }
+        };
+        let mut base_trade = trade.clone();
+        base_trade.value = CashValue::from(*trade.value * rate);
         let mut cost = CashValue::default();
         for trade_cost in &self.trade_costs {
-            cost = CashValue::from(*cost + *trade_cost.calc(trade));
+            cost = CashValue::from(*cost + *trade_cost.calc(&base_trade, exchange));
         }
-        cost
+        CashValue::from(*cost / rate)
     }
 
-    fn calc_trade_impact(&self, budget: &f64, price: &f64, is_buy: bool) -> (CashValue, Price) {
-        BrokerCost::trade_impact_total(&self.trade_costs, budget, price, is_buy)
+    fn calc_trade_impact(
+        &self,
+        symbol: &str,
+        budget: &f64,
+        price: &f64,
+        is_buy: bool,
+    ) -> (CashValue, Price) {
+        let exchange = self
+            .get_instrument(symbol)
+            .and_then(|instrument| instrument.exchange.as_ref());
+        BrokerCost::trade_impact_total(&self.trade_costs, budget, price, is_buy, exchange)
     }
 
+    //Entitlement is calculated from the position held at the start of the ex-date, payment is
+    //held until the pay date
     fn pay_dividends(&mut self) {
         info!("BROKER: Checking dividends");
         if let Some(dividends) = self.data.get_dividends() {
@@ -355,17 +1311,42 @@ impl<T: DataSource> BacktestBroker for SimulatedBroker<T> {
                         "BROKER: Found dividend of {:?} for portfolio holding {:?}",
                         dividend.value, dividend.symbol
                     );
-                    let cash_value = CashValue::from(*qty.clone() * *dividend.value);
-                    self.credit(&cash_value);
-                    let dividend_paid = DividendPayment::new(
-                        cash_value.clone(),
+                    let gross = *qty.clone() * *dividend.value;
+                    //Dividends are paid in the currency that the security is quoted in
+                    let currency = self
+                        .get_quote(&dividend.symbol)
+                        .map(|quote| quote.currency)
+                        .unwrap_or(self.base_currency);
+                    let withheld = match &self.withholding_tax {
+                        Some(tax) if gross > 0.0 => {
+                            let country = self
+                                .get_instrument(&dividend.symbol)
+                                .and_then(|instrument| instrument.country.as_ref());
+                            gross * tax.rate(&dividend.symbol, country)
+                        }
+                        _ => 0.0,
+                    };
+                    let mut payment = DividendPayment::new_with_currency(
+                        gross - withheld,
                         dividend.symbol.clone(),
-                        dividend.date,
+                        dividend.pay_date,
+                        currency,
                     );
-                    self.log.record(dividend_paid);
+                    payment.withheld = CashValue::from(withheld);
+                    self.pending_dividends.push(payment);
                 }
             }
         }
+
+        let now = self.exchange.now();
+        let (due, pending): (Vec<DividendPayment>, Vec<DividendPayment>) =
+            std::mem::take(&mut self.pending_dividends)
+                .into_iter()
+                .partition(|payment| payment.date <= now);
+        self.pending_dividends = pending;
+        for payment in due {
+            self.pay_dividend(payment);
+        }
     }
 
     fn send_order(&mut self, order: Order) -> BrokerEvent {
@@ -380,51 +1361,17 @@ impl<T: DataSource> BacktestBroker for SimulatedBroker<T> {
                     order.get_symbol()
                 );
 
-                let quote = self.get_quote(&order.get_symbol()).unwrap();
-                let price = match order.get_order_type() {
-                    OrderType::MarketBuy | OrderType::LimitBuy | OrderType::StopBuy => &quote.ask,
-                    OrderType::MarketSell | OrderType::LimitSell | OrderType::StopSell => &quote.bid,
-                };
-
-                if let Err(_err) =
-                    BrokerCalculations::client_has_sufficient_cash(&order, &price, self)
-                {
-                    info!(
-                        "BROKER: Unable to send {:?} order for {:?} shares of {:?} to exchange",
-                        order.get_order_type(),
-                        order.get_shares(),
-                        order.get_symbol()
-                    );
-                    return BrokerEvent::OrderInvalid(order.clone());
-                }
-                if let Err(_err) =
-                    BrokerCalculations::client_has_sufficient_holdings_for_sale(&order, self)
-                {
-                    info!(
-                        "BROKER: Unable to send {:?} order for {:?} shares of {:?} to exchange",
-                        order.get_order_type(),
-                        order.get_shares(),
-                        order.get_symbol()
-                    );
-                    return BrokerEvent::OrderInvalid(order.clone());
-                }
-                if let Err(_err) = BrokerCalculations::client_is_issuing_nonsense_order(&order) {
-                    info!(
-                        "BROKER: Unable to send {:?} order for {:?} shares of {:?} to exchange",
-                        order.get_order_type(),
-                        order.get_shares(),
-                        order.get_symbol()
-                    );
-                    return BrokerEvent::OrderInvalid(order.clone());
+                if !self.validate_order(&order) {
+                    return BrokerEvent::OrderInvalid(order);
                 }
-                self.exchange.insert_order(order.clone());
+                let order_id = self.exchange.insert_order(order.clone());
                 info!(
                     "BROKER: Successfully sent {:?} order for {:?} shares of {:?} to exchange",
                     order.get_order_type(),
                     order.get_shares(),
                     order.get_symbol()
                 );
-                BrokerEvent::OrderSentToExchange(order)
+                BrokerEvent::OrderSentToExchange(order, order_id)
             }
             SimulatedBrokerReadyState::Invalid => {
                 panic!("Tried to send order before calling check");
@@ -442,16 +1389,116 @@ impl<T: DataSource> BacktestBroker for SimulatedBroker<T> {
         res
     }
 
-    fn clear_pending_market_orders_by_symbol(&mut self, symbol: &str) {
-        self.exchange.clear_pending_market_orders_by_symbol(symbol);
+    fn send_oco_orders(&mut self, orders: Vec<Order>) -> Vec<BrokerEvent> {
+        match self.ready_state {
+            SimulatedBrokerReadyState::Ready => {
+                //Group is only sent if every order is valid, as the orders only make sense together
+                if !orders.iter().all(|order| self.validate_order(order)) {
+                    return orders.into_iter().map(BrokerEvent::OrderInvalid).collect();
+                }
+                let order_ids = self.exchange.insert_oco_group(orders.clone());
+                info!(
+                    "BROKER: Sent one-cancels-other group {:?} to exchange",
+                    order_ids
+                );
+                orders
+                    .into_iter()
+                    .zip(order_ids)
+                    .map(|(order, order_id)| BrokerEvent::OrderSentToExchange(order, order_id))
+                    .collect()
+            }
+            SimulatedBrokerReadyState::Invalid => {
+                panic!("Tried to send order before calling check");
+            }
+            SimulatedBrokerReadyState::InsufficientCash => {
+                orders.into_iter().map(BrokerEvent::OrderInvalid).collect()
+            }
+        }
     }
-}
-
-impl<T: DataSource> TransferCash for SimulatedBroker<T> {}
 
-impl<T: DataSource> GetsQuote for SimulatedBroker<T> {
-    fn get_quote(&self, symbol: &str) -> Option<&Quote> {
-        self.exchange.get_quote(symbol)
+    fn send_bracket_order(&mut self, bracket: BracketOrder) -> BrokerEvent {
+        match self.ready_state {
+            SimulatedBrokerReadyState::Ready => {
+                //Exits close a position that doesn't exist yet so can only be checked for sense
+                let exits_valid = [&bracket.take_profit, &bracket.stop_loss]
+                    .iter()
+                    .all(|exit| {
+                        BrokerCalculations::client_is_issuing_nonsense_order(exit, self).is_ok()
+                    });
+                if !exits_valid || !self.validate_order(&bracket.entry) {
+                    return BrokerEvent::OrderInvalid(bracket.entry);
+                }
+                let entry = bracket.entry.clone();
+                let order_id = self.exchange.insert_bracket(bracket);
+                info!(
+                    "BROKER: Sent bracket order {:?} for {:?} shares of {:?} to exchange",
+                    order_id,
+                    entry.get_shares(),
+                    entry.get_symbol()
+                );
+                BrokerEvent::OrderSentToExchange(entry, order_id)
+            }
+            SimulatedBrokerReadyState::Invalid => {
+                panic!("Tried to send order before calling check");
+            }
+            SimulatedBrokerReadyState::InsufficientCash => BrokerEvent::OrderInvalid(bracket.entry),
+        }
+    }
+
+    fn clear_pending_market_orders_by_symbol(&mut self, symbol: &str) {
+        self.exchange.clear_pending_market_orders_by_symbol(symbol);
+    }
+
+    fn cancel_order(&mut self, order_id: OrderId) -> BrokerEvent {
+        if let SimulatedBrokerReadyState::Invalid = self.ready_state {
+            panic!("Tried to cancel order before calling check");
+        }
+        if self.exchange.get_order(&order_id).is_none() {
+            info!("BROKER: Unable to cancel order {:?}, not found", order_id);
+            return BrokerEvent::OrderNotFound(order_id);
+        }
+        self.exchange.delete_order(order_id);
+        info!("BROKER: Cancelled order {:?}", order_id);
+        BrokerEvent::OrderCancelled(order_id)
+    }
+
+    fn modify_order(&mut self, order_id: OrderId, order: Order) -> BrokerEvent {
+        match self.ready_state {
+            SimulatedBrokerReadyState::Ready => {
+                if self.exchange.get_order(&order_id).is_none() {
+                    info!("BROKER: Unable to modify order {:?}, not found", order_id);
+                    return BrokerEvent::OrderNotFound(order_id);
+                }
+                if !self.validate_order(&order) {
+                    return BrokerEvent::OrderInvalid(order);
+                }
+                self.exchange.modify_order(order_id, order.clone());
+                info!(
+                    "BROKER: Modified order {:?} to {:?} order for {:?} shares of {:?}",
+                    order_id,
+                    order.get_order_type(),
+                    order.get_shares(),
+                    order.get_symbol()
+                );
+                BrokerEvent::OrderModified(order, order_id)
+            }
+            SimulatedBrokerReadyState::Invalid => {
+                panic!("Tried to modify order before calling check");
+            }
+            SimulatedBrokerReadyState::InsufficientCash => BrokerEvent::OrderInvalid(order),
+        }
+    }
+
+    fn get_order_status(&self, order_id: &OrderId) -> Option<OrderStatus> {
+        self.exchange.get_order_status(order_id).cloned()
+    }
+}
+
+impl<T: DataSource> TransferCash for SimulatedBroker<T> {}
+
+impl<T: DataSource> GetsQuote for SimulatedBroker<T> {
+    fn get_quote(&self, symbol: &str) -> Option<&Quote> {
+        self.exchange.get_quote(symbol)
     }
 
     fn get_quotes(&self) -> Option<&Vec<Quote>> {
@@ -467,6 +1514,24 @@ impl<T: DataSource> EventLog for SimulatedBroker<T> {
     fn dividends_between(&self, start: &i64, end: &i64) -> Vec<DividendPayment> {
         self.log.dividends_between(start, end)
     }
+
+    fn realised_gains_between(&self, start: &i64, end: &i64) -> Vec<RealisedGain> {
+        self.lots
+            .realised()
+            .into_iter()
+            .filter(|v| v.disposed >= DateTime::from(*start) && v.disposed <= DateTime::from(*end))
+            .collect()
+    }
+
+    fn wash_sales_between(&self, start: &i64, end: &i64) -> Vec<WashSale> {
+        self.log
+            .wash_sales_between(self.lot_method.clone(), start, end)
+    }
+
+    fn adjusted_gains_between(&self, start: &i64, end: &i64) -> Vec<RealisedGain> {
+        self.log
+            .adjusted_gains_between(self.lot_method.clone(), start, end)
+    }
 }
 
 ///Represents whether the broker is in a state from which we can begin to modify state and issue
@@ -493,15 +1558,20 @@ enum SimulatedBrokerReadyState {
 mod tests {
 
     use super::{SimulatedBroker, SimulatedBrokerBuilder};
+    use crate::broker::lots::LotMethod;
+    use crate::broker::pnl::PnlSource;
     use crate::broker::{
-        BacktestBroker, BrokerCashEvent, BrokerCost, BrokerEvent, Dividend, Quote, TransferCash,
+        AccountFee, BacktestBroker, Bar, BracketOrder, BrokerCalculations, BrokerCashEvent,
+        BrokerCost, BrokerEvent, CashInterest, CashMerger, CorporateAction, Delisting, Dividend,
+        DividendReinvestment, EventLog, FxRate, Instrument, InterestRate, Margin, Quote, SpinOff,
+        StockMerger, TransferCash, WithholdingTax,
     };
-    use crate::broker::{Order, OrderType};
+    use crate::broker::{Order, OrderId, OrderStatus, OrderType, Split, TimeInForce, Trail};
     use crate::clock::{Clock, ClockBuilder};
     use crate::exchange::DefaultExchangeBuilder;
     use crate::input::{HashMapInput, HashMapInputBuilder};
-    use crate::types::{DateTime, Frequency};
-  
+    use crate::types::{Currency, DateTime, Frequency, PortfolioAllocation};
+
     use std::collections::HashMap;
     use std::rc::Rc;
 
@@ -548,6 +1618,49 @@ mod tests {
         (brkr, clock)
     }
 
+    //Clock ticks every second from 100. Once any instruments are given, symbols without an
+    //instrument can't be traded. Options are set by the test.
+    fn setup_builder(
+        source: &mut HashMapInputBuilder,
+        length: i64,
+        instruments: Vec<Instrument>,
+    ) -> (SimulatedBrokerBuilder<HashMapInput>, Clock) {
+        let clock = ClockBuilder::with_length_in_seconds(100, length)
+            .with_frequency(&Frequency::Second)
+            .build();
+
+        let source = source.with_clock(Rc::clone(&clock)).build();
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source.clone())
+            .with_instruments(instruments)
+            .build();
+
+        let mut builder = SimulatedBrokerBuilder::new();
+        builder.with_data(source).with_exchange(exchange);
+        (builder, clock)
+    }
+
+    //Single symbol with prices that rise and then fall, options are set by the test
+    fn setup_abc(
+        dividends: HashMap<DateTime, Vec<Dividend>>,
+    ) -> (SimulatedBrokerBuilder<HashMapInput>, Clock) {
+        let mut prices: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        prices.insert(100.into(), vec![Quote::new(100.00, 101.00, 100, "ABC")]);
+        prices.insert(101.into(), vec![Quote::new(104.00, 105.00, 101, "ABC")]);
+        prices.insert(102.into(), vec![Quote::new(95.00, 96.00, 102, "ABC")]);
+        prices.insert(103.into(), vec![Quote::new(95.00, 96.00, 103, "ABC")]);
+
+        setup_builder(
+            HashMapInputBuilder::new()
+                .with_quotes(prices)
+                .with_dividends(dividends),
+            5,
+            Vec::new(),
+        )
+    }
+
     #[test]
     fn test_cash_deposit_withdraw() {
         let (mut brkr, clock) = setup();
@@ -603,6 +1716,57 @@ mod tests {
         assert_eq!(*qty.clone(), 495.00);
     }
 
+    #[test]
+    fn test_that_trade_costs_are_deducted_when_trades_settle() {
+        let (mut builder, clock) = setup_abc(HashMap::new());
+        let mut brkr = builder
+            .with_trade_costs(vec![BrokerCost::flat(1.0), BrokerCost::buy_tax(0.005)])
+            .build();
+
+        brkr.deposit_cash(&100_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 100.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 100.0));
+        brkr.finish();
+        //Buy of 10_500 pays the flat cost and tax
+        assert_eq!(*brkr.get_cash_balance(), 89_446.5);
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        //Sale of 9_500 only pays the flat cost
+        assert_eq!(*brkr.get_cash_balance(), 98_945.5);
+
+        let trades = brkr.trades_between(&100, &102);
+        assert_eq!(*trades[0].costs, 53.5);
+        assert_eq!(*trades[1].costs, 1.0);
+    }
+
+    #[test]
+    fn test_that_order_for_all_cash_is_rejected_once_costs_are_included() {
+        //Ask is 101 so ten shares cost exactly the cash held
+        let (builder, _clock) = setup_abc(HashMap::new());
+        let mut brkr = builder.build();
+        brkr.deposit_cash(&1010.0);
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+
+        let (mut builder, _clock) = setup_abc(HashMap::new());
+        let mut brkr = builder
+            .with_trade_costs(vec![BrokerCost::flat(1.0)])
+            .build();
+        brkr.deposit_cash(&1010.0);
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+
+        brkr.deposit_cash(&1.0);
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+    }
+
     #[test]
     fn test_that_buy_order_larger_than_cash_fails_with_error_returned_without_panic() {
         let (mut brkr, clock) = setup();
@@ -708,8 +1872,9 @@ mod tests {
         brkr.check();
         brkr.finish();
 
+        //Profit is net of the trade cost paid to open the position
         let profit = brkr.get_position_profit("ABC").unwrap();
-        assert_eq!(*profit, -4950.00);
+        assert_eq!(*profit, -4951.00);
     }
 
     #[test]
@@ -731,6 +1896,367 @@ mod tests {
         assert_ne!(cash_before_dividend, cash_after_dividend);
     }
 
+    #[test]
+    fn test_that_dividends_are_reinvested_on_next_tick() {
+        let mut dividends: HashMap<DateTime, Vec<Dividend>> = HashMap::new();
+        dividends.insert(102.into(), vec![Dividend::new(5.0, "ABC", 102)]);
+
+        let (mut builder, clock) = setup_abc(dividends);
+        let mut brkr = builder
+            .with_dividend_reinvestment(DividendReinvestment::Symbols(vec!["ABC".to_string()]))
+            .build();
+
+        brkr.deposit_cash(&11_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 100.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert_eq!(*brkr.get_cash_balance(), 500.0);
+
+        //Dividend of 500 is paid, reinvestment is sent but cannot execute until the next tick
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert_eq!(*brkr.get_cash_balance(), 1000.0);
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 100.0);
+        assert!(brkr.dividend_reinvestments().is_empty());
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        //5 shares at 96, remainder is held in cash
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 105.0);
+        assert_eq!(*brkr.get_cash_balance(), 520.0);
+
+        let reinvestments = brkr.dividend_reinvestments();
+        assert_eq!(reinvestments.len(), 1);
+        assert_eq!(*reinvestments[0].quantity, 5.0);
+        //Reinvestment is included in trades and cost basis
+        assert_eq!(brkr.trades_between(&100, &103).len(), 2);
+        assert_eq!(*brkr.cost_basis("ABC").unwrap(), 10_980.0 / 105.0);
+    }
+
+    #[test]
+    fn test_that_dividends_are_paid_on_pay_date_net_of_withholding_tax() {
+        let mut prices: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        for date in 100..104 {
+            prices.insert(
+                date.into(),
+                vec![
+                    Quote::new(100.00, 100.00, date, "ABC"),
+                    Quote::new(10.00, 10.00, date, "BCD"),
+                ],
+            );
+        }
+
+        //ABC is paid the tick after the ex-date, BCD is paid on the ex-date
+        let mut dividends: HashMap<DateTime, Vec<Dividend>> = HashMap::new();
+        dividends.insert(
+            102.into(),
+            vec![
+                Dividend::new_with_pay_date(1.0, "ABC", 102, 103),
+                Dividend::new(1.0, "BCD", 102),
+            ],
+        );
+
+        let mut abc = Instrument::new("ABC");
+        abc.country = Some("US".to_string());
+        //No country so is taxed at the default rate
+        let bcd = Instrument::new("BCD");
+
+        let mut tax = WithholdingTax::new(0.1);
+        tax.countries.insert("US".to_string(), 0.3);
+
+        let (mut builder, clock) = setup_builder(
+            HashMapInputBuilder::new()
+                .with_quotes(prices)
+                .with_dividends(dividends),
+            5,
+            vec![abc, bcd],
+        );
+        let mut brkr = builder.with_withholding_tax(tax).build();
+
+        brkr.deposit_cash(&11_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 100.0));
+        brkr.send_order(Order::market(OrderType::MarketBuy, "BCD", 100.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        //Sale executes on the ex-date so the position is still entitled to the dividend
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 100.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        let dividends = brkr.dividends_between(&100, &102);
+        assert_eq!(dividends.len(), 1);
+        assert_eq!(dividends[0].symbol, "BCD");
+        assert_eq!(*dividends[0].value, 90.0);
+        assert_eq!(*dividends[0].withheld, 10.0);
+        assert_eq!(*dividends[0].gross(), 100.0);
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        let dividends = brkr.dividends_between(&103, &103);
+        assert_eq!(dividends.len(), 1);
+        assert_eq!(dividends[0].symbol, "ABC");
+        assert_eq!(*dividends[0].value, 70.0);
+        assert_eq!(*dividends[0].withheld, 30.0);
+        assert_eq!(*brkr.get_cash_balance(), 10_160.0);
+    }
+
+    //Runs daily from the 1st January, without prices, so that periodic cash flows can be tested
+    fn setup_daily(
+        interest_rates: HashMap<DateTime, Vec<InterestRate>>,
+    ) -> (SimulatedBrokerBuilder<HashMapInput>, Clock) {
+        let clock = ClockBuilder::with_length_in_days(0, 35)
+            .with_frequency(&Frequency::Daily)
+            .build();
+
+        let source = HashMapInputBuilder::new()
+            .with_quotes(HashMap::new())
+            .with_interest_rates(interest_rates)
+            .with_clock(Rc::clone(&clock))
+            .build();
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source.clone())
+            .build();
+
+        let mut builder = SimulatedBrokerBuilder::new();
+        builder.with_data(source).with_exchange(exchange);
+        (builder, clock)
+    }
+
+    #[test]
+    fn test_that_cash_interest_is_accrued_daily_and_credited_monthly() {
+        let (mut builder, clock) = setup_daily(HashMap::new());
+        let mut brkr = builder
+            .with_cash_interest(CashInterest::fixed(0.1, Frequency::Monthly))
+            .build();
+        brkr.deposit_cash(&36_500.0);
+        brkr.finish();
+
+        //Clock starts on the 1st January, interest is accrued each day but credited in February
+        for _ in 0..30 {
+            clock.borrow_mut().tick();
+            brkr.check();
+            brkr.finish();
+        }
+        assert!(brkr.interest_paid().is_empty());
+        assert_eq!(*brkr.get_cash_balance(), 36_500.0);
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        let paid = brkr.interest_paid();
+        assert_eq!(paid.len(), 1);
+        assert!((*paid[0].value - 310.0).abs() < 1e-6);
+        assert!((*brkr.get_cash_balance() - 36_810.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_that_cash_interest_uses_rate_in_effect_from_source() {
+        let mut rates: HashMap<DateTime, Vec<InterestRate>> = HashMap::new();
+        rates.insert(0.into(), vec![InterestRate::new(Currency::USD, 0.365, 0)]);
+        rates.insert(
+            86_400.into(),
+            vec![InterestRate::new(Currency::USD, 0.73, 86_400)],
+        );
+        let (mut builder, clock) = setup_daily(rates);
+        let mut brkr = builder
+            .with_cash_interest(CashInterest::from_data_source(Frequency::Daily))
+            .build();
+        brkr.deposit_cash(&1_000.0);
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert!((*brkr.get_cash_balance() - 1_001.0).abs() < 1e-6);
+
+        //New rate is paid on the balance including interest already credited
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert!((*brkr.get_cash_balance() - 1_003.002).abs() < 1e-6);
+        assert_eq!(brkr.interest_paid().len(), 2);
+    }
+
+    #[test]
+    fn test_that_account_fees_are_charged_at_end_of_period() {
+        let (mut builder, clock) = setup_daily(HashMap::new());
+        let mut brkr = builder
+            .with_account_fees(vec![
+                AccountFee::pct_of_value(0.0365, Frequency::Monthly),
+                AccountFee::flat(5.0, Frequency::Monthly),
+                AccountFee::capped_pct_of_value(0.0365, 100.0, Frequency::Monthly),
+            ])
+            .build();
+        brkr.deposit_cash(&100_000.0);
+        brkr.finish();
+
+        for _ in 0..30 {
+            clock.borrow_mut().tick();
+            brkr.check();
+            brkr.finish();
+        }
+        assert!(brkr.fees().is_empty());
+
+        //Percentage fees of 10 a day are accrued for 31 days in January, one is capped
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        let fees = brkr.fees();
+        assert_eq!(fees.len(), 3);
+        assert!((*fees[0].value - 310.0).abs() < 1e-6);
+        assert_eq!(*fees[1].value, 5.0);
+        assert_eq!(*fees[2].value, 100.0);
+        assert!((*brkr.total_fees() - 415.0).abs() < 1e-6);
+        assert!((*brkr.get_cash_balance() - 99_585.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_that_selected_lots_are_relieved_first() {
+        let (mut builder, clock) = setup_abc(HashMap::new());
+        let mut brkr = builder
+            .with_trade_costs(vec![BrokerCost::flat(1.0)])
+            .with_lot_method(LotMethod::Specific)
+            .build();
+        brkr.deposit_cash(&100_000.0);
+
+        //Lots are bought at 105 and 96, the second lot is selected so is relieved before the first
+        for _ in 0..2 {
+            brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+            brkr.finish();
+            clock.borrow_mut().tick();
+            brkr.check();
+        }
+        let lots = brkr.open_lots("ABC");
+        assert_eq!(lots.len(), 2);
+        assert_eq!(*lots[0].price(), 105.1);
+
+        brkr.select_lots("ABC", vec![lots[1].id]);
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 15.0));
+        brkr.finish();
+        clock.borrow_mut().tick();
+        brkr.check();
+
+        let gains = brkr.realised_gains();
+        assert_eq!(gains.len(), 2);
+        assert_eq!(gains[0].lot_id, lots[1].id);
+        assert!((*gains[0].gain() - (10.0 * (95.0 - 96.1) - 1.0 * 10.0 / 15.0)).abs() < 1e-6);
+        assert_eq!(gains[0].holding_period(), 1);
+        assert_eq!(*gains[1].quantity, 5.0);
+
+        let open = brkr.open_lots("ABC");
+        assert_eq!(open.len(), 1);
+        assert_eq!(*open[0].quantity, 5.0);
+        assert_eq!(open[0].date, lots[0].date);
+        assert_eq!(brkr.realised_gains_between(&103, &103).len(), 2);
+    }
+
+    #[test]
+    fn test_that_pnl_is_split_into_realised_and_unrealised() {
+        let (mut brkr, clock) = setup();
+        brkr.deposit_cash(&100_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.finish();
+
+        //Lot costs 1051 including the trade cost, and is valued at the bid of 104
+        clock.borrow_mut().tick();
+        brkr.check();
+        assert!((*brkr.unrealised_pnl("ABC").unwrap() + 11.0).abs() < 1e-6);
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 5.0));
+        brkr.finish();
+
+        //Half the lot is sold at 95, and a dividend of 5 a share is paid on ten shares
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        let ledger = brkr.pnl_ledger();
+        assert_eq!(ledger.entries().len(), 2);
+        let usd = Currency::default();
+        let realised = ledger.source_total(&PnlSource::RealisedGain, &102.into());
+        assert!((*realised[&usd] + 51.5).abs() < 1e-6);
+        assert_eq!(
+            *ledger.source_total(&PnlSource::Dividend, &102.into())[&usd],
+            50.0
+        );
+        assert!(ledger.total(&101.into()).is_empty());
+        assert!((*brkr.unrealised_pnl("ABC").unwrap() + 50.5).abs() < 1e-6);
+        assert!((*brkr.position_pnl("ABC") + 52.0).abs() < 1e-6);
+        assert!((*brkr.total_pnl() + 52.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_that_wash_sale_adjusts_gains_reported_through_audit() {
+        let (builder, clock) = setup_abc(HashMap::new());
+        let mut brkr = builder.build();
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //Shares bought at 105 and sold at 95 are bought back straight away, so the loss of 100 is
+        //only disallowed in the adjusted gains
+        assert_eq!(brkr.wash_sales_between(&102, &102).len(), 1);
+        assert_eq!(*brkr.realised_gains_between(&102, &102)[0].gain(), -100.0);
+        assert_eq!(*brkr.adjusted_gains_between(&102, &102)[0].gain(), 0.0);
+        assert_eq!(*brkr.open_lots("ABC")[0].cost, 960.0);
+        assert_eq!(*brkr.wash_sales().open_lots("ABC")[0].cost, 1060.0);
+    }
+
+    #[test]
+    fn test_that_realised_pnl_is_available_at_past_dates() {
+        let (mut brkr, clock) = setup();
+        brkr.deposit_cash(&100_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 5.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 5.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //Sale on the last tick doesn't change the profit realised at earlier dates
+        let usd = Currency::default();
+        let ledger = brkr.pnl_ledger();
+        assert!(ledger.symbol_total("ABC", &101.into()).is_empty());
+        assert!((*ledger.symbol_total("ABC", &102.into())[&usd] + 1.5).abs() < 1e-6);
+        assert!((*ledger.symbol_total("ABC", &103.into())[&usd] + 53.0).abs() < 1e-6);
+        assert!(brkr.unrealised_pnl("ABC").is_none());
+        assert!((*brkr.position_pnl("ABC") + 53.0).abs() < 1e-6);
+    }
+
     #[test]
     #[should_panic]
     fn test_that_broker_builder_fails_without_exchange() {
@@ -928,5 +2454,890 @@ mod tests {
         assert!(*cash1 > 0.0);
     }
 
-    
+    fn setup_margin(instruments: Vec<Instrument>) -> (SimulatedBroker<HashMapInput>, Clock) {
+        let mut prices: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        let clock = ClockBuilder::with_length_in_days(0, 4)
+            .with_frequency(&Frequency::Daily)
+            .build();
+
+        let bids = vec![100.0, 100.0, 100.0, 60.0, 60.0];
+        for (date, bid) in clock.borrow().peek().zip(bids) {
+            prices.insert(date.clone(), vec![Quote::new(bid, bid, date, "ABC")]);
+        }
+
+        let source = HashMapInputBuilder::new()
+            .with_quotes(prices)
+            .with_clock(Rc::clone(&clock))
+            .build();
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source.clone())
+            .with_instruments(instruments)
+            .build();
+
+        let brkr = SimulatedBrokerBuilder::new()
+            .with_data(source)
+            .with_exchange(exchange)
+            .with_margin(Margin::new(0.5, 0.3, 0.1))
+            .build();
+        (brkr, clock)
+    }
+
+    #[test]
+    fn test_that_margin_account_can_open_levered_position() {
+        let (mut brkr, clock) = setup_margin(Vec::new());
+        brkr.deposit_cash(&10_000.0);
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 190.0));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+        //Exceeds twice the equity in the account
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 210.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //Negative cash balance is a loan so isn't rebalanced
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 190.0);
+        assert_eq!(*brkr.get_cash_balance(), -9_000.0);
+        assert_eq!(*brkr.get_buying_power(), 1_000.0);
+    }
+
+    #[test]
+    fn test_that_margin_loan_is_charged_interest() {
+        let (mut brkr, clock) = setup_margin(Vec::new());
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 190.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //One day of interest at 10% on the loan
+        let interest = 9_000.0 * 0.1 * (86_400.0 / 31_536_000.0);
+        let cash = brkr.get_cash_balance();
+        assert!((*cash + 9_000.0 + interest).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_that_margin_interest_is_charged_from_build_date() {
+        let (mut brkr, clock) = setup_margin(Vec::new());
+        //Loan is taken before the first check, so interest is due for the first day
+        brkr.debit_force(&9_000.0);
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        let interest = 9_000.0 * 0.1 * (86_400.0 / 31_536_000.0);
+        assert!((*brkr.get_cash_balance() + 9_000.0 + interest).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_that_margin_call_liquidates_positions() {
+        let (mut brkr, clock) = setup_margin(Vec::new());
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 190.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert!(brkr.margin_calls().is_empty());
+
+        //Price falls by 40%, equity is now below the maintenance margin
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert_eq!(brkr.margin_calls().len(), 1);
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //Position reduced so that the margin is back above the initial margin
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 79.0);
+        let equity = brkr.get_total_value();
+        let exposure = brkr.get_gross_exposure();
+        assert!(*equity / *exposure >= 0.5);
+    }
+
+    #[test]
+    fn test_that_margin_call_liquidates_in_whole_lots() {
+        let mut abc = Instrument::new("ABC");
+        abc.lot_size = 10.0;
+        let (mut brkr, clock) = setup_margin(vec![abc]);
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 190.0));
+        brkr.finish();
+
+        for _ in 0..4 {
+            clock.borrow_mut().tick();
+            brkr.check();
+            brkr.finish();
+        }
+
+        //Sale of 111 shares is rounded up to 120 so the remaining position is still whole lots
+        assert_eq!(brkr.margin_calls().len(), 1);
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 70.0);
+    }
+
+    #[test]
+    fn test_that_margin_call_does_not_liquidate_untradable_positions() {
+        let mut abc = Instrument::new("ABC");
+        abc.delisted = Some((86400 * 3).into());
+        let (mut brkr, clock) = setup_margin(vec![abc]);
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 190.0));
+        brkr.finish();
+
+        for _ in 0..4 {
+            clock.borrow_mut().tick();
+            brkr.check();
+            brkr.finish();
+        }
+
+        //Margin call is made on each check but the exchange can't sell a delisted instrument
+        assert_eq!(brkr.margin_calls().len(), 2);
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 190.0);
+    }
+
+    #[test]
+    fn test_that_short_sale_creates_negative_holding_and_increases_cash() {
+        let (mut builder, clock) = setup_abc(HashMap::new());
+        let mut brkr = builder.with_short_selling(true).build();
+        brkr.deposit_cash(&100_000.0);
+        let res = brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 100.0));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        let qty = brkr.get_position_qty("ABC").unwrap();
+        assert_eq!(**qty, -100.0);
+        //Sold at the bid of 104
+        assert_eq!(*brkr.get_cash_balance(), 110_400.0);
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //Short position is valued at the ask, and the total value includes the liability
+        let value = brkr.get_position_value("ABC").unwrap();
+        assert_eq!(*value, -9_600.0);
+        assert_eq!(*brkr.get_total_value(), 100_800.0);
+        let profit = brkr.get_position_profit("ABC").unwrap();
+        assert_eq!(*profit, 800.0);
+    }
+
+    #[test]
+    fn test_that_short_sale_without_sufficient_collateral_fails() {
+        let (mut builder, clock) = setup_abc(HashMap::new());
+        let mut brkr = builder.with_short_selling(true).build();
+        brkr.deposit_cash(&1_000.0);
+        let res = brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 100.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        assert!(brkr.get_position_qty("ABC").is_none());
+    }
+
+    #[test]
+    fn test_that_cash_held_as_collateral_cannot_be_withdrawn_or_spent() {
+        let (mut builder, clock) = setup_abc(HashMap::new());
+        let mut brkr = builder.with_short_selling(true).build();
+        brkr.deposit_cash(&20_000.0);
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 100.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+
+        //Cash is 30_400 but 21_000 is held against the short position
+        assert_eq!(*brkr.get_buying_power(), 9_400.0);
+        assert!(matches!(
+            brkr.withdraw_cash(&10_000.0),
+            BrokerCashEvent::WithdrawFailure(..)
+        ));
+        let res = brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 100.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+        //Covering the short position doesn't require any free cash
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 100.0));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        assert!(brkr.get_position_qty("ABC").is_none());
+    }
+
+    fn setup_fx() -> (SimulatedBroker<HashMapInput>, Clock) {
+        let (builder, clock) = setup_fx_prices(vec![100.0; 5], vec![0.8, 0.8, 0.5, 0.5, 0.5]);
+        (builder.build(), clock)
+    }
+
+    //ABC is quoted in USD and BCD in GBP, which is the base currency, with a price and rate for
+    //each of the five ticks
+    fn setup_fx_prices(
+        abc: Vec<f64>,
+        rates: Vec<f64>,
+    ) -> (SimulatedBrokerBuilder<HashMapInput>, Clock) {
+        let mut prices: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        let mut fx_rates: HashMap<DateTime, Vec<FxRate>> = HashMap::new();
+        for (date, (price, rate)) in (100..105).zip(abc.into_iter().zip(rates)) {
+            prices.insert(
+                date.into(),
+                vec![
+                    Quote::new_with_currency(price, price, date, "ABC", Currency::USD),
+                    Quote::new_with_currency(10.0, 10.0, date, "BCD", Currency::GBP),
+                ],
+            );
+            fx_rates.insert(
+                date.into(),
+                vec![FxRate::new(Currency::USD, Currency::GBP, rate, date)],
+            );
+        }
+
+        let (mut builder, clock) = setup_builder(
+            HashMapInputBuilder::new()
+                .with_quotes(prices)
+                .with_fx_rates(fx_rates),
+            5,
+            Vec::new(),
+        );
+        builder.with_base_currency(Currency::GBP);
+        (builder, clock)
+    }
+
+    #[test]
+    fn test_that_foreign_position_is_valued_in_base_currency() {
+        let (mut brkr, clock) = setup_fx();
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.send_order(Order::market(OrderType::MarketBuy, "BCD", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //USD purchase is funded from GBP at 0.8 so no USD balance is left
+        assert_eq!(*brkr.get_cash_balance_in(&Currency::USD), 0.0);
+        assert_eq!(*brkr.get_cash_balance(), 10_000.0 - 800.0 - 100.0);
+        assert_eq!(*brkr.get_position_value("ABC").unwrap(), 800.0);
+        assert_eq!(*brkr.get_position_value("BCD").unwrap(), 100.0);
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //Price of the asset hasn't changed but the value to a GBP investor has
+        assert_eq!(*brkr.get_position_value("ABC").unwrap(), 500.0);
+        assert_eq!(*brkr.get_position_profit("ABC").unwrap(), 0.0);
+        assert_eq!(*brkr.get_total_value(), 10_000.0 - 900.0 + 600.0);
+    }
+
+    #[test]
+    fn test_that_costs_on_foreign_trade_are_converted_from_base_currency() {
+        let (mut builder, clock) = setup_fx_prices(vec![100.0; 5], vec![0.8; 5]);
+        let mut brkr = builder
+            .with_trade_costs(vec![BrokerCost::flat(8.0)])
+            .build();
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //Flat cost of 8 GBP is charged as 10 USD with the trade
+        let trades = brkr.trades_between(&100, &101);
+        assert_eq!(*trades[0].costs, 10.0);
+        assert_eq!(*brkr.get_cash_balance_in(&Currency::USD), 0.0);
+        assert_eq!(*brkr.get_cash_balance(), 10_000.0 - 808.0);
+    }
+
+    #[test]
+    fn test_that_foreign_realised_pnl_is_converted_into_base_currency() {
+        let (builder, clock) =
+            setup_fx_prices(vec![100.0, 100.0, 120.0, 120.0, 120.0], vec![0.5; 5]);
+        let mut brkr = builder.build();
+
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 5.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        //Gain of 100 USD is realised on the sale and 100 USD is unrealised on the shares held
+        let ledger = brkr.pnl_ledger();
+        assert_eq!(*ledger.total(&103.into())[&Currency::USD], 100.0);
+        assert_eq!(*brkr.unrealised_pnl("ABC").unwrap(), 50.0);
+        assert_eq!(*brkr.position_pnl("ABC"), 100.0);
+        assert_eq!(*brkr.total_pnl(), 100.0);
+        //Disposals are reported in sterling at the rate on the date of each trade
+        let disposals = brkr.uk_capital_gains().disposals();
+        assert_eq!(*disposals[0].gain(), 50.0);
+    }
+
+    #[test]
+    fn test_that_sale_of_foreign_position_is_held_in_foreign_currency() {
+        let (mut brkr, clock) = setup_fx();
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+
+        assert_eq!(*brkr.get_cash_balance_in(&Currency::USD), 1_000.0);
+        assert_eq!(*brkr.get_cash_balance_in(&Currency::GBP), 9_200.0);
+        assert_eq!(*brkr.get_cash_balance(), 9_700.0);
+
+        let res = brkr.convert_cash(&Currency::USD, &Currency::GBP, &1_000.0);
+        assert!(matches!(res, BrokerCashEvent::ConversionSuccess(value) if *value == 500.0));
+        assert_eq!(*brkr.get_cash_balance_in(&Currency::USD), 0.0);
+        assert_eq!(*brkr.get_cash_balance_in(&Currency::GBP), 9_700.0);
+
+        let res = brkr.convert_cash(&Currency::USD, &Currency::GBP, &1.0);
+        assert!(matches!(res, BrokerCashEvent::ConversionFailure(..)));
+    }
+
+    #[test]
+    fn test_that_fx_rate_is_available_before_first_check() {
+        let (brkr, _clock) = setup_fx();
+        assert_eq!(brkr.get_fx_rate(&Currency::EUR), None);
+        assert_eq!(brkr.get_fx_rate(&Currency::USD), Some(0.8));
+        assert_eq!(brkr.get_fx_rate(&Currency::GBP), Some(1.0));
+    }
+
+    #[test]
+    fn test_that_broker_can_cancel_and_modify_resting_orders() {
+        let (mut brkr, clock) = setup();
+        brkr.deposit_cash(&100_000.0);
+
+        let res = brkr.send_order(Order::delayed(OrderType::StopBuy, "ABC", 10.0, 200.0));
+        let cancelled = match res {
+            BrokerEvent::OrderSentToExchange(_order, order_id) => order_id,
+            _ => panic!("Order should have been sent"),
+        };
+        let res = brkr.send_order(Order::delayed(OrderType::StopBuy, "ABC", 10.0, 200.0));
+        let modified = match res {
+            BrokerEvent::OrderSentToExchange(_order, order_id) => order_id,
+            _ => panic!("Order should have been sent"),
+        };
+        assert_eq!(
+            brkr.get_order_status(&cancelled),
+            Some(OrderStatus::Pending)
+        );
+
+        let res = brkr.cancel_order(cancelled);
+        assert!(matches!(res, BrokerEvent::OrderCancelled(..)));
+        assert_eq!(
+            brkr.get_order_status(&cancelled),
+            Some(OrderStatus::Cancelled)
+        );
+        let res = brkr.cancel_order(cancelled);
+        assert!(matches!(res, BrokerEvent::OrderNotFound(..)));
+
+        let res = brkr.modify_order(
+            modified,
+            Order::delayed(OrderType::StopBuy, "ABC", 10.0, 102.0),
+        );
+        assert!(matches!(res, BrokerEvent::OrderModified(..)));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+
+        assert_eq!(brkr.get_order_status(&modified), Some(OrderStatus::Filled));
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 10.0);
+        let res = brkr.modify_order(
+            modified,
+            Order::delayed(OrderType::StopBuy, "ABC", 10.0, 102.0),
+        );
+        assert!(matches!(res, BrokerEvent::OrderNotFound(..)));
+    }
+
+    #[test]
+    fn test_that_expired_orders_are_recorded_by_broker() {
+        let (mut brkr, clock) = setup();
+        brkr.deposit_cash(&100_000.0);
+
+        let mut order = Order::delayed(OrderType::StopBuy, "ABC", 10.0, 200.0);
+        order.set_time_in_force(TimeInForce::ImmediateOrCancel);
+        brkr.send_order(order);
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+
+        let expired = brkr.expired_orders();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(**expired.first().unwrap().order.get_shares(), 10.0);
+        assert!(brkr.get_position_qty("ABC").is_none());
+    }
+
+    #[test]
+    fn test_that_trailing_stop_without_valid_trail_is_invalid() {
+        let (mut brkr, _clock) = setup();
+        brkr.deposit_cash(&100_000.0);
+
+        let res = brkr.send_order(Order::trailing(
+            OrderType::TrailingStopBuy,
+            "ABC",
+            10.0,
+            Trail::Percentage(1.5),
+        ));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+
+        let res = brkr.send_order(Order::trailing(
+            OrderType::TrailingStopBuy,
+            "ABC",
+            10.0,
+            Trail::Percentage(0.05),
+        ));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+    }
+
+    #[test]
+    fn test_that_bracket_order_opens_and_closes_position() {
+        let (mut brkr, clock) = setup();
+        brkr.deposit_cash(&100_000.0);
+
+        //Price falls through the stop-loss on the second tick
+        let bracket = BracketOrder::new(
+            Order::market(OrderType::MarketBuy, "ABC", 100.0),
+            200.0,
+            100.0,
+        );
+        let res = brkr.send_bracket_order(bracket);
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 100.0);
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert!(brkr.get_position_qty("ABC").is_none());
+        assert_eq!(brkr.trades_between(&0, &200).len(), 2);
+    }
+
+    #[test]
+    fn test_that_oco_orders_are_not_sent_if_any_order_is_invalid() {
+        let (mut brkr, _clock) = setup();
+        brkr.deposit_cash(&100_000.0);
+
+        let res = brkr.send_oco_orders(vec![
+            Order::delayed(OrderType::StopBuy, "ABC", 10.0, 200.0),
+            Order::delayed(OrderType::StopBuy, "ABC", 0.0, 200.0),
+        ]);
+        assert!(res
+            .iter()
+            .all(|event| matches!(event, BrokerEvent::OrderInvalid(..))));
+    }
+
+    #[test]
+    fn test_that_broker_can_trade_and_value_with_bars() {
+        let mut bars: HashMap<DateTime, Vec<Bar>> = HashMap::new();
+        let prices = vec![(100.0, 101.0), (102.0, 104.0), (105.0, 103.0)];
+        for (date, (open, close)) in (100..103).zip(prices) {
+            bars.insert(
+                date.into(),
+                vec![Bar::new(open, 110.0, 90.0, close, date, "ABC")],
+            );
+        }
+
+        let (builder, clock) =
+            setup_builder(HashMapInputBuilder::new().with_bars(bars), 3, Vec::new());
+        let mut brkr = builder.build();
+
+        brkr.deposit_cash(&10_000.0);
+        //Broker has no price until the exchange has seen the first bar
+        let res = brkr.send_order(Order::market(OrderType::MarketOnOpenBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+        brkr.finish();
+
+        brkr.check();
+        let res = brkr.send_order(Order::market(OrderType::MarketOnOpenBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+
+        assert_eq!(*brkr.get_cash_balance(), 10_000.0 - 1020.0);
+        assert_eq!(*brkr.get_position_value("ABC").unwrap(), 1040.0);
+    }
+
+    #[test]
+    fn test_that_auction_orders_are_rejected_without_bars() {
+        let (mut brkr, _clock) = setup();
+        brkr.deposit_cash(&100_000.0);
+
+        let res = brkr.send_order(Order::market(OrderType::MarketOnOpenBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+        let res = brkr.send_order(Order::market(OrderType::MarketOnCloseBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+    }
+
+    fn setup_instruments() -> (SimulatedBroker<HashMapInput>, Clock) {
+        let mut prices: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        prices.insert(
+            100.into(),
+            vec![
+                Quote::new(30_000.0, 30_000.0, 100, "BTC"),
+                Quote::new(100.0, 100.0, 100, "ABC"),
+                Quote::new(100.0, 100.0, 100, "BCD"),
+            ],
+        );
+        prices.insert(
+            101.into(),
+            vec![
+                Quote::new(30_000.0, 30_000.0, 101, "BTC"),
+                Quote::new(100.0, 100.0, 101, "ABC"),
+                Quote::new(100.0, 100.0, 101, "BCD"),
+            ],
+        );
+
+        let clock = ClockBuilder::with_length_in_seconds(100, 2)
+            .with_frequency(&Frequency::Second)
+            .build();
+
+        let source = HashMapInputBuilder::new()
+            .with_quotes(prices)
+            .with_clock(Rc::clone(&clock))
+            .build();
+
+        let mut btc = Instrument::new_fractional("BTC", 0.0001);
+        btc.min_order_size = 0.001;
+        btc.tick_size = Some(0.01);
+        //Delisted on the second tick, BCD has no instrument
+        let mut abc = Instrument::new("ABC");
+        abc.delisted = Some(101.into());
+
+        let exchange = DefaultExchangeBuilder::new()
+            .with_clock(Rc::clone(&clock))
+            .with_data_source(source.clone())
+            .with_instruments(vec![btc, abc])
+            .build();
+
+        let brkr = SimulatedBrokerBuilder::new()
+            .with_data(source)
+            .with_exchange(exchange)
+            .build();
+        (brkr, clock)
+    }
+
+    #[test]
+    fn test_that_target_weights_are_sized_with_instrument_lot_size() {
+        let (mut brkr, clock) = setup_instruments();
+        brkr.deposit_cash(&1_000.0);
+
+        let mut weights = PortfolioAllocation::new();
+        weights.insert("BTC", 1.0);
+        let orders = BrokerCalculations::diff_brkr_against_target_weights(&weights, &mut brkr);
+        assert_eq!(orders.len(), 1);
+        let shares = **orders.first().unwrap().get_shares();
+        //Whole shares would leave the account entirely in cash
+        assert!((shares - 0.0333).abs() < 1e-9);
+
+        brkr.send_orders(orders);
+        brkr.finish();
+        clock.borrow_mut().tick();
+        brkr.check();
+        assert!((**brkr.get_position_qty("BTC").unwrap() - 0.0333).abs() < 1e-9);
+        assert!((*brkr.get_cash_balance() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_that_orders_that_do_not_fit_instrument_are_invalid() {
+        let (mut brkr, _clock) = setup_instruments();
+        brkr.deposit_cash(&1_000.0);
+
+        //Not a multiple of the lot size
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "BTC", 0.00105));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+        //Below the minimum order size
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "BTC", 0.0005));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+        //Not a multiple of the tick size
+        let res = brkr.send_order(Order::delayed(
+            OrderType::LimitBuy,
+            "BTC",
+            0.001,
+            29_000.001,
+        ));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+
+        let res = brkr.send_order(Order::delayed(OrderType::LimitBuy, "BTC", 0.001, 29_000.01));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "BTC", 0.0011));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+    }
+
+    #[test]
+    fn test_that_instrument_rounds_quantities_to_lot_size() {
+        let mut instrument = Instrument::new("ABC");
+        instrument.lot_size = 100.0;
+        assert_eq!(instrument.round_quantity(250.0), 200.0);
+        assert_eq!(instrument.round_quantity(-250.0), -200.0);
+        assert_eq!(instrument.round_quantity_up(250.0), 300.0);
+        assert_eq!(instrument.round_quantity(50.0), 0.0);
+
+        let mut instrument = Instrument::new_fractional("BTC", 0.1);
+        instrument.min_order_size = 0.5;
+        assert!((instrument.round_quantity(0.75) - 0.7).abs() < 1e-9);
+        assert_eq!(instrument.round_quantity(0.45), 0.0);
+        assert!((instrument.round_quantity_up(0.2) - 0.5).abs() < 1e-9);
+        assert!(!instrument.is_valid_quantity(&0.3.into()));
+        assert!(instrument.is_valid_quantity(&0.7.into()));
+    }
+
+    #[test]
+    fn test_that_orders_for_unknown_or_delisted_instruments_are_invalid() {
+        let (mut brkr, clock) = setup_instruments();
+        brkr.deposit_cash(&100_000.0);
+
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "BCD", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderSentToExchange(..)));
+        let resting = Order::delayed(OrderType::LimitBuy, "ABC", 10.0, 50.0);
+        let res = brkr.send_order(resting);
+        let resting_id = match res {
+            BrokerEvent::OrderSentToExchange(_, id) => id,
+            _ => panic!("Order for listed instrument should be sent"),
+        };
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        //Market order is not executed because the instrument cannot trade on this date, the
+        //resting order is removed rather than staying in the book
+        assert!(brkr.get_position_qty("ABC").is_none());
+        assert!(matches!(
+            brkr.get_order_status(&resting_id),
+            Some(OrderStatus::Expired)
+        ));
+        assert_eq!(brkr.expired_orders().len(), 2);
+
+        let res = brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        assert!(matches!(res, BrokerEvent::OrderInvalid(..)));
+    }
+
+    #[test]
+    fn test_that_reverse_split_adjusts_holdings_and_pays_cash_for_fraction() {
+        let mut prices: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        prices.insert(100.into(), vec![Quote::new(100.0, 100.0, 100, "ABC")]);
+        prices.insert(101.into(), vec![Quote::new(100.0, 100.0, 101, "ABC")]);
+        prices.insert(102.into(), vec![Quote::new(1000.0, 1000.0, 102, "ABC")]);
+        let mut actions: HashMap<DateTime, Vec<CorporateAction>> = HashMap::new();
+        actions.insert(102.into(), vec![Split::new("ABC", 0.1, 102).into()]);
+
+        let (builder, clock) = setup_builder(
+            HashMapInputBuilder::new()
+                .with_quotes(prices)
+                .with_corporate_actions(actions),
+            3,
+            Vec::new(),
+        );
+        let mut brkr = builder.build();
+
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 15.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+        assert_eq!(*brkr.get_cash_balance(), 8_500.0);
+        assert_eq!(*brkr.cost_basis("ABC").unwrap(), 100.0);
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        //15 shares become 1.5, half a share is sold at the new price
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 1.0);
+        assert_eq!(*brkr.get_cash_balance(), 9_000.0);
+        assert_eq!(*brkr.cost_basis("ABC").unwrap(), 1000.0);
+        assert_eq!(brkr.corporate_actions().len(), 1);
+        assert_eq!(brkr.trades_between(&100, &102).len(), 2);
+    }
+    //Position of 10 shares in ABC, bought at 100, with a resting order in ABC when the action
+    //takes effect on the third tick
+    fn setup_corporate_action(action: CorporateAction) -> (SimulatedBroker<HashMapInput>, OrderId) {
+        let mut prices: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        for date in 100..103 {
+            prices.insert(
+                date.into(),
+                vec![
+                    Quote::new(100.0, 100.0, date, "ABC"),
+                    Quote::new(50.0, 50.0, date, "BCD"),
+                ],
+            );
+        }
+        let mut actions: HashMap<DateTime, Vec<CorporateAction>> = HashMap::new();
+        actions.insert(102.into(), vec![action]);
+
+        let (builder, clock) = setup_builder(
+            HashMapInputBuilder::new()
+                .with_quotes(prices)
+                .with_corporate_actions(actions),
+            3,
+            Vec::new(),
+        );
+        let mut brkr = builder.build();
+
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketBuy, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        let order_id =
+            match brkr.send_order(Order::delayed(OrderType::LimitSell, "ABC", 10.0, 200.0)) {
+                BrokerEvent::OrderSentToExchange(_, order_id) => order_id,
+                _ => panic!("Resting order should be sent"),
+            };
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        (brkr, order_id)
+    }
+
+    #[test]
+    fn test_that_cash_merger_converts_position_to_cash() {
+        let (brkr, order_id) = setup_corporate_action(CashMerger::new("ABC", 120.0, 102).into());
+
+        assert!(brkr.get_position_qty("ABC").is_none());
+        assert_eq!(*brkr.get_cash_balance(), 10_200.0);
+        assert!(matches!(
+            brkr.get_order_status(&order_id),
+            Some(OrderStatus::Cancelled)
+        ));
+        assert_eq!(brkr.corporate_actions().len(), 1);
+        assert_eq!(brkr.trades_between(&100, &102).len(), 2);
+    }
+
+    #[test]
+    fn test_that_stock_merger_converts_position_and_cost_to_new_symbol() {
+        let (brkr, order_id) =
+            setup_corporate_action(StockMerger::new("ABC", "BCD", 2.5, 102).into());
+
+        assert!(brkr.get_position_qty("ABC").is_none());
+        assert_eq!(**brkr.get_position_qty("BCD").unwrap(), 25.0);
+        assert_eq!(*brkr.cost_basis("BCD").unwrap(), 40.0);
+        assert_eq!(*brkr.get_cash_balance(), 9_000.0);
+        assert!(matches!(
+            brkr.get_order_status(&order_id),
+            Some(OrderStatus::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_that_spin_off_creates_position_with_allocated_cost() {
+        let (brkr, order_id) =
+            setup_corporate_action(SpinOff::new("ABC", "BCD", 0.5, 0.2, 102).into());
+
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), 10.0);
+        assert_eq!(**brkr.get_position_qty("BCD").unwrap(), 5.0);
+        assert_eq!(*brkr.cost_basis("ABC").unwrap(), 80.0);
+        assert_eq!(*brkr.cost_basis("BCD").unwrap(), 40.0);
+        assert!(matches!(
+            brkr.get_order_status(&order_id),
+            Some(OrderStatus::Pending)
+        ));
+    }
+
+    #[test]
+    fn test_that_delisting_writes_off_or_cashes_out_position() {
+        let (brkr, _order_id) = setup_corporate_action(Delisting::new("ABC", false, 102).into());
+        assert!(brkr.get_position_qty("ABC").is_none());
+        assert_eq!(*brkr.get_cash_balance(), 9_000.0);
+        let trades = brkr.trades_between(&102, &102);
+        assert_eq!(*trades.first().unwrap().value, 0.0);
+
+        let (brkr, _order_id) = setup_corporate_action(Delisting::new("ABC", true, 102).into());
+        assert!(brkr.get_position_qty("ABC").is_none());
+        assert_eq!(*brkr.get_cash_balance(), 10_000.0);
+    }
+
+    #[test]
+    fn test_that_delisting_without_cash_out_leaves_short_position_open() {
+        let mut prices: HashMap<DateTime, Vec<Quote>> = HashMap::new();
+        for date in 100..103 {
+            prices.insert(date.into(), vec![Quote::new(100.0, 100.0, date, "ABC")]);
+        }
+        let mut actions: HashMap<DateTime, Vec<CorporateAction>> = HashMap::new();
+        actions.insert(102.into(), vec![Delisting::new("ABC", false, 102).into()]);
+
+        let (mut builder, clock) = setup_builder(
+            HashMapInputBuilder::new()
+                .with_quotes(prices)
+                .with_corporate_actions(actions),
+            3,
+            Vec::new(),
+        );
+        let mut brkr = builder.with_short_selling(true).build();
+        brkr.deposit_cash(&10_000.0);
+        brkr.send_order(Order::market(OrderType::MarketSell, "ABC", 10.0));
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+        brkr.finish();
+
+        clock.borrow_mut().tick();
+        brkr.check();
+
+        //No buy back is recorded so the proceeds of the short sale aren't realised
+        assert_eq!(**brkr.get_position_qty("ABC").unwrap(), -10.0);
+        assert_eq!(*brkr.get_cash_balance(), 11_000.0);
+        assert!(brkr.trades_between(&102, &102).is_empty());
+    }
 }
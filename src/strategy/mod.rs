@@ -1,6 +1,8 @@
 use log::info;
 use std::rc::Rc;
 
+use crate::broker::lots::RealisedGain;
+use crate::broker::wash::WashSale;
 use crate::broker::{
     BacktestBroker, BrokerCalculations, BrokerCashEvent, DividendPayment, EventLog, Trade,
     TransferCash,
@@ -26,7 +28,7 @@ use crate::types::{CashValue, PortfolioAllocation, StrategySnapshot};
 ///not required to run the strategy, it is only used to record the state for performance calcs. Strategy
 ///implementations should run idempotently, although some with a dependence on external data which
 ///has it's own state, without much additional state
-
+///
 ///The `Strategy` trait defines the key lifecycle events that are required to create and run a backtest.
 ///This functionality is closely bound into `SimContext` which is the struct that wraps around the
 ///components of a backtest, runs it, and offers the interface into the components (like
@@ -54,6 +56,18 @@ pub enum StrategyEvent {
 pub trait Audit {
     fn trades_between(&self, start: &i64, end: &i64) -> Vec<Trade>;
     fn dividends_between(&self, start: &i64, end: &i64) -> Vec<DividendPayment>;
+    //Strategies that don't track tax lots report no gains
+    fn realised_gains_between(&self, _start: &i64, _end: &i64) -> Vec<RealisedGain> {
+        Vec::new()
+    }
+    //Strategies that don't check for wash sales report none
+    fn wash_sales_between(&self, _start: &i64, _end: &i64) -> Vec<WashSale> {
+        Vec::new()
+    }
+    //Realised gains after wash sale adjustments, which can differ from `realised_gains_between`
+    fn adjusted_gains_between(&self, _start: &i64, _end: &i64) -> Vec<RealisedGain> {
+        Vec::new()
+    }
 }
 
 ///Trait to transfer cash into a strategy either at the start or whilst it is running. This is in a
@@ -149,6 +163,7 @@ impl<T: DataSource> StaticWeightStrategy<T> {
             portfolio_value: self.brkr.get_total_value(),
             net_cash_flow: self.net_cash_flow.clone(),
             inflation: 0.0,
+            fees: self.brkr.total_fees(),
         }
     }
 }
@@ -228,6 +243,18 @@ impl<T: DataSource> Audit for StaticWeightStrategy<T> {
     fn dividends_between(&self, start: &i64, end: &i64) -> Vec<DividendPayment> {
         self.brkr.dividends_between(start, end)
     }
+
+    fn realised_gains_between(&self, start: &i64, end: &i64) -> Vec<RealisedGain> {
+        self.brkr.realised_gains_between(start, end)
+    }
+
+    fn wash_sales_between(&self, start: &i64, end: &i64) -> Vec<WashSale> {
+        self.brkr.wash_sales_between(start, end)
+    }
+
+    fn adjusted_gains_between(&self, start: &i64, end: &i64) -> Vec<RealisedGain> {
+        self.brkr.adjusted_gains_between(start, end)
+    }
 }
 
 impl<T: DataSource> History for StaticWeightStrategy<T> {
@@ -1,6 +1,9 @@
 use itertools::Itertools;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
 use std::hash::Hash;
 use std::ops::Deref;
+use std::str::FromStr;
 use std::{collections::HashMap, ops::Add};
 use time::{format_description, Date, OffsetDateTime};
 
@@ -145,8 +148,40 @@ impl DateTime {
         date.month().into()
     }
 
+    pub fn is_same_day(&self, other: &DateTime) -> bool {
+        let date: OffsetDateTime = self.clone().into();
+        let other_date: OffsetDateTime = other.clone().into();
+        date.date() == other_date.date()
+    }
+
+    //Whether both dates fall within the same day, month, or year. With a frequency of a second,
+    //dates must be equal.
+    pub fn is_same_period(&self, other: &DateTime, frequency: &Frequency) -> bool {
+        let date: OffsetDateTime = self.clone().into();
+        let other_date: OffsetDateTime = other.clone().into();
+        match frequency {
+            Frequency::Second => self == other,
+            Frequency::Daily => date.date() == other_date.date(),
+            Frequency::Monthly => {
+                date.year() == other_date.year() && date.month() == other_date.month()
+            }
+            Frequency::Yearly => date.year() == other_date.year(),
+        }
+    }
+
+    //UK tax years run from 6 April to 5 April, and are identified by the calendar year in which
+    //they start
+    pub fn uk_tax_year(&self) -> i32 {
+        let date: OffsetDateTime = self.clone().into();
+        if (date.month() as u8, date.day()) >= (time::Month::April as u8, 6) {
+            date.year()
+        } else {
+            date.year() - 1
+        }
+    }
+
     pub fn from_date_string(val: &str, date_fmt: &str) -> Self {
-        let format = format_description::parse(date_fmt).unwrap();
+        let format = format_description::parse_borrowed::<1>(date_fmt).unwrap();
         let parsed_date = Date::parse(val, &format).unwrap();
         let parsed_time = parsed_date.with_time(time::macros::time!(09:00));
         Self::from(parsed_time.assume_utc().unix_timestamp())
@@ -189,6 +224,86 @@ impl From<i64> for DateTime {
     }
 }
 
+///ISO 4217 code of the currency that a value is held in. Values without an explicit currency, for
+///example quotes created with `Quote::new`, are assumed to be in [Currency::USD].
+///
+///let c = Currency::new("GBP");
+///let c: Result<Currency, _> = "GBP".parse();
+#[derive(Clone, Copy, Hash, Eq, PartialEq)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const USD: Currency = Currency(*b"USD");
+    pub const GBP: Currency = Currency(*b"GBP");
+    pub const EUR: Currency = Currency(*b"EUR");
+    pub const JPY: Currency = Currency(*b"JPY");
+    pub const CHF: Currency = Currency(*b"CHF");
+
+    //Panics on an invalid code, codes from input data should be parsed instead
+    pub fn new(code: &str) -> Self {
+        code.parse()
+            .expect("Currency code must be three uppercase letters")
+    }
+
+    pub fn code(&self) -> &str {
+        //Can only be created from ascii so this is always valid
+        std::str::from_utf8(&self.0).unwrap()
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::USD
+    }
+}
+
+impl FromStr for Currency {
+    type Err = InvalidCurrencyError;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let bytes = code.as_bytes();
+        if bytes.len() != 3 || !bytes.iter().all(|b| b.is_ascii_uppercase()) {
+            return Err(InvalidCurrencyError(code.to_string()));
+        }
+        Ok(Self([bytes[0], bytes[1], bytes[2]]))
+    }
+}
+
+impl TryFrom<&str> for Currency {
+    type Error = InvalidCurrencyError;
+
+    fn try_from(code: &str) -> Result<Self, Self::Error> {
+        code.parse()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidCurrencyError(pub String);
+
+impl Error for InvalidCurrencyError {}
+
+impl Display for InvalidCurrencyError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Currency code {:?} must be three uppercase letters",
+            self.0
+        )
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+impl Debug for Currency {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub struct PortfolioQty(f64);
 
@@ -390,12 +505,16 @@ impl Frequency {
 ///
 /// net_cash_flow variable is a sum, not a measure of flow within the period. To get flows, we have
 /// to diff each value with the previous one.
+///
+/// fees is also a sum, of the account fees charged by the broker. Fees have already been deducted
+/// from portfolio_value.
 #[derive(Clone, Debug)]
 pub struct StrategySnapshot {
     pub date: DateTime,
     pub portfolio_value: CashValue,
     pub net_cash_flow: CashValue,
     pub inflation: f64,
+    pub fees: CashValue,
 }
 
 impl StrategySnapshot {
@@ -405,6 +524,7 @@ impl StrategySnapshot {
             portfolio_value,
             net_cash_flow,
             inflation: 0.0,
+            fees: CashValue::from(0.0),
         }
     }
 
@@ -419,6 +539,7 @@ impl StrategySnapshot {
             portfolio_value,
             net_cash_flow,
             inflation,
+            fees: CashValue::from(0.0),
         }
     }
 }
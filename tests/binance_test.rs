@@ -2,7 +2,9 @@ use std::collections::HashMap;
 use std::io::{Cursor, Write};
 use std::rc::Rc;
 
-use alator::broker::{BacktestBroker, GetsQuote, Order, OrderType, Quote, TransferCash};
+use alator::broker::{
+    BacktestBroker, GetsQuote, Instrument, Order, OrderType, Quote, TransferCash,
+};
 use alator::clock::{Clock, ClockBuilder};
 use alator::exchange::DefaultExchangeBuilder;
 use alator::input::{HashMapInput, HashMapInputBuilder, QuotesHashMap};
@@ -30,46 +32,44 @@ fn build_data() -> (QuotesHashMap, (i64, i64)) {
                 for i in 0..zip.len() {
                     if let Ok(mut zip_file) = zip.by_index(i) {
                         let mut rdr = csv::Reader::from_reader(&mut zip_file);
-                        for result in rdr.records() {
-                            if let Ok(row) = result {
-                                /*
-                                 * Binance data format:
-                                 * 1607444700000,          // Open time
-                                 * "18879.99",             // Open
-                                 * "18900.00",             // High
-                                 * "18878.98",             // Low
-                                 * "18896.13",             // Close (or latest price)
-                                 * "492.363",              // Volume
-                                 * 1607444759999,          // Close time
-                                 * "9302145.66080",        // Quote asset volume
-                                 * 1874,                   // Number of trades
-                                 * "385.983",              // Taker buy volume
-                                 * "7292402.33267",        // Taker buy quote asset volume
-                                 * "0"                     // Ignore.
-                                 */
-                                let open_date = (row[0].parse::<i64>().unwrap()) / 1000;
-                                if open_date < min_date {
-                                    min_date = open_date;
-                                }
-                                let quote = Quote {
-                                    bid: row[1].parse::<f64>().unwrap().into(),
-                                    ask: row[1].parse::<f64>().unwrap().into(),
-                                    date: open_date.into(),
-                                    symbol: "BTC".into(),
-                                };
-                                quotes.insert(open_date.into(), vec![quote]);
-                                let close_date = (row[6].parse::<i64>().unwrap()) / 1000;
-                                if close_date > max_date {
-                                    max_date = close_date;
-                                }
-                                let quote1 = Quote {
-                                    bid: row[4].parse::<f64>().unwrap().into(),
-                                    ask: row[4].parse::<f64>().unwrap().into(),
-                                    date: close_date.into(),
-                                    symbol: "BTC".into(),
-                                };
-                                quotes.insert(close_date.into(), vec![quote1]);
+                        for row in rdr.records().flatten() {
+                            /*
+                             * Binance data format:
+                             * 1607444700000,          // Open time
+                             * "18879.99",             // Open
+                             * "18900.00",             // High
+                             * "18878.98",             // Low
+                             * "18896.13",             // Close (or latest price)
+                             * "492.363",              // Volume
+                             * 1607444759999,          // Close time
+                             * "9302145.66080",        // Quote asset volume
+                             * 1874,                   // Number of trades
+                             * "385.983",              // Taker buy volume
+                             * "7292402.33267",        // Taker buy quote asset volume
+                             * "0"                     // Ignore.
+                             */
+                            let open_date = (row[0].parse::<i64>().unwrap()) / 1000;
+                            if open_date < min_date {
+                                min_date = open_date;
                             }
+                            let quote = Quote::new(
+                                row[1].parse::<f64>().unwrap(),
+                                row[1].parse::<f64>().unwrap(),
+                                open_date,
+                                "BTC",
+                            );
+                            quotes.insert(open_date.into(), vec![quote]);
+                            let close_date = (row[6].parse::<i64>().unwrap()) / 1000;
+                            if close_date > max_date {
+                                max_date = close_date;
+                            }
+                            let quote1 = Quote::new(
+                                row[4].parse::<f64>().unwrap(),
+                                row[4].parse::<f64>().unwrap(),
+                                close_date,
+                                "BTC",
+                            );
+                            quotes.insert(close_date.into(), vec![quote1]);
                         }
                     }
                 }
@@ -140,7 +140,7 @@ struct MovingAverageStrategy {
 
 impl TransferTo for MovingAverageStrategy {
     fn deposit_cash(&mut self, cash: &f64) -> StrategyEvent {
-        self.brkr.deposit_cash(&cash);
+        self.brkr.deposit_cash(cash);
         StrategyEvent::DepositSuccess(CashValue::from(*cash))
     }
 }
@@ -179,8 +179,8 @@ impl Strategy for MovingAverageStrategy {
         //incorrect state, a runtime failure seems most appropriate.
         if let Some(quote) = self.brkr.get_quote("BTC") {
             //Update our moving averages with the latest quote
-            self.ten.update(&quote);
-            self.fifty.update(&quote);
+            self.ten.update(quote);
+            self.fifty.update(quote);
 
             //If we are at the start of the simulation and don't have full data for each moving
             //average then don't trade
@@ -195,14 +195,14 @@ impl Strategy for MovingAverageStrategy {
             //added in the future but it adds dependencies on the underlying asset which is not
             //ideal currently.
             if self.ten.avg() > self.fifty.avg() {
-                if let None = self.brkr.get_position_qty("BTC") {
+                if self.brkr.get_position_qty("BTC").is_none() {
                     let value = self.brkr.get_liquidation_value();
                     let pct_value = CashValue::from(*value * 0.1);
-                    //All this casting is required because, at the moment, we haven't moved fully
-                    //away from positions reqpresented in whole numbers. Strategies should work but
-                    //I am not sure if the result is correct.
-                    let qty = (f64::from(pct_value) / f64::from(*quote.ask)).floor();
-                    let order = Order::market(OrderType::MarketBuy, "BTC", qty.clone());
+                    //BTC trades in fractions, so the order is sized to the lot size of the
+                    //instrument rather than whole coins
+                    let instrument = self.brkr.get_instrument("BTC").unwrap();
+                    let qty = instrument.round_quantity(f64::from(pct_value) / (*quote.ask));
+                    let order = Order::market(OrderType::MarketBuy, "BTC", qty);
                     self.brkr.send_order(order);
                 }
             } else {
@@ -220,6 +220,7 @@ impl Strategy for MovingAverageStrategy {
             portfolio_value: val.clone(),
             net_cash_flow: CashValue::from(0.0),
             inflation: 0.0,
+            fees: self.brkr.total_fees(),
         };
 
         self.history.push(snap);
@@ -272,6 +273,7 @@ fn binance_test() {
     let exchange = DefaultExchangeBuilder::new()
         .with_clock(Rc::clone(&clock))
         .with_data_source(data.clone())
+        .with_instruments(vec![Instrument::new_fractional("BTC", 0.00001)])
         .build();
 
     let simbrkr = SimulatedBrokerBuilder::new()